@@ -0,0 +1,162 @@
+//! Applies OsmChange diffs to OSM data.
+
+use indicatif::ProgressBar;
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::mpsc::Receiver;
+
+use crate::channel::ChunkSender;
+use crate::elements::{Action, Element};
+use crate::progress::start_spinner;
+use crate::sort::sort_key;
+use crate::SkywayError;
+
+/// Collects changes into the order they will be applied in. If an element is changed
+/// more than once, only its newest version (or the last one read, for a tie) is kept.
+pub fn collect_changes<I>(changes: I) -> BTreeMap<(u8, i64), Element>
+where
+    I: IntoIterator<Item = Element>,
+{
+    let mut collected: BTreeMap<(u8, i64), Element> = BTreeMap::new();
+    for element in changes {
+        let key = sort_key(&element);
+        let is_newer = match collected.get(&key) {
+            Some(existing) => element.version.unwrap_or(0) >= existing.version.unwrap_or(0),
+            None => true,
+        };
+        if is_newer {
+            collected.insert(key, element);
+        }
+    }
+    collected
+}
+
+// deleted elements are left out of the snapshot entirely. Deletes from
+// o5c and OPL change files have no action, only `visible: false`
+fn push_change(output: &mut Vec<Element>, mut element: Element) {
+    if element.action != Some(Action::Delete) && element.visible != Some(false) {
+        element.action = None;
+        output.push(element);
+    }
+}
+
+/// Merges changes into a stream of elements, which must be sorted by type, then ID.
+/// The output is a snapshot, also sorted by type, then ID.
+///
+/// * `changes`: The changes to apply, as returned by `collect_changes`.
+/// * `receiver`: Receiver for a channel of `Element`s.
+/// * `sender`: Sender for a channel of `Element`s.
+/// * `progress`: The ProgressBar for this operation.
+pub fn apply_changes(
+    changes: BTreeMap<(u8, i64), Element>,
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    progress: ProgressBar,
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Applying changes...");
+
+    let mut changes = changes.into_iter().peekable();
+    let mut last_key = None;
+    let mut output = Vec::new();
+
+    for element in receiver.iter().flatten() {
+        let key = sort_key(&element);
+        if last_key.is_some_and(|k| key < k) {
+            return Err(SkywayError::InvalidInput(String::from(
+                "Input must be sorted by type, then ID to apply changes to it",
+            )));
+        }
+        last_key = Some(key);
+
+        // changes to elements that come before this one (or aren't in the input)
+        while let Some((_, change)) = changes.next_if(|(k, _)| *k < key) {
+            push_change(&mut output, change);
+        }
+
+        // a change to this element replaces it
+        match changes.next_if(|(k, _)| *k == key) {
+            Some((_, change)) => push_change(&mut output, change),
+            None => output.push(element),
+        }
+
+        if output.len() >= 1000 {
+            sender.send(mem::take(&mut output))?;
+        }
+    }
+
+    for (_, change) in changes {
+        push_change(&mut output, change);
+    }
+    if !output.is_empty() {
+        sender.send(output)?;
+    }
+    progress.finish_with_message("Applying changes...done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::elements::ElementType;
+    use crate::fixtures;
+    use std::sync::mpsc::channel;
+
+    fn element(id: i64, version: i32, action: Option<Action>, is_way: bool) -> Element {
+        let element_type = if is_way {
+            ElementType::Way { nodes: vec![1, 2] }
+        } else {
+            ElementType::Node { lat: 1.0, lon: 2.0 }
+        };
+        Element {
+            version: Some(version),
+            visible: Some(action != Some(Action::Delete)),
+            action,
+            ..fixtures::element(id, element_type, &[])
+        }
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let changes = collect_changes(vec![
+            element(2, 2, Some(Action::Modify), false),
+            element(2, 3, Some(Action::Delete), false),
+            element(4, 1, Some(Action::Create), false),
+            element(5, 2, Some(Action::Modify), true),
+            element(9, 1, Some(Action::Create), true),
+            // as read from an o5c file
+            Element {
+                visible: Some(false),
+                ..element(3, 2, None, false)
+            },
+        ]);
+
+        let (base_sender, base_receiver) = channel();
+        base_sender
+            .send(vec![
+                element(1, 1, None, false),
+                element(2, 1, None, false),
+                element(3, 1, None, false),
+                element(5, 1, None, true),
+            ])
+            .unwrap();
+        drop(base_sender);
+
+        let (sender, receiver) = chunk_channel(16);
+        apply_changes(changes, base_receiver, sender, ProgressBar::hidden()).unwrap();
+        let output: Vec<_> = receiver
+            .iter()
+            .flatten()
+            .map(|e| (sort_key(&e), e.version, e.action))
+            .collect();
+        assert_eq!(
+            output,
+            vec![
+                ((0, 1), Some(1), None),
+                ((0, 4), Some(1), None),
+                ((1, 5), Some(2), None),
+                ((1, 9), Some(1), None),
+            ]
+        );
+    }
+}
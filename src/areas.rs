@@ -0,0 +1,292 @@
+//! Recognizes areas, and assembles multipolygon and boundary relations into polygons.
+
+use std::collections::HashMap;
+
+use crate::elements::{Element, ElementType, Member, SimpleElementType};
+use crate::locations::LocationIndex;
+
+/// A closed ring of `(lon, lat)` positions, whose first and last positions are the same.
+pub type Ring = Vec<(f64, f64)>;
+
+/// A polygon, made up of an outer ring and any number of inner rings (holes).
+/// Outer rings run counterclockwise and inner rings run clockwise, as GeoJSON recommends.
+#[derive(Debug, PartialEq)]
+pub struct Area {
+    pub outer: Ring,
+    pub inners: Vec<Ring>,
+}
+
+// closed ways with any of these keys are areas, unless they are tagged area=no
+const AREA_KEYS: [&str; 13] = [
+    "amenity",
+    "building",
+    "building:part",
+    "craft",
+    "historic",
+    "landuse",
+    "leisure",
+    "military",
+    "natural",
+    "office",
+    "place",
+    "shop",
+    "tourism",
+];
+
+// natural features that are lines, even when they are closed
+const LINEAR_NATURAL_VALUES: [&str; 5] = ["arete", "cliff", "coastline", "ridge", "tree_row"];
+
+fn has_area_tags(tags: &HashMap<String, String>) -> bool {
+    match tags.get("area").map(String::as_str) {
+        Some("yes") => return true,
+        Some("no") => return false,
+        _ => {}
+    }
+    if let Some(v) = tags.get("natural") {
+        if LINEAR_NATURAL_VALUES.contains(&v.as_str()) {
+            return false;
+        }
+    }
+    AREA_KEYS.iter().any(|k| tags.contains_key(*k))
+}
+
+/// Whether an element describes an area: either a closed way tagged as one
+/// (e.g. with `building=*` or `area=yes`), or a multipolygon or boundary relation.
+pub fn is_area(element: &Element) -> bool {
+    match &element.element_type {
+        ElementType::Node { .. } => false,
+        ElementType::Way { nodes } => {
+            nodes.len() >= 4 && nodes.first() == nodes.last() && has_area_tags(&element.tags)
+        }
+        ElementType::Relation { .. } => matches!(
+            element.tags.get("type").map(String::as_str),
+            Some("multipolygon") | Some("boundary")
+        ),
+    }
+}
+
+/// The part of an area a relation member makes up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RingRole {
+    /// An `outer` way, or one with an empty role.
+    Outer,
+    /// An `inner` way, which is part of a hole.
+    Inner,
+}
+
+/// The part of an area a multipolygon or boundary relation's member makes up, or `None`
+/// if it isn't a way with an `outer`, `inner` or empty role (like a boundary's `label`
+/// node or `subarea` relations).
+pub fn ring_role(member: &Member) -> Option<RingRole> {
+    let Some(SimpleElementType::Way) = member.t else {
+        return None;
+    };
+    match member.role.as_deref() {
+        None | Some("") | Some("outer") => Some(RingRole::Outer),
+        Some("inner") => Some(RingRole::Inner),
+        _ => None,
+    }
+}
+
+// even-odd ray casting, with the ray running east from the point
+pub(crate) fn ring_contains(ring: &[(f64, f64)], lat: f64, lon: f64) -> bool {
+    let mut inside = false;
+    let mut previous = match ring.last() {
+        Some(p) => *p,
+        None => return false,
+    };
+    for &(x, y) in ring {
+        let (px, py) = previous;
+        if (y > lat) != (py > lat) && lon < (px - x) * (lat - y) / (py - y) + x {
+            inside = !inside;
+        }
+        previous = (x, y);
+    }
+    inside
+}
+
+// twice the ring's area, positive if it runs counterclockwise
+fn signed_area(ring: &[(f64, f64)]) -> f64 {
+    ring.windows(2)
+        .map(|w| w[0].0 * w[1].1 - w[1].0 * w[0].1)
+        .sum()
+}
+
+// join ways end to end until each forms a closed ring. Ways may need to be
+// reversed to fit, and if any of them can't be closed, there's no valid ring
+fn stitch_rings(mut ways: Vec<&[i64]>) -> Option<Vec<Vec<i64>>> {
+    let mut rings = Vec::new();
+    while !ways.is_empty() {
+        let mut ring = ways.remove(0).to_vec();
+        while ring.first() != ring.last() {
+            let end = *ring.last()?;
+            let i = ways
+                .iter()
+                .position(|w| w.first() == Some(&end) || w.last() == Some(&end))?;
+            let next = ways.remove(i);
+            if next.first() == Some(&end) {
+                ring.extend(&next[1..]);
+            } else {
+                ring.extend(next.iter().rev().skip(1));
+            }
+        }
+        if ring.len() < 4 {
+            return None;
+        }
+        rings.push(ring);
+    }
+    Some(rings)
+}
+
+// look up the location of every node in a ring, orienting it counterclockwise
+// (or clockwise, for inner rings)
+fn locate_ring(nodes: &[i64], locations: &dyn LocationIndex, clockwise: bool) -> Option<Ring> {
+    let mut ring = nodes
+        .iter()
+        .map(|n| locations.get(*n).map(|(lat, lon)| (lon, lat)))
+        .collect::<Option<Ring>>()?;
+    if (signed_area(&ring) < 0.0) != clockwise {
+        ring.reverse();
+    }
+    Some(ring)
+}
+
+/// Assembles a multipolygon or boundary relation's `outer` and `inner` member ways
+/// (members with an empty role count as outer) into polygons. Each inner ring becomes
+/// a hole in the smallest outer ring around it.
+///
+/// Returns `None` if the relation is incomplete (a member way, or one of its nodes,
+/// is missing) or its ways can't be joined into closed rings.
+///
+/// * `members`: The relation's members.
+/// * `way_nodes`: The nodes of the relation's `outer` and `inner` member ways (other
+///   members are ignored, so they don't need to be here).
+/// * `locations`: The location of every node those ways reference.
+pub fn assemble_areas(
+    members: &[Member],
+    way_nodes: &HashMap<i64, Vec<i64>>,
+    locations: &dyn LocationIndex,
+) -> Option<Vec<Area>> {
+    let mut outer_ways = Vec::new();
+    let mut inner_ways = Vec::new();
+    for member in members {
+        let Some(role) = ring_role(member) else {
+            continue;
+        };
+        let nodes = way_nodes.get(&member.id)?;
+        // ways with fewer than two nodes don't contribute anything to a ring
+        if nodes.len() < 2 {
+            continue;
+        }
+        match role {
+            RingRole::Outer => outer_ways.push(nodes.as_slice()),
+            RingRole::Inner => inner_ways.push(nodes.as_slice()),
+        }
+    }
+
+    let mut areas = stitch_rings(outer_ways)?
+        .iter()
+        .map(|r| {
+            locate_ring(r, locations, false).map(|outer| Area {
+                outer,
+                inners: Vec::new(),
+            })
+        })
+        .collect::<Option<Vec<Area>>>()?;
+    if areas.is_empty() {
+        return None;
+    }
+
+    for ring in stitch_rings(inner_ways)? {
+        let inner = locate_ring(&ring, locations, true)?;
+        // inner rings that aren't inside any outer ring are left out
+        let (lon, lat) = inner[0];
+        let outer = areas
+            .iter_mut()
+            .filter(|a| ring_contains(&a.outer, lat, lon))
+            .min_by(|a, b| signed_area(&a.outer).total_cmp(&signed_area(&b.outer)));
+        if let Some(a) = outer {
+            a.inners.push(inner);
+        }
+    }
+    Some(areas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::locations::SparseLocationIndex;
+
+    fn member(id: i64, role: &str) -> Member {
+        Member {
+            t: Some(SimpleElementType::Way),
+            id,
+            role: Some(role.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_assemble_areas() {
+        let mut locations = SparseLocationIndex::new();
+        // a 4x4 square (nodes 1-4) with a 2x2 hole (nodes 5-8) and a separate
+        // square off to the east (nodes 9-12), with coordinates given as (lat, lon)
+        for (id, lat, lon) in [
+            (1, 0.0, 0.0),
+            (2, 0.0, 4.0),
+            (3, 4.0, 4.0),
+            (4, 4.0, 0.0),
+            (5, 1.0, 1.0),
+            (6, 1.0, 3.0),
+            (7, 3.0, 3.0),
+            (8, 3.0, 1.0),
+            (9, 0.0, 10.0),
+            (10, 0.0, 11.0),
+            (11, 1.0, 11.0),
+            (12, 1.0, 10.0),
+        ] {
+            locations.insert(id, lat, lon).unwrap();
+        }
+        // the outer ring of the first square is split into two ways, one of them
+        // running the wrong direction, and the hole is drawn counterclockwise
+        let way_nodes = HashMap::from([
+            (100, vec![1, 2, 3]),
+            (101, vec![1, 4, 3]),
+            (102, vec![5, 6, 7, 8, 5]),
+            (103, vec![9, 12, 11, 10, 9]),
+        ]);
+        // ways with other roles aren't part of the area, so it doesn't matter that they're missing
+        let members = vec![
+            member(100, "outer"),
+            member(102, "inner"),
+            member(101, "outer"),
+            member(103, ""),
+            member(104, "subarea"),
+        ];
+
+        let areas = assemble_areas(&members, &way_nodes, &locations).unwrap();
+        assert_eq!(areas.len(), 2);
+        assert_eq!(
+            areas[0].outer,
+            vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0), (0.0, 0.0)]
+        );
+        assert_eq!(
+            areas[0].inners,
+            vec![vec![
+                (1.0, 1.0),
+                (1.0, 3.0),
+                (3.0, 3.0),
+                (3.0, 1.0),
+                (1.0, 1.0)
+            ]]
+        );
+        assert!(signed_area(&areas[1].outer) > 0.0);
+        assert!(areas[1].inners.is_empty());
+
+        // an unclosed ring, or a missing way, can't be assembled
+        assert_eq!(assemble_areas(&members[..1], &way_nodes, &locations), None);
+        assert_eq!(
+            assemble_areas(&[member(999, "outer")], &way_nodes, &locations),
+            None
+        );
+    }
+}
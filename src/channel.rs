@@ -0,0 +1,168 @@
+//! Bounded channels for passing chunks of elements between pipeline stages.
+
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SendError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::elements::Element;
+use crate::reorder::reorder;
+use crate::threadpools::thread_pool;
+use crate::SkywayError;
+
+/// Creates a channel that holds up to `bound` chunks of elements, after
+/// which sending waits until the receiver has taken one out.
+pub fn chunk_channel(bound: usize) -> (ChunkSender, Receiver<Vec<Element>>) {
+    let (sender, receiver) = mpsc::sync_channel(bound);
+    let sender = ChunkSender {
+        sender,
+        stalled: StallTime::default(),
+    };
+    (sender, receiver)
+}
+
+/// The sending half of a [`chunk_channel`], which keeps track of how long
+/// it has waited for the receiver to make room.
+#[derive(Clone)]
+pub struct ChunkSender {
+    sender: SyncSender<Vec<Element>>,
+    stalled: StallTime,
+}
+
+impl ChunkSender {
+    /// Sends a chunk, waiting if the channel is full. Returns an error
+    /// if the receiver is gone.
+    pub fn send(&self, chunk: Vec<Element>) -> Result<(), SendError<Vec<Element>>> {
+        match self.sender.try_send(chunk) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(chunk)) => {
+                let start = Instant::now();
+                let result = self.sender.send(chunk);
+                self.stalled.add(start.elapsed());
+                result
+            }
+            Err(TrySendError::Disconnected(chunk)) => Err(SendError(chunk)),
+        }
+    }
+
+    /// How long this sender (and its clones) have waited for the receiver, which can be
+    /// read while the sender is in use, and after it's gone. A stage that waits a lot is
+    /// faster than the one after it.
+    pub fn stall_time(&self) -> StallTime {
+        self.stalled.clone()
+    }
+}
+
+/// The time a [`ChunkSender`] has spent waiting on a full channel.
+#[derive(Clone, Default)]
+pub struct StallTime(Arc<AtomicU64>);
+
+impl StallTime {
+    fn add(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.0.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Encodes chunks (of elements, or blobs of input) on a pool of `threads` threads, and
+/// hands each result to `output` on the calling thread, in input order unless
+/// `preserve_order` is false. Encoding waits while `buffer_chunks` results are waiting
+/// to be output, and stops early once `output` returns an error.
+pub(crate) fn encode_in_parallel<I, T>(
+    chunks: I,
+    threads: usize,
+    buffer_chunks: usize,
+    preserve_order: bool,
+    encode: impl Fn(I::Item) -> T + Send + Sync,
+    mut output: impl FnMut(T) -> Result<(), SkywayError>,
+) -> Result<(), SkywayError>
+where
+    I: Iterator + Send,
+    I::Item: Send,
+    T: Send,
+{
+    let (sender, receiver) = mpsc::sync_channel(buffer_chunks);
+    let pool = thread_pool(threads)?;
+    thread::scope(|s| {
+        // once the results stop being received, sending fails and encoding stops
+        s.spawn(move || {
+            pool.install(move || {
+                chunks
+                    .enumerate()
+                    .par_bridge()
+                    .map(|(i, chunk)| (i, encode(chunk)))
+                    .try_for_each(|c| sender.send(c))
+            })
+        });
+        for encoded in reorder(receiver, preserve_order) {
+            output(encoded)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_chunk_channel() {
+        let (sender, receiver) = chunk_channel(1);
+        let stall_time = sender.stall_time();
+        sender.send(Vec::new()).unwrap();
+        assert_eq!(stall_time.get(), Duration::ZERO);
+
+        // the channel is full, so the second chunk waits for the first to be received
+        let receiver_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            receiver.iter().count()
+        });
+        sender.send(Vec::new()).unwrap();
+        drop(sender);
+        assert_eq!(receiver_thread.join().unwrap(), 2);
+        assert!(stall_time.get() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_encode_in_parallel() {
+        let mut output = Vec::new();
+        encode_in_parallel(
+            0..100,
+            4,
+            2,
+            true,
+            |i| i * 2,
+            |i| {
+                output.push(i);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(output, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+
+        // an output error stops encoding, and is returned
+        let mut count = 0;
+        let result = encode_in_parallel(
+            0..,
+            4,
+            2,
+            true,
+            |i| i,
+            |_| {
+                count += 1;
+                match count {
+                    10 => Err(SkywayError::ChannelError),
+                    _ => Ok(()),
+                }
+            },
+        );
+        assert!(matches!(result, Err(SkywayError::ChannelError)));
+    }
+}
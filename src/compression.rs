@@ -0,0 +1,172 @@
+//! Transparent compression and decompression of input and output streams.
+
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Compression formats skyway can read and write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Bzip2,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    /// Converts a file extension `&str` into the appropriate Compression variant.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bz2" => Ok(Compression::Bzip2),
+            "gz" => Ok(Compression::Gzip),
+            "zst" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Compression {
+    /// Detects compression from the first few bytes of a stream.
+    fn from_magic_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits a path's extension into the data format's extension and the compression, if any,
+/// so `planet.osm.bz2` becomes `(Some("osm"), Some(Compression::Bzip2))`.
+pub fn split_extension(path: &Path) -> (Option<String>, Option<Compression>) {
+    let Some(ext) = path.extension().and_then(|e| e.to_str()) else {
+        return (None, None);
+    };
+    match Compression::from_str(ext) {
+        Ok(compression) => {
+            let inner = path
+                .file_stem()
+                .and_then(|stem| Path::new(stem).extension())
+                .and_then(|e| e.to_str())
+                .map(str::to_owned);
+            (inner, Some(compression))
+        }
+        Err(_) => (Some(ext.to_owned()), None),
+    }
+}
+
+/// Wraps a stream in the decoder matching its magic bytes, so compressed
+/// files (or compressed data piped to stdin) are read transparently.
+pub fn decompress(src: Box<dyn Read + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+    let mut src = BufReader::new(src);
+    let compression = Compression::from_magic_bytes(src.fill_buf()?);
+    Ok(match compression {
+        None => Box::new(src),
+        Some(Compression::Bzip2) => Box::new(BufReader::new(MultiBzDecoder::new(src))),
+        Some(Compression::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(src))),
+        Some(Compression::Zstd) => Box::new(BufReader::new(zstd::Decoder::with_buffer(src)?)),
+    })
+}
+
+/// A destination that may compress what is written to it. `finish` must
+/// be called once writing is done, to write out the end of the stream.
+pub enum CompressedWriter<W: Write> {
+    Uncompressed(W),
+    Bzip2(BzEncoder<W>),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(dest: W, compression: Option<Compression>) -> io::Result<Self> {
+        Ok(match compression {
+            None => CompressedWriter::Uncompressed(dest),
+            Some(Compression::Bzip2) => {
+                CompressedWriter::Bzip2(BzEncoder::new(dest, bzip2::Compression::default()))
+            }
+            Some(Compression::Gzip) => {
+                CompressedWriter::Gzip(GzEncoder::new(dest, flate2::Compression::default()))
+            }
+            Some(Compression::Zstd) => CompressedWriter::Zstd(zstd::Encoder::new(dest, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressedWriter::Uncompressed(mut w) => w.flush().map(|_| w),
+            CompressedWriter::Bzip2(e) => e.finish(),
+            CompressedWriter::Gzip(e) => e.finish(),
+            CompressedWriter::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Uncompressed(w) => w.write(buf),
+            CompressedWriter::Bzip2(e) => e.write(buf),
+            CompressedWriter::Gzip(e) => e.write(buf),
+            CompressedWriter::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Uncompressed(w) => w.flush(),
+            CompressedWriter::Bzip2(e) => e.flush(),
+            CompressedWriter::Gzip(e) => e.flush(),
+            CompressedWriter::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_extension() {
+        assert_eq!(
+            split_extension(Path::new("planet.osm.bz2")),
+            (Some(String::from("osm")), Some(Compression::Bzip2))
+        );
+        assert_eq!(
+            split_extension(Path::new("data/1234.osc.gz")),
+            (Some(String::from("osc")), Some(Compression::Gzip))
+        );
+        assert_eq!(
+            split_extension(Path::new("extract.pbf")),
+            (Some(String::from("pbf")), None)
+        );
+        assert_eq!(
+            split_extension(Path::new("archive.zst")),
+            (None, Some(Compression::Zstd))
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for compression in [Compression::Bzip2, Compression::Gzip, Compression::Zstd] {
+            let mut writer = CompressedWriter::new(Vec::new(), Some(compression)).unwrap();
+            writer.write_all(b"n1 v1 x1 y1\n").unwrap();
+            let compressed = writer.finish().unwrap();
+
+            let mut output = String::new();
+            decompress(Box::new(io::Cursor::new(compressed)))
+                .unwrap()
+                .read_to_string(&mut output)
+                .unwrap();
+            assert_eq!(output, "n1 v1 x1 y1\n");
+        }
+    }
+}
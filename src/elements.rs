@@ -1,6 +1,7 @@
 //! A data structure for OpenStreetMap element data.
 
 use std::collections::HashMap;
+use std::str::FromStr;
 
 /// Element types without any additional metadata.
 #[derive(Debug)]
@@ -26,6 +27,14 @@ pub enum ElementType {
     Relation { members: Vec<Member> },
 }
 
+/// What an OsmChange file does with an element.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Create,
+    Modify,
+    Delete,
+}
+
 /// An OpenStreetMap element.
 #[derive(Debug)]
 pub struct Element {
@@ -38,6 +47,50 @@ pub struct Element {
     pub visible: Option<bool>,
     pub tags: HashMap<String, String>,
     pub element_type: ElementType,
+    /// The change this element belongs to, if it was read from an OsmChange file.
+    pub action: Option<Action>,
+}
+
+/// A bounding box, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+impl Bounds {
+    /// Whether a location is inside the box, including its edges.
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        (self.min_lat..=self.max_lat).contains(&lat) && (self.min_lon..=self.max_lon).contains(&lon)
+    }
+}
+
+impl FromStr for Bounds {
+    type Err = String;
+
+    /// Parses a bounding box given as `minlon,minlat,maxlon,maxlat`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid bounding box {s:?}: {e}"))?;
+        match values[..] {
+            [min_lon, min_lat, max_lon, max_lat] if min_lon <= max_lon && min_lat <= max_lat => {
+                Ok(Bounds {
+                    min_lat,
+                    min_lon,
+                    max_lat,
+                    max_lon,
+                })
+            }
+            _ => Err(format!(
+                "Invalid bounding box {s:?}, expected minlon,minlat,maxlon,maxlat"
+            )),
+        }
+    }
 }
 
 /// Document-level metadata.
@@ -48,4 +101,24 @@ pub struct Metadata {
     pub copyright: Option<String>,
     pub license: Option<String>,
     pub timestamp: Option<String>,
+    pub bbox: Option<Bounds>,
+    pub replication_sequence_number: Option<i64>,
+    pub replication_base_url: Option<String>,
+    /// Features a reader must support to understand the source file (e.g. PBF's `DenseNodes`).
+    pub required_features: Vec<String>,
+    /// Features the source file has that a reader may ignore (e.g. PBF's `Sort.Type_then_ID`).
+    pub optional_features: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bounds() {
+        let bounds: Bounds = "-77.5,37.5,-77.4,37.6".parse().unwrap();
+        assert_eq!((bounds.min_lon, bounds.max_lat), (-77.5, 37.6));
+        assert!("-77.4,37.5,-77.5,37.6".parse::<Bounds>().is_err());
+        assert!("1,2,3".parse::<Bounds>().is_err());
+    }
 }
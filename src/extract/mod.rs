@@ -0,0 +1,340 @@
+//! Cuts geographic extracts out of OSM data.
+
+use indicatif::ProgressBar;
+use std::collections::HashSet;
+use std::io;
+use std::mem;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+
+use crate::channel::ChunkSender;
+use crate::elements::{Bounds, Element, ElementType, SimpleElementType};
+use crate::progress::start_spinner;
+use crate::spool::Spool;
+use crate::SkywayError;
+
+mod polygon;
+pub use polygon::Polygon;
+
+/// The area an extract is cut from.
+#[derive(Debug)]
+pub enum Region {
+    Bounds(Bounds),
+    Polygon(Polygon),
+}
+
+impl Region {
+    /// Whether a location is inside the region.
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match self {
+            Region::Bounds(b) => b.contains(lat, lon),
+            Region::Polygon(p) => p.contains(lat, lon),
+        }
+    }
+
+    /// The smallest bounding box around the region.
+    pub fn bounds(&self) -> Bounds {
+        match self {
+            Region::Bounds(b) => *b,
+            Region::Polygon(p) => p.bounds(),
+        }
+    }
+}
+
+/// Which elements an extract keeps, following osmium's strategies of the same names.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtractStrategy {
+    /// Nodes inside the region, ways with at least one of those nodes,
+    /// and relations with any of those nodes or ways (or relations) as members.
+    Simple,
+    /// Like `Simple`, but ways are complete: every node they reference is kept.
+    CompleteWays,
+    /// Like `CompleteWays`, but multipolygon relations are complete too:
+    /// every way they reference (and those ways' nodes) is kept.
+    Smart,
+}
+
+impl FromStr for ExtractStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "simple" => Ok(ExtractStrategy::Simple),
+            "complete_ways" => Ok(ExtractStrategy::CompleteWays),
+            "smart" => Ok(ExtractStrategy::Smart),
+            _ => Err(format!("Unknown extract strategy: {s}")),
+        }
+    }
+}
+
+// the members of a relation, kept in memory so that
+// relations referencing other relations can be resolved
+struct RelationMembers {
+    id: i64,
+    is_multipolygon: bool,
+    nodes: Vec<i64>,
+    ways: Vec<i64>,
+    relations: Vec<i64>,
+}
+
+impl RelationMembers {
+    fn from(element: &Element) -> Option<Self> {
+        let ElementType::Relation { members } = &element.element_type else {
+            return None;
+        };
+        let mut relation = RelationMembers {
+            id: element.id,
+            is_multipolygon: element.tags.get("type").map(String::as_str) == Some("multipolygon"),
+            nodes: Vec::new(),
+            ways: Vec::new(),
+            relations: Vec::new(),
+        };
+        for member in members {
+            match member.t {
+                Some(SimpleElementType::Node) => relation.nodes.push(member.id),
+                Some(SimpleElementType::Way) => relation.ways.push(member.id),
+                Some(SimpleElementType::Relation) => relation.relations.push(member.id),
+                None => {}
+            }
+        }
+        Some(relation)
+    }
+}
+
+// the IDs of every element that will be in the extract
+#[derive(Default)]
+struct Selection {
+    nodes: HashSet<i64>,
+    ways: HashSet<i64>,
+    relations: HashSet<i64>,
+}
+
+impl Selection {
+    fn contains(&self, element: &Element) -> bool {
+        match element.element_type {
+            ElementType::Node { .. } => self.nodes.contains(&element.id),
+            ElementType::Way { .. } => self.ways.contains(&element.id),
+            ElementType::Relation { .. } => self.relations.contains(&element.id),
+        }
+    }
+}
+
+// first pass: spool the input, collecting the nodes inside the region and every relation
+fn spool_input(
+    receiver: Receiver<Vec<Element>>,
+    region: &Region,
+) -> io::Result<(Spool, HashSet<i64>, Vec<RelationMembers>)> {
+    let mut inside_nodes = HashSet::new();
+    let mut relations = Vec::new();
+    let mut spool = Spool::new()?;
+    for element in receiver.iter().flatten() {
+        match element.element_type {
+            ElementType::Node { lat, lon } if region.contains(lat, lon) => {
+                inside_nodes.insert(element.id);
+            }
+            ElementType::Relation { .. } => relations.extend(RelationMembers::from(&element)),
+            _ => {}
+        }
+        spool.push(&element)?;
+    }
+    Ok((spool, inside_nodes, relations))
+}
+
+fn extract(
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    region: Region,
+    strategy: ExtractStrategy,
+) -> Result<(), SkywayError> {
+    let (mut spool, inside_nodes, relations) = spool_input(receiver, &region)?;
+    let mut selection = Selection::default();
+
+    // second pass: ways with a node inside the region, and (unless the
+    // strategy is simple) the nodes those ways reference
+    let mut way_nodes = HashSet::new();
+    for element in spool.iter()? {
+        let element = element?;
+        if let ElementType::Way { nodes } = element.element_type {
+            if nodes.iter().any(|n| inside_nodes.contains(n)) {
+                selection.ways.insert(element.id);
+                if strategy != ExtractStrategy::Simple {
+                    way_nodes.extend(nodes);
+                }
+            }
+        }
+    }
+
+    // relations with a member inside the region, then relations with those as
+    // members, until there are no more to add
+    for relation in &relations {
+        if relation.nodes.iter().any(|n| inside_nodes.contains(n))
+            || relation.ways.iter().any(|w| selection.ways.contains(w))
+        {
+            selection.relations.insert(relation.id);
+        }
+    }
+    loop {
+        let parents: Vec<i64> = relations
+            .iter()
+            .filter(|r| !selection.relations.contains(&r.id))
+            .filter(|r| r.relations.iter().any(|m| selection.relations.contains(m)))
+            .map(|r| r.id)
+            .collect();
+        if parents.is_empty() {
+            break;
+        }
+        selection.relations.extend(parents);
+    }
+
+    // third pass (smart only): complete the ways of multipolygon relations
+    if strategy == ExtractStrategy::Smart {
+        let multipolygon_ways: HashSet<i64> = relations
+            .iter()
+            .filter(|r| r.is_multipolygon && selection.relations.contains(&r.id))
+            .flat_map(|r| r.ways.iter().copied())
+            .filter(|w| !selection.ways.contains(w))
+            .collect();
+        if !multipolygon_ways.is_empty() {
+            for element in spool.iter()? {
+                let element = element?;
+                if let ElementType::Way { nodes } = element.element_type {
+                    if multipolygon_ways.contains(&element.id) {
+                        way_nodes.extend(nodes);
+                    }
+                }
+            }
+            selection.ways.extend(multipolygon_ways);
+        }
+    }
+    selection.nodes = inside_nodes;
+    selection.nodes.extend(way_nodes);
+
+    // final pass: send everything that was selected, in input order
+    let mut output = Vec::new();
+    for element in spool.iter()? {
+        let element = element?;
+        if !selection.contains(&element) {
+            continue;
+        }
+        output.push(element);
+        if output.len() >= 1000 {
+            sender.send(mem::take(&mut output))?;
+        }
+    }
+    if !output.is_empty() {
+        sender.send(output)?;
+    }
+    Ok(())
+}
+
+/// Extracts the elements in a region. The input is written to a temporary file,
+/// which is read several times to find every element the strategy keeps.
+///
+/// * `receiver`: Receiver for a channel of `Element`s.
+/// * `sender`: Sender for a channel of `Element`s.
+/// * `region`: The bounding box or polygon to extract.
+/// * `strategy`: Which elements to keep.
+/// * `progress`: The ProgressBar for this operation.
+pub fn extract_elements(
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    region: Region,
+    strategy: ExtractStrategy,
+    progress: ProgressBar,
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Extracting elements...");
+
+    extract(receiver, sender, region, strategy)?;
+
+    progress.finish_with_message("Extracting elements...done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::elements::Member;
+    use crate::fixtures::element;
+    use crate::sort::sort_key;
+    use std::sync::mpsc::channel;
+
+    fn relation(id: i64, t: SimpleElementType, member: i64, tags: &[(&str, &str)]) -> Element {
+        let members = vec![Member {
+            t: Some(t),
+            id: member,
+            role: None,
+        }];
+        element(id, ElementType::Relation { members }, tags)
+    }
+
+    fn run(strategy: ExtractStrategy) -> Vec<(u8, i64)> {
+        let node = |id, lon| element(id, ElementType::Node { lat: 0.5, lon }, &[]);
+        let way = |id, nodes| element(id, ElementType::Way { nodes }, &[]);
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                node(1, 0.5),
+                node(2, 1.5),
+                node(3, 2.5),
+                node(4, 3.5),
+                way(10, vec![1, 2]),
+                way(11, vec![3, 4]),
+                relation(20, SimpleElementType::Way, 11, &[("type", "multipolygon")]),
+                relation(21, SimpleElementType::Node, 1, &[]),
+                relation(22, SimpleElementType::Relation, 21, &[]),
+                relation(23, SimpleElementType::Way, 11, &[]),
+            ])
+            .unwrap();
+        // the multipolygon is only in the extract through its other member, node 1
+        sender
+            .send(vec![{
+                let mut r = relation(24, SimpleElementType::Node, 1, &[("type", "multipolygon")]);
+                if let ElementType::Relation { members } = &mut r.element_type {
+                    members.push(Member {
+                        t: Some(SimpleElementType::Way),
+                        id: 11,
+                        role: Some(String::from("outer")),
+                    });
+                }
+                r
+            }])
+            .unwrap();
+        drop(sender);
+
+        let region = Region::Bounds("0,0,1,1".parse().unwrap());
+        let (output_sender, output_receiver) = chunk_channel(16);
+        extract(receiver, output_sender, region, strategy).unwrap();
+        output_receiver
+            .iter()
+            .flatten()
+            .map(|e| sort_key(&e))
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_strategies() {
+        assert_eq!(
+            run(ExtractStrategy::Simple),
+            vec![(0, 1), (1, 10), (2, 21), (2, 22), (2, 24)]
+        );
+        assert_eq!(
+            run(ExtractStrategy::CompleteWays),
+            vec![(0, 1), (0, 2), (1, 10), (2, 21), (2, 22), (2, 24)]
+        );
+        assert_eq!(
+            run(ExtractStrategy::Smart),
+            vec![
+                (0, 1),
+                (0, 2),
+                (0, 3),
+                (0, 4),
+                (1, 10),
+                (1, 11),
+                (2, 21),
+                (2, 22),
+                (2, 24)
+            ]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Reads extract polygons from Osmosis `.poly` files and GeoJSON.
+
+use serde_json::Value;
+
+use crate::areas::ring_contains;
+use crate::elements::Bounds;
+
+// a closed ring of (lon, lat) points
+type Ring = Vec<(f64, f64)>;
+
+// rings are applied in order: a point inside an outer ring is added to
+// the area, and a point inside a hole is taken back out of it
+#[derive(Debug)]
+struct Area {
+    rings: Vec<(Ring, bool)>,
+}
+
+/// An area made up of one or more polygons, each of which may have holes.
+#[derive(Debug)]
+pub struct Polygon {
+    areas: Vec<Area>,
+    bounds: Bounds,
+}
+
+impl Area {
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        let mut inside = false;
+        for (ring, is_hole) in &self.rings {
+            if ring_contains(ring, lat, lon) {
+                inside = !is_hole;
+            }
+        }
+        inside
+    }
+}
+
+impl Polygon {
+    fn new(areas: Vec<Area>) -> Result<Self, String> {
+        let mut points = areas
+            .iter()
+            .flat_map(|a| a.rings.iter())
+            .filter(|(_, is_hole)| !is_hole)
+            .flat_map(|(ring, _)| ring.iter());
+        let Some(&(lon, lat)) = points.next() else {
+            return Err(String::from("Polygon has no outer rings"));
+        };
+        let mut bounds = Bounds {
+            min_lat: lat,
+            min_lon: lon,
+            max_lat: lat,
+            max_lon: lon,
+        };
+        for &(lon, lat) in points {
+            bounds.min_lat = bounds.min_lat.min(lat);
+            bounds.min_lon = bounds.min_lon.min(lon);
+            bounds.max_lat = bounds.max_lat.max(lat);
+            bounds.max_lon = bounds.max_lon.max(lon);
+        }
+        Ok(Polygon { areas, bounds })
+    }
+
+    /// Whether a location is inside the polygon.
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        self.bounds.contains(lat, lon) && self.areas.iter().any(|a| a.contains(lat, lon))
+    }
+
+    /// The smallest bounding box around the polygon.
+    pub fn bounds(&self) -> Bounds {
+        self.bounds
+    }
+
+    /// Parses an Osmosis polygon filter file. Sections are applied in order,
+    /// and sections whose names start with `!` are holes.
+    pub fn from_poly(contents: &str) -> Result<Self, String> {
+        let mut lines = contents.lines().map(str::trim).filter(|l| !l.is_empty());
+        // the first line is the polygon's name
+        lines.next().ok_or("Polygon file is empty")?;
+
+        let mut rings = Vec::new();
+        loop {
+            let section = lines
+                .next()
+                .ok_or("Polygon file is missing its final END")?;
+            if section == "END" {
+                break;
+            }
+            let mut ring = Vec::new();
+            loop {
+                let line = lines
+                    .next()
+                    .ok_or_else(|| format!("Section {section} is missing its END"))?;
+                if line == "END" {
+                    break;
+                }
+                let point = line
+                    .split_whitespace()
+                    .map(|v| v.parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("Invalid point {line:?}: {e}"))?;
+                match point[..] {
+                    [lon, lat] => ring.push((lon, lat)),
+                    _ => return Err(format!("Invalid point {line:?}")),
+                }
+            }
+            rings.push((ring, section.starts_with('!')));
+        }
+        Polygon::new(vec![Area { rings }])
+    }
+
+    /// Parses a GeoJSON Polygon or MultiPolygon, which may be a bare geometry,
+    /// a Feature, or a FeatureCollection (in which case every polygon in it is used).
+    pub fn from_geojson(contents: &str) -> Result<Self, String> {
+        let value: Value =
+            serde_json::from_str(contents).map_err(|e| format!("Invalid GeoJSON: {e}"))?;
+        let mut areas = Vec::new();
+        collect_areas(&value, &mut areas)?;
+        Polygon::new(areas)
+    }
+}
+
+fn parse_ring(value: &Value) -> Result<Ring, String> {
+    value
+        .as_array()
+        .ok_or("GeoJSON ring is not an array")?
+        .iter()
+        .map(|p| match p.as_array().map(|p| (p.first(), p.get(1))) {
+            Some((Some(lon), Some(lat))) => match (lon.as_f64(), lat.as_f64()) {
+                (Some(lon), Some(lat)) => Ok((lon, lat)),
+                _ => Err(format!("Invalid GeoJSON position: {p}")),
+            },
+            _ => Err(format!("Invalid GeoJSON position: {p}")),
+        })
+        .collect()
+}
+
+// the first ring of a GeoJSON polygon is its outer ring, and the rest are holes
+fn parse_polygon(value: &Value) -> Result<Area, String> {
+    let rings = value
+        .as_array()
+        .ok_or("GeoJSON polygon coordinates are not an array")?
+        .iter()
+        .enumerate()
+        .map(|(i, ring)| parse_ring(ring).map(|r| (r, i > 0)))
+        .collect::<Result<_, _>>()?;
+    Ok(Area { rings })
+}
+
+fn collect_areas(value: &Value, areas: &mut Vec<Area>) -> Result<(), String> {
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in value["features"].as_array().into_iter().flatten() {
+                collect_areas(feature, areas)?;
+            }
+        }
+        Some("Feature") => collect_areas(&value["geometry"], areas)?,
+        Some("Polygon") => areas.push(parse_polygon(&value["coordinates"])?),
+        Some("MultiPolygon") => {
+            for polygon in value["coordinates"]
+                .as_array()
+                .ok_or("GeoJSON multipolygon coordinates are not an array")?
+            {
+                areas.push(parse_polygon(polygon)?);
+            }
+        }
+        // other geometries in a FeatureCollection are skipped
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_poly() {
+        let polygon = Polygon::from_poly(
+            "richmond
+1
+   -77.6   37.4
+   -77.3   37.4
+   -77.3   37.7
+   -77.6   37.7
+END
+!2
+   -77.5   37.5
+   -77.4   37.5
+   -77.4   37.6
+   -77.5   37.6
+END
+END
+",
+        )
+        .unwrap();
+        assert!(polygon.contains(37.45, -77.45));
+        assert!(!polygon.contains(37.55, -77.45));
+        assert!(!polygon.contains(37.8, -77.45));
+        assert_eq!(polygon.bounds().max_lat, 37.7);
+    }
+
+    #[test]
+    fn test_from_geojson() {
+        // a triangle, and a square with a hole that has an island in it
+        let polygon = Polygon::from_geojson(
+            r#"{"type": "Feature", "properties": {}, "geometry": {"type": "MultiPolygon", "coordinates": [
+                [[[0, 0], [2, 0], [0, 2], [0, 0]]],
+                [[[10, 10], [20, 10], [20, 20], [10, 20], [10, 10]],
+                 [[12, 12], [18, 12], [18, 18], [12, 18], [12, 12]]],
+                [[[14, 14], [16, 14], [16, 16], [14, 16], [14, 14]]]
+            ]}}"#,
+        )
+        .unwrap();
+        assert!(polygon.contains(0.5, 0.5));
+        assert!(!polygon.contains(1.5, 1.5));
+        assert!(polygon.contains(11.0, 11.0));
+        assert!(!polygon.contains(13.0, 13.0));
+        assert!(polygon.contains(15.0, 15.0));
+        assert!(Polygon::from_geojson(r#"{"type": "Point", "coordinates": [0, 0]}"#).is_err());
+    }
+}
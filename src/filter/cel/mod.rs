@@ -1,19 +1,52 @@
 use crate::filter::ElementFilter;
-use cel_interpreter::{Context, Program, Value};
+use cel_interpreter::{Context, ExecutionError, Program, Value};
+use serde::Serialize;
 
+use crate::areas::is_area;
 use crate::elements::{Element, ElementType};
+use crate::locations::{LocationIndex, SharedLocationIndex};
+use crate::SkywayError;
 
-pub struct CelFilter(Program);
+pub struct CelFilter {
+    program: Program,
+    locations: Option<SharedLocationIndex>,
+}
+
+#[derive(Serialize)]
+struct Coordinate {
+    lat: f64,
+    lon: f64,
+}
 
-#[allow(unused_variables)] // I expect to need element in future iterations of this function
-fn convert_filter_output(value: &Value, element: &mut Element) -> bool {
+// a node's own location, or the locations of a way's nodes that can be found
+fn coordinates(element: &Element, locations: Option<&dyn LocationIndex>) -> Vec<Coordinate> {
+    match (&element.element_type, locations) {
+        (ElementType::Node { lat, lon }, _) => vec![Coordinate {
+            lat: *lat,
+            lon: *lon,
+        }],
+        (ElementType::Way { nodes }, Some(locations)) => nodes
+            .iter()
+            .filter_map(|n| locations.get(*n))
+            .map(|(lat, lon)| Coordinate { lat, lon })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// filters can be any CEL expression, so whether one returns a boolean
+// is only known once it runs
+fn convert_filter_output(value: &Value, element: &mut Element) -> Result<bool, SkywayError> {
     match value {
-        Value::Bool(keep_element) => *keep_element,
-        _ => panic!("Unexpected output from CEL filter (not a boolean): {value:?}"),
+        Value::Bool(keep_element) => Ok(*keep_element),
+        _ => Err(SkywayError::FilterError(format!(
+            "CEL filter returned {value:?} for element {}, not a boolean",
+            element.id
+        ))),
     }
 }
 
-fn generate_context<'a>(element: &Element) -> Context<'a> {
+fn generate_context<'a>(element: &Element, locations: Option<&dyn LocationIndex>) -> Context<'a> {
     let mut context = Context::default();
     context
         .add_variable("tags", element.tags.to_owned())
@@ -40,28 +73,75 @@ fn generate_context<'a>(element: &Element) -> Context<'a> {
             },
         )
         .unwrap();
+    context.add_variable("area", is_area(element)).unwrap();
+    context
+        .add_variable("coordinates", coordinates(element, locations))
+        .unwrap();
     context
 }
 
 impl ElementFilter for CelFilter {
-    fn evaluate(&self, element: &mut Element) -> bool {
-        let context = generate_context(&element);
-        match &self.0.execute(&context) {
+    fn evaluate(&self, element: &mut Element) -> Result<bool, SkywayError> {
+        let index = match &self.locations {
+            Some(locations) => Some(locations.read().map_err(|_| {
+                SkywayError::FilterError(String::from("Location index lock is poisoned"))
+            })?),
+            None => None,
+        };
+        let context = generate_context(element, index.as_deref().map(|i| i.as_ref()));
+        match &self.program.execute(&context) {
             Ok(o) => convert_filter_output(o, element),
-            Err(e) => {
-                eprintln!("Unable to execute filter for element: {e:?}, skipping...");
-                false
-            }
+            // an element without the tag (or key) an expression looks up doesn't match it
+            Err(ExecutionError::NoSuchKey(_)) => Ok(false),
+            Err(e) => Err(SkywayError::FilterError(format!(
+                "Unable to execute CEL filter for element {}: {e}",
+                element.id
+            ))),
         }
     }
+
+    fn set_location_index(&mut self, locations: SharedLocationIndex) {
+        self.locations = Some(locations);
+    }
 }
 
-pub fn compile_cel_filter(filter_content: &str) -> Option<CelFilter> {
-    let program = match Program::compile(filter_content) {
-        Ok(p) => p,
-        Err(e) => {
-            panic!("Error parsing CEL filter: {e:?}");
-        }
-    };
-    Some(CelFilter(program))
+pub fn compile_cel_filter(filter_content: &str) -> Result<CelFilter, SkywayError> {
+    let program = Program::compile(filter_content)
+        .map_err(|e| SkywayError::FilterError(format!("Invalid CEL expression\n{e}")))?;
+    Ok(CelFilter {
+        program,
+        locations: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::element;
+
+    #[test]
+    fn test_evaluate() {
+        let mut element = element(
+            4,
+            ElementType::Node { lat: 1.0, lon: 2.0 },
+            &[("amenity", "cafe")],
+        );
+        let filter = compile_cel_filter("tags.amenity == 'cafe'").unwrap();
+        assert!(filter.evaluate(&mut element).unwrap());
+
+        let filter = compile_cel_filter("tags.highway == 'path'").unwrap();
+        assert!(!filter.evaluate(&mut element).unwrap());
+
+        let filter = compile_cel_filter("tags.amenity + 1 == 2").unwrap();
+        assert!(matches!(
+            filter.evaluate(&mut element),
+            Err(SkywayError::FilterError(_))
+        ));
+
+        let filter = compile_cel_filter("'abc'").unwrap();
+        assert!(matches!(
+            filter.evaluate(&mut element),
+            Err(SkywayError::FilterError(_))
+        ));
+    }
 }
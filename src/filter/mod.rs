@@ -2,29 +2,43 @@
 
 mod cel;
 mod osmfilter;
+mod referenced;
 
 use cel::compile_cel_filter;
 use indicatif::ProgressBar;
 use osmfilter::parse::parse_filter;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Receiver;
 
+use crate::channel::ChunkSender;
 use crate::elements::Element;
+use crate::locations::SharedLocationIndex;
+use crate::progress::start_spinner;
+use crate::SkywayError;
+
+pub use referenced::filter_elements_with_references;
 
 /// Represents a filter that can be evaluated on an `Element`, transforming it.
 pub trait ElementFilter: Send {
-    fn evaluate(&self, element: &mut Element) -> bool;
+    /// Whether to keep the element. Returns an error if the filter can't be
+    /// evaluated at all, such as a CEL expression that doesn't return a boolean.
+    fn evaluate(&self, element: &mut Element) -> Result<bool, SkywayError>;
+
+    /// Gives the filter a location index filled in by an earlier stage, which it can use
+    /// to look up the locations of the nodes that ways reference. Filters that don't
+    /// need locations ignore it.
+    fn set_location_index(&mut self, _locations: SharedLocationIndex) {}
 }
 
-pub fn create_filter(filter_contents: &str) -> Box<dyn ElementFilter> {
-    let osmfilter = parse_filter(filter_contents);
-    if let Some(f) = osmfilter {
-        return Box::new(f);
-    }
-    let celfilter = compile_cel_filter(filter_contents);
-    if let Some(f) = celfilter {
-        return Box::new(f);
+/// Parses a filter, which is an OSMFilter file if it starts with an OSMFilter header
+/// and a CEL expression otherwise.
+pub fn create_filter(filter_contents: &str) -> Result<Box<dyn ElementFilter>, SkywayError> {
+    if filter_contents.trim_start().starts_with("OSMFilter") {
+        return match parse_filter(filter_contents) {
+            Ok(f) => Ok(Box::new(f)),
+            Err(e) => Err(SkywayError::FilterError(e)),
+        };
     }
-    panic!("Unable to parse filter: {filter_contents:?}");
+    Ok(Box::new(compile_cel_filter(filter_contents)?))
 }
 
 /// Filters OSM data.
@@ -36,30 +50,20 @@ pub fn create_filter(filter_contents: &str) -> Box<dyn ElementFilter> {
 pub fn filter_elements(
     filter: Box<dyn ElementFilter>,
     receiver: Receiver<Vec<Element>>,
-    sender: Sender<Vec<Element>>,
+    sender: ChunkSender,
     progress: ProgressBar,
-) {
-    progress.set_message("Filtering elements...");
-    let progress_clone = progress.clone();
-    std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        progress_clone.tick();
-        if progress_clone.is_finished() {
-            break;
-        }
-    });
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Filtering elements...");
 
-    receiver
-        .iter()
-        .map(|c| {
-            let mut keep_elements = Vec::new();
-            for mut element in c {
-                if filter.evaluate(&mut element) {
-                    keep_elements.push(element);
-                }
+    for chunk in receiver {
+        let mut keep_elements = Vec::new();
+        for mut element in chunk {
+            if filter.evaluate(&mut element)? {
+                keep_elements.push(element);
             }
-            keep_elements
-        })
-        .for_each(|c| sender.send(c).expect("Unable to send element to channel"));
+        }
+        sender.send(keep_elements)?;
+    }
     progress.finish_with_message("Filtering elements...done");
+    Ok(())
 }
@@ -1,5 +1,7 @@
+use crate::areas::is_area;
 use crate::elements::{Element, ElementType};
 use crate::filter::ElementFilter;
+use crate::SkywayError;
 
 #[derive(Debug)]
 pub enum SelectorStatement {
@@ -7,6 +9,8 @@ pub enum SelectorStatement {
         node: bool,
         way: bool,
         relation: bool,
+        // closed ways tagged as areas, and multipolygon and boundary relations
+        area: bool,
     },
     Has {
         key: String,
@@ -23,11 +27,15 @@ fn test_selector(selector: &SelectorStatement, element: &Element) -> bool {
             node,
             way,
             relation,
-        } => match &element.element_type {
-            ElementType::Node { .. } => node.to_owned(),
-            ElementType::Way { .. } => way.to_owned(),
-            ElementType::Relation { .. } => relation.to_owned(),
-        },
+            area,
+        } => {
+            let type_selected = match &element.element_type {
+                ElementType::Node { .. } => node.to_owned(),
+                ElementType::Way { .. } => way.to_owned(),
+                ElementType::Relation { .. } => relation.to_owned(),
+            };
+            type_selected || (*area && is_area(element))
+        }
         SelectorStatement::Has { key } => element.tags.contains_key(key.as_str()),
         SelectorStatement::Equals { key, value } => match element.tags.get(key.as_str()) {
             Some(v) => v == value,
@@ -114,14 +122,39 @@ pub struct OsmFilter {
 }
 
 impl ElementFilter for OsmFilter {
-    fn evaluate(&self, element: &mut Element) -> bool {
+    fn evaluate(&self, element: &mut Element) -> Result<bool, SkywayError> {
         for statement in &self.statements {
             match evaluate_statement(statement, element) {
                 StatementResult::Continue => {}
-                StatementResult::Commit => return true,
-                StatementResult::Drop => return false,
+                StatementResult::Commit => return Ok(true),
+                StatementResult::Drop => return Ok(false),
             }
         }
-        true // commit element if we've exhausted all statements
+        Ok(true) // commit element if we've exhausted all statements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::osmfilter::parse::parse_filter;
+    use crate::fixtures::element;
+
+    fn way(nodes: Vec<i64>, tags: &[(&str, &str)]) -> Element {
+        element(1, ElementType::Way { nodes }, tags)
+    }
+
+    #[test]
+    fn test_area_selector() {
+        let filter = parse_filter("OSMFilter v0.2.0\n\nTYPE area\n\tCOMMIT\nDROP\n").unwrap();
+        assert!(filter
+            .evaluate(&mut way(vec![1, 2, 3, 1], &[("building", "yes")]))
+            .unwrap());
+        assert!(!filter
+            .evaluate(&mut way(vec![1, 2, 3], &[("building", "yes")]))
+            .unwrap());
+        assert!(!filter
+            .evaluate(&mut way(vec![1, 2, 3, 1], &[("highway", "service")]))
+            .unwrap());
     }
 }
@@ -46,6 +46,7 @@ fn parse_type_selector(pair: Pair<Rule>) -> SelectorStatement {
         node: types.contains(&Rule::node),
         way: types.contains(&Rule::way),
         relation: types.contains(&Rule::relation),
+        area: types.contains(&Rule::area),
     }
 }
 
@@ -108,13 +109,9 @@ fn _interpret_body(body: Pair<Rule>) -> OsmFilter {
     }
 }
 
-pub fn parse_filter(filter_content: &str) -> Option<OsmFilter> {
-    let mut file = match OSMFilterParser::parse(Rule::file, filter_content) {
-        Ok(v) => v,
-        Err(_) => {
-            return None;
-        }
-    };
+pub fn parse_filter(filter_content: &str) -> Result<OsmFilter, String> {
+    let mut file = OSMFilterParser::parse(Rule::file, filter_content)
+        .map_err(|e| format!("Invalid OSMFilter file\n{e}"))?;
 
     match file.next() {
         Some(a) => {
@@ -134,7 +131,7 @@ pub fn parse_filter(filter_content: &str) -> Option<OsmFilter> {
 
     match file.next() {
         Some(a) => match a.as_rule() {
-            Rule::body => Some(_interpret_body(a)),
+            Rule::body => Ok(_interpret_body(a)),
             _ => unreachable!(),
         },
         _ => unreachable!(),
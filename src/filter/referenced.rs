@@ -0,0 +1,256 @@
+//! Keeps filtered output referentially complete, by restoring the elements that kept
+//! ways (and, optionally, relations) reference.
+
+use indicatif::ProgressBar;
+use std::collections::HashSet;
+use std::io;
+use std::mem;
+use std::sync::mpsc::Receiver;
+
+use super::ElementFilter;
+use crate::channel::ChunkSender;
+use crate::elements::{Element, ElementType, Member, SimpleElementType};
+use crate::progress::start_spinner;
+use crate::spool::{write_element, Spool};
+use crate::SkywayError;
+
+// the elements that have to be restored, and the ways and
+// relations whose own references have been added already
+#[derive(Default)]
+struct References {
+    nodes: HashSet<i64>,
+    ways: HashSet<i64>,
+    relations: HashSet<i64>,
+    expanded_ways: HashSet<i64>,
+    expanded_relations: HashSet<i64>,
+}
+
+impl References {
+    fn add_members(&mut self, members: &[Member]) {
+        for member in members {
+            match member.t {
+                Some(SimpleElementType::Node) => self.nodes.insert(member.id),
+                Some(SimpleElementType::Way) => self.ways.insert(member.id),
+                Some(SimpleElementType::Relation) => self.relations.insert(member.id),
+                None => false,
+            };
+        }
+    }
+
+    // add everything an element references; returns whether it hadn't been added before
+    fn expand(&mut self, element: &Element, relation_members: bool) -> bool {
+        match &element.element_type {
+            ElementType::Node { .. } => false,
+            ElementType::Way { nodes } => {
+                let is_new = self.expanded_ways.insert(element.id);
+                if is_new {
+                    self.nodes.extend(nodes);
+                }
+                is_new
+            }
+            ElementType::Relation { members } => {
+                let is_new = relation_members && self.expanded_relations.insert(element.id);
+                if is_new {
+                    self.add_members(members);
+                }
+                is_new
+            }
+        }
+    }
+
+    fn contains(&self, element: &Element) -> bool {
+        match element.element_type {
+            ElementType::Node { .. } => self.nodes.contains(&element.id),
+            ElementType::Way { .. } => self.ways.contains(&element.id),
+            ElementType::Relation { .. } => self.relations.contains(&element.id),
+        }
+    }
+}
+
+// whether an element passes every filter, stopping at the first one it doesn't
+fn evaluate_all(
+    filters: &[Box<dyn ElementFilter>],
+    element: &mut Element,
+) -> Result<bool, SkywayError> {
+    for filter in filters {
+        if !filter.evaluate(element)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn filter_and_restore(
+    filters: Vec<Box<dyn ElementFilter>>,
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    relation_members: bool,
+) -> Result<(), SkywayError> {
+    // first pass: filter the input, holding on to the elements that were kept
+    // (as filtered) and the ones that weren't (as read) in two temporary files
+    let mut kept = Spool::new()?;
+    let mut kept_positions = Vec::new();
+    let mut dropped = Spool::new()?;
+    let mut references = References::default();
+    let mut encoded = Vec::new();
+    for (position, mut element) in receiver.iter().flatten().enumerate() {
+        encoded.clear();
+        write_element(&mut encoded, &element)?;
+        if evaluate_all(&filters, &mut element)? {
+            references.expand(&element, relation_members);
+            kept.push(&element)?;
+            kept_positions.push(position);
+        } else {
+            dropped.push_encoded(&encoded)?;
+        }
+    }
+
+    // the ways and relations that were restored may reference more
+    // elements, so keep going until nothing new is added
+    if relation_members {
+        loop {
+            let mut expanded = false;
+            for element in dropped.iter()? {
+                let element = element?;
+                if references.contains(&element) {
+                    expanded |= references.expand(&element, relation_members);
+                }
+            }
+            if !expanded {
+                break;
+            }
+        }
+    }
+
+    // final pass: interleave the kept elements with the restored ones, in input order
+    let mut output = Vec::new();
+    let mut send = |element| -> Result<(), SkywayError> {
+        output.push(element);
+        if output.len() >= 1000 {
+            sender.send(mem::take(&mut output))?;
+        }
+        Ok(())
+    };
+    let mut kept = kept.iter()?;
+    let mut kept_positions = kept_positions.into_iter().peekable();
+    let mut position = 0;
+    for element in dropped.iter()? {
+        let element = element?;
+        while kept_positions.next_if_eq(&position).is_some() {
+            match kept.next() {
+                Some(next) => send(next?)?,
+                None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            }
+            position += 1;
+        }
+        if references.contains(&element) {
+            send(element)?;
+        }
+        position += 1;
+    }
+    for element in kept {
+        send(element?)?;
+    }
+    if !output.is_empty() {
+        sender.send(output)?;
+    }
+    Ok(())
+}
+
+/// Filters OSM data, then restores every node that a kept way references, so that
+/// the output is still a valid OSM file. With `relation_members`, the members of kept
+/// relations (and their nodes and members, in turn) are restored too.
+/// Restored elements are written as they were read, without the filters applied.
+///
+/// * `filters`: The filters to apply, in order.
+/// * `receiver`: Receiver for a channel of `Element`s.
+/// * `sender`: Sender for a channel of `Element`s.
+/// * `relation_members`: Whether to restore the members of kept relations.
+/// * `progress`: The ProgressBar for this operation.
+pub fn filter_elements_with_references(
+    filters: Vec<Box<dyn ElementFilter>>,
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    relation_members: bool,
+    progress: ProgressBar,
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Filtering elements...");
+
+    filter_and_restore(filters, receiver, sender, relation_members)?;
+
+    progress.finish_with_message("Filtering elements...done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::filter::create_filter;
+    use crate::fixtures::element;
+    use std::sync::mpsc::channel;
+
+    fn member(t: SimpleElementType, id: i64) -> Member {
+        Member {
+            t: Some(t),
+            id,
+            role: None,
+        }
+    }
+
+    fn run(relation_members: bool) -> Vec<(i64, usize)> {
+        let node = |id, tags| element(id, ElementType::Node { lat: 1.0, lon: 2.0 }, tags);
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                node(1, &[("name", "Main Street")]),
+                node(2, &[]),
+                node(3, &[]),
+                node(4, &[]),
+                element(
+                    10,
+                    ElementType::Way { nodes: vec![1, 2] },
+                    &[("highway", "primary")],
+                ),
+                element(11, ElementType::Way { nodes: vec![3] }, &[]),
+                element(
+                    20,
+                    ElementType::Relation {
+                        members: vec![member(SimpleElementType::Way, 11)],
+                    },
+                    &[],
+                ),
+                element(
+                    21,
+                    ElementType::Relation {
+                        members: vec![member(SimpleElementType::Relation, 20)],
+                    },
+                    &[("highway", "pedestrian")],
+                ),
+            ])
+            .unwrap();
+        drop(sender);
+
+        // node 1 loses its name before it's dropped, but is restored as it was read
+        let filter = create_filter(
+            "OSMFilter v0.2.0\n\nTYPE node\n\tDELETE \"name\"\nHAS \"highway\"\n\tCOMMIT\nDROP\n",
+        )
+        .unwrap();
+        let (output_sender, output_receiver) = chunk_channel(16);
+        filter_and_restore(vec![filter], receiver, output_sender, relation_members).unwrap();
+        output_receiver
+            .iter()
+            .flatten()
+            .map(|e| (e.id, e.tags.len()))
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_and_restore() {
+        assert_eq!(run(false), vec![(1, 1), (2, 0), (10, 1), (21, 1)]);
+        assert_eq!(
+            run(true),
+            vec![(1, 1), (2, 0), (3, 0), (10, 1), (11, 0), (20, 0), (21, 1)]
+        );
+    }
+}
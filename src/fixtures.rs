@@ -0,0 +1,22 @@
+//! Elements for tests to build their input from.
+
+use crate::elements::{Element, ElementType};
+
+/// An element with the given tags and no metadata other than its version, which is 1.
+pub(crate) fn element(id: i64, element_type: ElementType, tags: &[(&str, &str)]) -> Element {
+    Element {
+        changeset: None,
+        user: None,
+        version: Some(1),
+        uid: None,
+        id,
+        timestamp: None,
+        visible: None,
+        tags: tags
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        element_type,
+        action: None,
+    }
+}
@@ -1,11 +1,53 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::mpsc::SendError;
 use thiserror::Error;
 
+pub mod apply;
+pub mod areas;
+pub mod channel;
+pub mod compression;
 pub mod elements;
+pub mod extract;
 pub mod filter;
+pub mod locations;
+pub mod pipeline;
 pub mod readers;
+pub mod sort;
 pub mod writers;
 
+#[cfg(test)]
+mod fixtures;
+mod progress;
+mod reorder;
+mod spool;
+mod stream;
 mod threadpools;
+mod timestamps;
+
+pub use pipeline::Pipeline;
+pub use stream::{open, open_with_options, read_elements, Elements, Writer};
+
+/// Where in the input a parse error was found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+    /// A line number, starting from 1, for line-based formats.
+    Line(usize),
+    /// A byte offset into the (decompressed) input.
+    Byte(u64),
+    /// Formats that don't keep track of where they are, like PBF.
+    Unknown,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Position::Line(line) => write!(f, "line {line}"),
+            Position::Byte(offset) => write!(f, "byte {offset}"),
+            Position::Unknown => f.write_str("an unknown position"),
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum SkywayError {
@@ -15,4 +57,28 @@ pub enum SkywayError {
     UnknownOutputFormat,
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("Unable to open {}: {source}", path.display())]
+    FileError {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not parse {format} input at {position}: {message}")]
+    ParseError {
+        format: &'static str,
+        position: Position,
+        message: String,
+    },
+    #[error("Filter error: {0}")]
+    FilterError(String),
+    /// A stage's channel closed early, which happens when the stage after it fails.
+    #[error("A pipeline stage stopped before all elements were passed to it")]
+    ChannelError,
+    #[error("{0}")]
+    InvalidInput(String),
+}
+
+impl<T> From<SendError<T>> for SkywayError {
+    fn from(_: SendError<T>) -> Self {
+        SkywayError::ChannelError
+    }
 }
@@ -0,0 +1,295 @@
+//! Indexes node locations, so that ways can be given geometries.
+
+use indicatif::ProgressBar;
+use memmap2::MmapMut;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, RwLock};
+
+use crate::channel::ChunkSender;
+use crate::elements::{Element, ElementType};
+use crate::progress::start_spinner;
+use crate::SkywayError;
+
+// locations are stored as fixed-point coordinates in units of 100 nanodegrees,
+// the same precision as the OSM database, which halves the memory they need
+const COORDINATE_SCALE: f64 = 1e7;
+
+// the mmap index grows its file at least this many locations (8 MiB) at a time
+const MIN_MMAP_CAPACITY: usize = 1 << 20;
+
+// the dense and mmap indexes keep IDs above this (several times the largest node ID
+// in OSM) in a sparse index, so that one stray ID can't make their arrays impossibly large
+const MAX_ARRAY_ID: i64 = 1 << 36;
+
+/// A lookup from node IDs to locations, filled in as nodes are read.
+pub trait LocationIndex: Send + Sync {
+    /// Stores a node's location. This can only fail for indexes that are kept in a file.
+    fn insert(&mut self, id: i64, lat: f64, lon: f64) -> io::Result<()>;
+
+    /// Returns a node's location as `(lat, lon)`, if it has been stored.
+    fn get(&self, id: i64) -> Option<(f64, f64)>;
+}
+
+/// A location index that can be filled by one pipeline stage while others read from it.
+pub type SharedLocationIndex = Arc<RwLock<Box<dyn LocationIndex>>>;
+
+/// Enum that represents the different ways node locations can be indexed.
+#[derive(Clone, Copy, Debug)]
+pub enum LocationIndexType {
+    /// A hash map, for extracts and other inputs with relatively few nodes.
+    Sparse,
+    /// An in-memory array indexed by node ID, for large inputs that fit in memory.
+    Dense,
+    /// An array indexed by node ID in a memory-mapped temporary file, for planet-scale inputs.
+    Mmap,
+}
+
+impl FromStr for LocationIndexType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sparse" => Ok(LocationIndexType::Sparse),
+            "dense" => Ok(LocationIndexType::Dense),
+            "mmap" => Ok(LocationIndexType::Mmap),
+            _ => Err(format!(
+                "Unknown location index: {s} (expected sparse, dense or mmap)"
+            )),
+        }
+    }
+}
+
+/// Creates an empty location index of the given type.
+pub fn create_location_index(
+    index_type: LocationIndexType,
+) -> Result<Box<dyn LocationIndex>, SkywayError> {
+    Ok(match index_type {
+        LocationIndexType::Sparse => Box::new(SparseLocationIndex::new()),
+        LocationIndexType::Dense => Box::new(DenseLocationIndex::new()),
+        LocationIndexType::Mmap => Box::new(MmapLocationIndex::new()?),
+    })
+}
+
+fn to_fixed(lat: f64, lon: f64) -> (i32, i32) {
+    (
+        (lat * COORDINATE_SCALE).round() as i32,
+        (lon * COORDINATE_SCALE).round() as i32,
+    )
+}
+
+fn from_fixed((lat, lon): (i32, i32)) -> (f64, f64) {
+    (lat as f64 / COORDINATE_SCALE, lon as f64 / COORDINATE_SCALE)
+}
+
+// the arrays pack each location into a u64 with the sign bits flipped, so that
+// zero (an unfilled slot) would be a latitude of -214 degrees, which can't happen
+fn pack(lat: f64, lon: f64) -> u64 {
+    let (lat, lon) = to_fixed(lat, lon);
+    ((lat as u32 ^ 0x8000_0000) as u64) << 32 | (lon as u32 ^ 0x8000_0000) as u64
+}
+
+fn unpack(packed: u64) -> Option<(f64, f64)> {
+    if packed == 0 {
+        return None;
+    }
+    let lat = ((packed >> 32) as u32 ^ 0x8000_0000) as i32;
+    let lon = (packed as u32 ^ 0x8000_0000) as i32;
+    Some(from_fixed((lat, lon)))
+}
+
+/// A location index backed by a hash map, which only uses memory for the nodes it holds.
+#[derive(Default)]
+pub struct SparseLocationIndex {
+    locations: HashMap<i64, (i32, i32)>,
+}
+
+impl SparseLocationIndex {
+    pub fn new() -> Self {
+        SparseLocationIndex::default()
+    }
+}
+
+impl LocationIndex for SparseLocationIndex {
+    fn insert(&mut self, id: i64, lat: f64, lon: f64) -> io::Result<()> {
+        self.locations.insert(id, to_fixed(lat, lon));
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<(f64, f64)> {
+        self.locations.get(&id).map(|&l| from_fixed(l))
+    }
+}
+
+// the slot for a node ID in the dense and mmap arrays, or None if it's negative or too
+// large and is kept in a sparse index instead. the end of the slot, in bytes, fits in a usize
+fn array_index(id: i64) -> Option<usize> {
+    if !(0..=MAX_ARRAY_ID).contains(&id) {
+        return None;
+    }
+    usize::try_from((id + 1) * 8).ok()?;
+    usize::try_from(id).ok()
+}
+
+/// A location index backed by an array in memory with a slot for every node ID up to
+/// the highest one seen, which takes 8 bytes per ID but is faster and, for large inputs,
+/// smaller than a sparse index. Negative IDs, and IDs above 2^36, are kept in a sparse
+/// index.
+#[derive(Default)]
+pub struct DenseLocationIndex {
+    locations: Vec<u64>,
+    outliers: SparseLocationIndex,
+}
+
+impl DenseLocationIndex {
+    pub fn new() -> Self {
+        DenseLocationIndex::default()
+    }
+}
+
+impl LocationIndex for DenseLocationIndex {
+    fn insert(&mut self, id: i64, lat: f64, lon: f64) -> io::Result<()> {
+        let Some(i) = array_index(id) else {
+            return self.outliers.insert(id, lat, lon);
+        };
+        if i >= self.locations.len() {
+            self.locations.resize(i + 1, 0);
+        }
+        self.locations[i] = pack(lat, lon);
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<(f64, f64)> {
+        let Some(i) = array_index(id) else {
+            return self.outliers.get(id);
+        };
+        self.locations.get(i).copied().and_then(unpack)
+    }
+}
+
+/// A location index laid out like a dense one, but in a temporary file that is mapped
+/// into memory, so the operating system can page it out. The file is sparse on most
+/// filesystems, so it only takes up disk space for the parts that are filled in.
+pub struct MmapLocationIndex {
+    file: File,
+    map: MmapMut,
+    outliers: SparseLocationIndex,
+}
+
+impl MmapLocationIndex {
+    pub fn new() -> io::Result<Self> {
+        let file = tempfile::tempfile()?;
+        let map = map_file(&file, MIN_MMAP_CAPACITY)?;
+        Ok(MmapLocationIndex {
+            file,
+            map,
+            outliers: SparseLocationIndex::new(),
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.map.len() / 8
+    }
+}
+
+fn map_file(file: &File, capacity: usize) -> io::Result<MmapMut> {
+    file.set_len(capacity as u64 * 8)?;
+    // SAFETY: the file is an unnamed temporary file that nothing else can open
+    unsafe { MmapMut::map_mut(file) }
+}
+
+impl LocationIndex for MmapLocationIndex {
+    fn insert(&mut self, id: i64, lat: f64, lon: f64) -> io::Result<()> {
+        let Some(i) = array_index(id) else {
+            return self.outliers.insert(id, lat, lon);
+        };
+        if i >= self.capacity() {
+            let capacity = (i + 1).checked_next_power_of_two().unwrap_or(i + 1);
+            self.map = map_file(&self.file, capacity)?;
+        }
+        self.map[i * 8..i * 8 + 8].copy_from_slice(&pack(lat, lon).to_le_bytes());
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Option<(f64, f64)> {
+        let Some(i) = array_index(id) else {
+            return self.outliers.get(id);
+        };
+        let bytes = self.map.get(i * 8..i * 8 + 8)?;
+        unpack(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Stores the location of every node that passes through in a shared location index,
+/// so that later stages (filters and writers) can look up the locations of the nodes
+/// that ways reference. Elements are passed along unchanged.
+///
+/// * `receiver`: Receiver for a channel of `Element`s.
+/// * `sender`: Sender for a channel of `Element`s.
+/// * `locations`: The index to fill in.
+/// * `progress`: The ProgressBar for this operation.
+pub fn index_locations(
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    locations: SharedLocationIndex,
+    progress: ProgressBar,
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Indexing locations...");
+
+    for chunk in receiver {
+        // every location in a chunk is stored before the chunk is sent on, so
+        // later stages will find the nodes of any way that comes after them
+        {
+            let mut index = locations.write().expect("Location index lock is poisoned");
+            for element in &chunk {
+                if let ElementType::Node { lat, lon } = element.element_type {
+                    index.insert(element.id, lat, lon)?;
+                }
+            }
+        }
+        sender.send(chunk)?;
+    }
+
+    progress.finish_with_message("Indexing locations...done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_indexes() {
+        for index_type in [
+            LocationIndexType::Sparse,
+            LocationIndexType::Dense,
+            LocationIndexType::Mmap,
+        ] {
+            let mut index = create_location_index(index_type).unwrap();
+            index.insert(1, 37.5407, -77.436).unwrap();
+            index.insert(-5, -90.0, -180.0).unwrap();
+            index.insert(3_000_000, 0.0, 0.0).unwrap();
+            index.insert(1, 37.5408, -77.4361).unwrap();
+            // far above any real node ID, so the arrays don't grow to fit it
+            index.insert(1_000_000_000_000_000, 1.0, 2.0).unwrap();
+            index.insert(i64::MAX, 3.0, 4.0).unwrap();
+
+            assert_eq!(index.get(1), Some((37.5408, -77.4361)), "{index_type:?}");
+            assert_eq!(index.get(-5), Some((-90.0, -180.0)), "{index_type:?}");
+            assert_eq!(index.get(3_000_000), Some((0.0, 0.0)), "{index_type:?}");
+            assert_eq!(index.get(2), None, "{index_type:?}");
+            assert_eq!(index.get(-2), None, "{index_type:?}");
+            assert_eq!(index.get(i64::MAX / 16), None, "{index_type:?}");
+            assert_eq!(
+                index.get(1_000_000_000_000_000),
+                Some((1.0, 2.0)),
+                "{index_type:?}"
+            );
+            assert_eq!(index.get(i64::MAX), Some((3.0, 4.0)), "{index_type:?}");
+            assert_eq!(index.get(MAX_ARRAY_ID + 1), None, "{index_type:?}");
+        }
+    }
+}
@@ -1,23 +1,29 @@
-use clap::Parser;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use env_logger::Env;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::{error, info};
 use std::fs;
-use std::io::stdout;
+use std::io::{stdout, BufWriter, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::mpsc;
-use std::thread;
-
-use skyway::elements::{Element, Metadata};
-use skyway::filter::{create_filter, filter_elements, ElementFilter};
-use skyway::readers::{get_reader, InputFileFormat};
-use skyway::writers::{write_file, OutputFileFormat};
-use skyway::SkywayError;
-
-fn get_file_extension(path: &Option<String>) -> Option<&str> {
+use std::sync::{Arc, RwLock};
+
+use skyway::apply::collect_changes;
+use skyway::compression::{split_extension, CompressedWriter};
+use skyway::elements::{Bounds, Element};
+use skyway::extract::{ExtractStrategy, Polygon, Region};
+use skyway::filter::{create_filter, ElementFilter};
+use skyway::locations::{create_location_index, LocationIndexType};
+use skyway::pipeline::DEFAULT_BUFFER_CHUNKS;
+use skyway::readers::{get_reader, InputFileFormat, OnError, ReadOptions, Rejects};
+use skyway::writers::{CsvColumn, OutputFileFormat, WriteOptions, DEFAULT_CSV_COLUMNS};
+use skyway::{Pipeline, SkywayError};
+
+// compressed files have a compound extension (e.g. `.osm.bz2`),
+// in which case the format comes from the inner extension
+fn get_file_extension(path: &Option<String>) -> Option<String> {
     path.as_ref()
-        .and_then(|p| std::path::Path::new(p).extension())
-        .and_then(|ext| ext.to_str())
+        .and_then(|p| split_extension(std::path::Path::new(p)).0)
 }
 
 fn parse_format<T: FromStr>(
@@ -35,7 +41,7 @@ where
         })
     } else {
         match get_file_extension(file_path) {
-            Some(ext) => T::from_str(ext).map_err(|_| {
+            Some(ext) => T::from_str(&ext).map_err(|_| {
                 error!("File extension not recognized: {}", ext);
                 io_error
             }),
@@ -52,11 +58,35 @@ where
 #[command(author = "Jacob Hall <email@jacobhall.net>")]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "Converts OpenStreetMap data between various file formats")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Applies OsmChange files to OSM data that is sorted by type, then ID
+    Apply(ApplyArgs),
+}
+
+#[derive(Args)]
+struct ConvertArgs {
     // Path to filter file
     #[arg(long)]
     filter: Option<Vec<String>>,
 
+    // With --filter, restore the nodes of ways that filters keep, so the output has no dangling references
+    #[arg(long, requires = "filter")]
+    add_referenced: bool,
+
+    // With --add-referenced, also restore the members of relations that filters keep
+    #[arg(long, requires = "add_referenced")]
+    add_relation_members: bool,
+
     // Source file format
     #[arg(long)]
     from: Option<String>,
@@ -72,125 +102,261 @@ struct Cli {
     // Path to output file
     #[arg(long)]
     output: Option<String>,
+
+    // Keep elements in input order when reading and writing in parallel
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    preserve_order: bool,
+
+    // Sort elements by type, then ID
+    #[arg(long)]
+    sort: bool,
+
+    // Extract the elements in a bounding box, given as minlon,minlat,maxlon,maxlat
+    #[arg(long, allow_hyphen_values = true)]
+    bbox: Option<Bounds>,
+
+    // Extract the elements in a polygon, from an Osmosis .poly or GeoJSON file
+    #[arg(long, conflicts_with = "bbox")]
+    polygon: Option<String>,
+
+    // Which elements to keep in an extract: simple, complete_ways or smart
+    #[arg(long, default_value = "complete_ways")]
+    strategy: ExtractStrategy,
+
+    // Index node locations for filters and writers, in a sparse, dense or mmap index
+    #[arg(long)]
+    location_index: Option<LocationIndexType>,
+
+    // Columns to write to CSV and TSV: @id, @type, @version, @user, @timestamp, @lat, @lon or tag keys
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_CSV_COLUMNS)]
+    columns: Vec<CsvColumn>,
+
+    // What to do with elements that can't be parsed: abort, skip (silently) or warn
+    #[arg(long, default_value = "abort")]
+    on_error: OnError,
+
+    // Write the raw text of elements that can't be parsed to this file
+    #[arg(long)]
+    rejects: Option<String>,
+
+    // How many chunks of 1000 elements can wait between two stages before the first one waits too
+    #[arg(long, default_value_t = DEFAULT_BUFFER_CHUNKS)]
+    buffer_chunks: usize,
 }
 
-fn main() -> Result<(), SkywayError> {
-    env_logger::init();
+#[derive(Args)]
+struct ApplyArgs {
+    // Path to change file (may be repeated, changes are applied in order)
+    #[arg(long, required = true)]
+    change: Vec<String>,
 
-    let cli = Cli::parse();
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
 
-    let from =
-        parse_format::<InputFileFormat>(&cli.from, &cli.input, SkywayError::UnknownInputFormat)?;
-    info!("Input format determined: {:?}", from);
+// reads every element from a change file into memory
+fn read_change_file(path: &str, rejects: Arc<Rejects>) -> Result<Vec<Element>, SkywayError> {
+    let from = parse_format::<InputFileFormat>(
+        &None,
+        &Some(path.to_owned()),
+        SkywayError::UnknownInputFormat,
+    )?;
+    let options = ReadOptions {
+        rejects,
+        ..Default::default()
+    };
+    let (_metadata, elements) = skyway::open_with_options(path, from, options)?;
+    elements.collect()
+}
 
-    let to =
-        parse_format::<OutputFileFormat>(&cli.to, &cli.output, SkywayError::UnknownOutputFormat)?;
-    info!("Output format determined: {:?}", to);
+// polygon files are read as GeoJSON, unless they have the .poly extension
+fn read_polygon(path: &str) -> Result<Polygon, SkywayError> {
+    let contents = fs::read_to_string(path).map_err(|source| SkywayError::FileError {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    let polygon = match get_file_extension(&Some(path.to_owned())).as_deref() {
+        Some("poly") => Polygon::from_poly(&contents),
+        _ => Polygon::from_geojson(&contents),
+    };
+    polygon
+        .map_err(|e| SkywayError::InvalidInput(format!("Unable to parse polygon file {path}: {e}")))
+}
 
-    // will hold this document's metadata
-    #[allow(clippy::needless_late_init)]
-    let metadata: Metadata;
+fn read_filter(path: &str) -> Result<Box<dyn ElementFilter>, SkywayError> {
+    let contents = fs::read_to_string(path).map_err(|source| SkywayError::FileError {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    create_filter(&contents).map_err(|e| match e {
+        SkywayError::FilterError(message) => SkywayError::FilterError(format!("{path}: {message}")),
+        e => e,
+    })
+}
 
-    // channel for sending elements from the reader to either
-    // a) the filter or b) the writer (if not using a filter)
-    let (reader_sender, reader_reciever) = mpsc::channel();
-    let (metadata_sender, metadata_reciever) = mpsc::channel();
+fn main() {
+    let cli = Cli::parse();
 
-    let multi = MultiProgress::new();
-    let spinner_style = ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {wide_msg}")
-        .unwrap()
-        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ");
+    // only errors are logged by default, unless elements that
+    // can't be parsed should be warned about
+    let on_error = match &cli.command {
+        Some(Command::Apply(apply)) => apply.convert.on_error,
+        None => cli.convert.on_error,
+    };
+    let default_filter = match on_error {
+        OnError::Warn => "warn",
+        _ => "error",
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(default_filter)).init();
+
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
 
-    let read_progress = multi.add(ProgressBar::new_spinner());
-    read_progress.set_style(spinner_style.clone());
+fn run(cli: Cli) -> Result<(), SkywayError> {
+    let (cli, change_paths) = match cli.command {
+        Some(Command::Apply(apply)) => (apply.convert, Some(apply.change)),
+        None => (cli.convert, None),
+    };
 
-    //  for reader progress
-    let progress_clone = read_progress.clone();
-    std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        progress_clone.tick();
-        if progress_clone.is_finished() {
-            break;
+    let rejects_file = match &cli.rejects {
+        Some(path) => match fs::File::create(path) {
+            Ok(f) => Some(Box::new(BufWriter::new(f)) as Box<dyn Write + Send>),
+            Err(source) => {
+                return Err(SkywayError::FileError {
+                    path: PathBuf::from(path),
+                    source,
+                })
+            }
+        },
+        None => None,
+    };
+    let rejects = Arc::new(Rejects::new(cli.on_error, rejects_file));
+
+    let changes = match change_paths {
+        Some(paths) => {
+            let mut changes = Vec::new();
+            for path in &paths {
+                info!("Reading change file: {}", path);
+                changes.extend(read_change_file(path, rejects.clone())?);
+            }
+            Some(collect_changes(changes))
         }
-    });
+        None => None,
+    };
 
-    let mut reader = get_reader(cli.input.as_deref(), from);
+    let from =
+        parse_format::<InputFileFormat>(&cli.from, &cli.input, SkywayError::UnknownInputFormat)?;
+    info!("Input format determined: {:?}", from);
 
-    // spawn a thread that reads the file and spits OSM element
-    // data into the channel, to be passed into the filter
-    // or data writer
-    let read_thread = thread::spawn(move || {
-        read_progress.set_message("Reading input...");
+    let to =
+        parse_format::<OutputFileFormat>(&cli.to, &cli.output, SkywayError::UnknownOutputFormat)?;
+    info!("Output format determined: {:?}", to);
 
-        reader.read(reader_sender, metadata_sender);
+    let region = match (cli.bbox, &cli.polygon) {
+        (Some(bounds), _) => Some(Region::Bounds(bounds)),
+        (None, Some(path)) => Some(Region::Polygon(read_polygon(path)?)),
+        (None, None) => None,
+    };
 
-        // complete reader progress spinner
-        read_progress.finish_with_message("Reading input...done");
-    });
+    let mut filters = Vec::new();
+    for filter_path in cli.filter.iter().flatten() {
+        filters.push(read_filter(filter_path)?);
+    }
 
-    metadata = match metadata_reciever.iter().next() {
-        Some(m) => m,
-        None => {
-            panic!("No metadata received from reader!");
+    // the output is opened before reading starts, so a bad path is reported right away
+    let (destination, compression): (Box<dyn Write + Send>, _) = match &cli.output {
+        None => (Box::new(stdout()), None),
+        Some(a) => {
+            let path = PathBuf::from(a);
+            let compression = split_extension(&path).1;
+            match fs::File::create(&path) {
+                Ok(b) => (Box::new(b), compression),
+                Err(source) => return Err(SkywayError::FileError { path, source }),
+            }
         }
     };
+    let destination = CompressedWriter::new(destination, compression)?;
 
-    // stack of filter threads that we'll need to hold open until each
-    // is done
-    let mut filter_threads = Vec::new();
-
-    // create variables that will hold the Sender and Receiver for the
-    // current (last created) filter
-    let mut this_sender: mpsc::Sender<Vec<Element>>;
-    let mut last_receiver: mpsc::Receiver<Vec<Element>> = reader_reciever;
-    let mut next_receiver: mpsc::Receiver<Vec<Element>>;
-
-    let mut filters: Vec<Box<dyn ElementFilter>> = Vec::new();
-
-    if let Some(filter_paths) = cli.filter {
-        for filter_path in filter_paths {
-            filters.push(create_filter(
-                fs::read_to_string(&filter_path)
-                    .unwrap_or_else(|e| {
-                        panic!("Unable to read filter file {}: {}", filter_path, e);
-                    })
-                    .as_str(),
-            ));
-        }
-    }
+    let options = ReadOptions {
+        preserve_order: cli.preserve_order,
+        buffer_chunks: cli.buffer_chunks,
+        rejects: rejects.clone(),
+        ..Default::default()
+    };
+    let reader = get_reader(cli.input.as_deref(), from, options)?;
 
-    for filter in filters {
-        let filter_progress = multi.add(ProgressBar::new_spinner());
-        filter_progress.set_style(spinner_style.clone());
+    let multi = MultiProgress::new();
+    let spinner_style = ProgressStyle::with_template("{spinner} {wide_msg} {prefix:.dim}")
+        .unwrap()
+        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏ ");
+    let mut pipeline = Pipeline::new(reader)
+        .buffer_chunks(cli.buffer_chunks)
+        .progress(move |_| multi.add(ProgressBar::new_spinner().with_style(spinner_style.clone())));
+
+    // index node locations as they're read, for the filters and writers that use them.
+    // writers that build geometries need one, and get a sparse index by default
+    let locations = match (cli.location_index, to.needs_locations()) {
+        (Some(index_type), _) => Some(index_type),
+        (None, true) => Some(LocationIndexType::Sparse),
+        (None, false) => None,
+    };
+    if let Some(index_type) = locations {
+        let index = create_location_index(index_type)?;
+        pipeline = pipeline.index_locations(Arc::new(RwLock::new(index)));
+    }
 
-        (this_sender, next_receiver) = mpsc::channel();
-        filter_threads.push(Some(thread::spawn(move || {
-            filter_elements(filter, last_receiver, this_sender, filter_progress);
-        })));
-        last_receiver = next_receiver;
+    // sort the input first, so that changes can be applied to it
+    if cli.sort {
+        pipeline = pipeline.sort();
     }
 
-    let write_progress = multi.add(ProgressBar::new_spinner());
-    write_progress.set_style(spinner_style.clone());
+    // merge changes into the input before any filters run
+    if let Some(changes) = changes {
+        pipeline = pipeline.apply_changes(changes);
+    }
 
-    let write_thread = thread::spawn(move || match cli.output {
-        None => write_file(last_receiver, metadata, to, stdout(), write_progress),
-        Some(a) => match fs::File::create(PathBuf::from(a)) {
-            Ok(b) => write_file(last_receiver, metadata, to, b, write_progress),
-            Err(e) => {
-                panic!("Unable to open output file: {e:?}");
-            }
-        },
-    });
+    // cut the extract out of the (updated) input, so that filters see whole ways and relations
+    if let Some(region) = region {
+        pipeline = pipeline.extract(region, cli.strategy);
+    }
 
-    read_thread.join().expect("Couldn't join on read thread!!");
-    for filter_thread in filter_threads {
-        let Some(ft) = filter_thread else { continue };
-        ft.join().expect("Couldn't join on filter thread!!");
+    if cli.add_referenced {
+        // the filters run together in one stage, which needs to see
+        // everything they dropped to restore what's still referenced
+        pipeline = pipeline.filter_with_references(filters, cli.add_relation_members);
+    } else {
+        for filter in filters {
+            pipeline = pipeline.filter(filter);
+        }
     }
-    write_thread
-        .join()
-        .expect("Couldn't join on write thread!!");
 
+    let options = WriteOptions {
+        preserve_order: cli.preserve_order,
+        columns: cli.columns,
+        ..Default::default()
+    };
+    let result = pipeline
+        .write_with_options(to, destination, options)
+        .and_then(|destination| Ok(destination.finish()?));
+
+    // the rejects file is flushed first, so that it has the element
+    // that stopped reading if that's what went wrong
+    let flushed = rejects.flush();
+    result?;
+    flushed?;
+
+    let skipped = match rejects.count() {
+        0 => None,
+        1 => Some(String::from("Skipped 1 element that couldn't be parsed")),
+        n => Some(format!("Skipped {n} elements that couldn't be parsed")),
+    };
+    match (skipped, &cli.rejects) {
+        (Some(skipped), Some(path)) => eprintln!("{skipped} (written to {path})"),
+        (Some(skipped), None) => eprintln!("{skipped}"),
+        (None, _) => {}
+    }
     Ok(())
 }
@@ -0,0 +1,464 @@
+//! Runs a reader, the stages that transform its elements, and a writer together.
+
+use indicatif::ProgressBar;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::apply::apply_changes;
+use crate::channel::chunk_channel;
+use crate::elements::Element;
+use crate::extract::{extract_elements, ExtractStrategy, Region};
+use crate::filter::{filter_elements, filter_elements_with_references, ElementFilter};
+use crate::locations::{index_locations, SharedLocationIndex};
+use crate::progress::start_spinner;
+use crate::readers::Reader;
+use crate::sort::{sort_elements, SORT_TYPE_THEN_ID};
+use crate::writers::{write_file, OutputFileFormat, WriteOptions};
+use crate::SkywayError;
+
+/// How many chunks of elements each channel between stages holds, unless told otherwise.
+pub const DEFAULT_BUFFER_CHUNKS: usize = 16;
+
+/// Enum that represents the stages of a pipeline, for telling progress bars apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Read,
+    IndexLocations,
+    Sort,
+    ApplyChanges,
+    Extract,
+    Filter,
+    Write,
+}
+
+// the stages between the reader and the writer, in the order they were added
+enum Step {
+    IndexLocations(SharedLocationIndex),
+    Sort,
+    ApplyChanges(BTreeMap<(u8, i64), Element>),
+    Extract(Region, ExtractStrategy),
+    Filter(Box<dyn ElementFilter>),
+    FilterWithReferences(Vec<Box<dyn ElementFilter>>, bool),
+}
+
+impl Step {
+    fn stage(&self) -> Stage {
+        match self {
+            Step::IndexLocations(_) => Stage::IndexLocations,
+            Step::Sort => Stage::Sort,
+            Step::ApplyChanges(_) => Stage::ApplyChanges,
+            Step::Extract(..) => Stage::Extract,
+            Step::Filter(_) | Step::FilterWithReferences(..) => Stage::Filter,
+        }
+    }
+}
+
+/// Reads elements, passes them through any number of stages, and writes them out,
+/// with each stage running on its own thread.
+///
+/// ```no_run
+/// use skyway::filter::create_filter;
+/// use skyway::readers::{get_reader, InputFileFormat, ReadOptions};
+/// use skyway::writers::OutputFileFormat;
+/// use skyway::Pipeline;
+///
+/// let reader = get_reader(Some("richmond.pbf"), InputFileFormat::Pbf, ReadOptions::default())?;
+/// let output = std::fs::File::create("cafes.osm")?;
+/// Pipeline::new(reader)
+///     .filter(create_filter("tags.amenity == 'cafe'")?)
+///     .sort()
+///     .write(OutputFileFormat::Xml, output)?;
+/// # Ok::<(), skyway::SkywayError>(())
+/// ```
+///
+/// How many threads readers and writers decode and encode chunks with is set
+/// in their `ReadOptions` and `WriteOptions`, unless it's set for both with
+/// [`Pipeline::threads`].
+pub struct Pipeline {
+    reader: Box<dyn Reader>,
+    steps: Vec<Step>,
+    buffer_chunks: usize,
+    threads: Option<usize>,
+    progress: Box<dyn Fn(Stage) -> ProgressBar + Send>,
+}
+
+impl Pipeline {
+    pub fn new(reader: Box<dyn Reader>) -> Self {
+        Pipeline {
+            reader,
+            steps: Vec::new(),
+            buffer_chunks: DEFAULT_BUFFER_CHUNKS,
+            threads: None,
+            progress: Box::new(|_| ProgressBar::hidden()),
+        }
+    }
+
+    /// Sets how many chunks of elements each channel between stages holds. A stage that
+    /// gets ahead of the one after it waits once the channel between them is full, so
+    /// this bounds how much memory elements on their way through the pipeline take up.
+    pub fn buffer_chunks(mut self, chunks: usize) -> Self {
+        self.buffer_chunks = chunks.max(1);
+        self
+    }
+
+    /// Sets how many threads the reader and the writer decode and encode chunks with
+    /// (or one per CPU, if it's 0), for the formats that work in parallel. This takes
+    /// the place of the `threads` the reader and writer were given in their options.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Sets the callback that makes the ProgressBar each stage reports its progress
+    /// on. It is called once for each stage, in order, when the pipeline starts.
+    /// Once a stage has waited for the one after it, its bar's prefix says for how long.
+    /// Progress isn't shown by default.
+    pub fn progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(Stage) -> ProgressBar + Send + 'static,
+    {
+        self.progress = Box::new(callback);
+        self
+    }
+
+    /// Stores the location of every node in `locations`, for the filters
+    /// and writers after this stage to look up.
+    pub fn index_locations(mut self, locations: SharedLocationIndex) -> Self {
+        self.steps.push(Step::IndexLocations(locations));
+        self
+    }
+
+    /// Sorts elements by type, then ID, marking the output as sorted.
+    pub fn sort(mut self) -> Self {
+        self.steps.push(Step::Sort);
+        self
+    }
+
+    /// Merges changes, as returned by `collect_changes`, into elements
+    /// that are sorted by type, then ID.
+    pub fn apply_changes(mut self, changes: BTreeMap<(u8, i64), Element>) -> Self {
+        self.steps.push(Step::ApplyChanges(changes));
+        self
+    }
+
+    /// Extracts the elements in a region, setting the output's bounding box to the region's.
+    pub fn extract(mut self, region: Region, strategy: ExtractStrategy) -> Self {
+        self.steps.push(Step::Extract(region, strategy));
+        self
+    }
+
+    /// Keeps the elements a filter matches, as the filter transforms them.
+    pub fn filter(mut self, filter: Box<dyn ElementFilter>) -> Self {
+        self.steps.push(Step::Filter(filter));
+        self
+    }
+
+    /// Runs filters together, restoring the nodes of the ways they keep (and the
+    /// members of the relations they keep, with `relation_members`).
+    pub fn filter_with_references(
+        mut self,
+        filters: Vec<Box<dyn ElementFilter>>,
+        relation_members: bool,
+    ) -> Self {
+        self.steps
+            .push(Step::FilterWithReferences(filters, relation_members));
+        self
+    }
+
+    /// Runs the pipeline, writing its output to `destination`, which is returned once
+    /// everything has been written.
+    pub fn write<D: Write>(self, to: OutputFileFormat, destination: D) -> Result<D, SkywayError> {
+        self.write_with_options(to, destination, WriteOptions::default())
+    }
+
+    /// Runs the pipeline like [`Pipeline::write`], with settings for the writer. Its
+    /// `buffer_chunks` is set to the pipeline's, like the channels between stages.
+    ///
+    /// Returns the first error any stage stopped with, once every stage has stopped.
+    pub fn write_with_options<D: Write>(
+        self,
+        to: OutputFileFormat,
+        mut destination: D,
+        mut options: WriteOptions,
+    ) -> Result<D, SkywayError> {
+        let Pipeline {
+            mut reader,
+            mut steps,
+            buffer_chunks,
+            threads,
+            progress,
+        } = self;
+
+        // filters and writers look up locations in the index
+        // filled in by an earlier stage, if there is one
+        let locations = steps.iter().find_map(|step| match step {
+            Step::IndexLocations(locations) => Some(locations.clone()),
+            _ => None,
+        });
+        if let Some(locations) = &locations {
+            for step in steps.iter_mut() {
+                match step {
+                    Step::Filter(filter) => filter.set_location_index(locations.clone()),
+                    Step::FilterWithReferences(filters, _) => {
+                        for filter in filters.iter_mut() {
+                            filter.set_location_index(locations.clone());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            options.locations.get_or_insert_with(|| locations.clone());
+        }
+        // writers that work in parallel hold on to as many chunks as the channels do
+        options.buffer_chunks = buffer_chunks;
+        if let Some(threads) = threads {
+            reader.set_threads(threads);
+            options.threads = threads;
+        }
+
+        let (reader_sender, reader_receiver) = chunk_channel(buffer_chunks);
+        let (metadata_sender, metadata_receiver) = mpsc::channel();
+
+        let read_progress = progress(Stage::Read);
+        start_spinner(&read_progress, "Reading input...");
+
+        // the stages that send elements on, with how long each has waited for the next one
+        let mut stalls = vec![(read_progress.clone(), reader_sender.stall_time())];
+
+        let read_thread = thread::spawn(move || {
+            reader.read(reader_sender, metadata_sender)?;
+            read_progress.finish_with_message("Reading input...done");
+            Ok(())
+        });
+
+        // readers send metadata before anything else, so if
+        // there isn't any, reading has already failed
+        let mut metadata = match metadata_receiver.recv() {
+            Ok(m) => m,
+            Err(_) => {
+                read_thread
+                    .join()
+                    .expect("Couldn't join on read thread!!")?;
+                return Err(SkywayError::ChannelError);
+            }
+        };
+
+        // let formats with a header flag for sorted data know whether the output is sorted.
+        // if chunks can be written out of order, the output can't be relied on to be sorted
+        let sorted = steps.iter().any(|step| matches!(step, Step::Sort));
+        if sorted
+            && !metadata
+                .optional_features
+                .iter()
+                .any(|f| f == SORT_TYPE_THEN_ID)
+        {
+            metadata
+                .optional_features
+                .push(SORT_TYPE_THEN_ID.to_owned());
+        }
+        if !options.preserve_order {
+            metadata
+                .optional_features
+                .retain(|f| f != SORT_TYPE_THEN_ID);
+        }
+        for step in &steps {
+            if let Step::Extract(region, _) = step {
+                metadata.bbox = Some(region.bounds());
+            }
+        }
+
+        // each stage receives from the one before it, and sends to the one after it
+        let mut threads: Vec<JoinHandle<Result<(), SkywayError>>> = vec![read_thread];
+        let mut last_receiver: Receiver<Vec<Element>> = reader_receiver;
+        for step in steps {
+            let (sender, next_receiver) = chunk_channel(buffer_chunks);
+            let receiver = last_receiver;
+            let stage_progress = progress(step.stage());
+            stalls.push((stage_progress.clone(), sender.stall_time()));
+            let thread = match step {
+                Step::IndexLocations(locations) => thread::spawn(move || {
+                    index_locations(receiver, sender, locations, stage_progress)
+                }),
+                Step::Sort => {
+                    thread::spawn(move || sort_elements(receiver, sender, stage_progress))
+                }
+                Step::ApplyChanges(changes) => {
+                    thread::spawn(move || apply_changes(changes, receiver, sender, stage_progress))
+                }
+                Step::Extract(region, strategy) => thread::spawn(move || {
+                    extract_elements(receiver, sender, region, strategy, stage_progress)
+                }),
+                Step::Filter(filter) => {
+                    thread::spawn(move || filter_elements(filter, receiver, sender, stage_progress))
+                }
+                Step::FilterWithReferences(filters, relation_members) => thread::spawn(move || {
+                    filter_elements_with_references(
+                        filters,
+                        receiver,
+                        sender,
+                        relation_members,
+                        stage_progress,
+                    )
+                }),
+            };
+            threads.push(thread);
+            last_receiver = next_receiver;
+        }
+
+        // a stage that keeps waiting for the one after it is held up by it, so how
+        // long each stage has waited is shown next to its progress, as it changes
+        let done = Arc::new(AtomicBool::new(false));
+        let done_clone = done.clone();
+        let stall_thread = thread::spawn(move || loop {
+            let finished = done_clone.load(Ordering::Relaxed);
+            for (stage_progress, stall_time) in &stalls {
+                let stalled = stall_time.get();
+                if stalled >= Duration::from_millis(100) {
+                    stage_progress.set_prefix(format!(
+                        "(waited {:.1}s for the next stage)",
+                        stalled.as_secs_f64()
+                    ));
+                }
+            }
+            if finished {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        });
+
+        // the writer runs on this thread, so the destination doesn't need to be sent anywhere
+        let write_result = write_file(
+            last_receiver,
+            metadata,
+            to,
+            &mut destination,
+            options,
+            progress(Stage::Write),
+        );
+
+        let mut results: Vec<_> = threads
+            .into_iter()
+            .map(|t| t.join().expect("Couldn't join on pipeline stage thread!!"))
+            .collect();
+        results.push(write_result);
+        done.store(true, Ordering::Relaxed);
+        stall_thread
+            .join()
+            .expect("Couldn't join on stall thread!!");
+        first_error(results)?;
+        Ok(destination)
+    }
+}
+
+// when a stage fails, the stages before it stop with a ChannelError once they can't
+// send to it, so the error that is reported is the first one that isn't
+fn first_error(results: Vec<Result<(), SkywayError>>) -> Result<(), SkywayError> {
+    let mut channel_error = None;
+    for result in results {
+        match result {
+            Err(SkywayError::ChannelError) => channel_error = Some(SkywayError::ChannelError),
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+    }
+    channel_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::ChunkSender;
+    use crate::elements::Metadata;
+    use crate::filter::create_filter;
+    use crate::readers::{get_reader, InputFileFormat, ReadOptions};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn opl_reader(input: &str) -> (tempfile::NamedTempFile, Box<dyn Reader>) {
+        let mut file = tempfile::Builder::new().suffix(".opl").tempfile().unwrap();
+        file.write_all(input.as_bytes()).unwrap();
+        let path = file.path().to_str().unwrap().to_owned();
+        let reader = get_reader(Some(&path), InputFileFormat::Opl, ReadOptions::default());
+        (file, reader.unwrap())
+    }
+
+    #[test]
+    fn test_pipeline() {
+        // enough chunks that a stage has to wait for the one after it
+        let mut input = String::new();
+        for id in (1..=5000).rev() {
+            let tag = if id % 2 == 0 { "Tamenity=cafe" } else { "T" };
+            input.push_str(&format!("n{id} v1 {tag} x-77.4 y37.5\n"));
+        }
+        let (_file, reader) = opl_reader(&input);
+
+        let stages = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let stages_clone = stages.clone();
+        let output = Pipeline::new(reader)
+            .buffer_chunks(1)
+            .progress(move |stage| {
+                stages_clone.lock().unwrap().push(stage);
+                ProgressBar::hidden()
+            })
+            .filter(create_filter("tags.amenity == 'cafe'").unwrap())
+            .sort()
+            .write(OutputFileFormat::Opl, Vec::new())
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let ids: Vec<&str> = output
+            .lines()
+            .map(|l| l.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(ids.len(), 2500);
+        assert_eq!(ids[0], "n2");
+        assert_eq!(ids[2499], "n5000");
+        assert_eq!(
+            *stages.lock().unwrap(),
+            vec![Stage::Read, Stage::Filter, Stage::Sort, Stage::Write]
+        );
+
+        // a stage that fails stops the pipeline, and its error is the one returned
+        let (_file, reader) = opl_reader("n1 v1 x1 y2\nn2 v1 x1 y2\nn1 v1 x1 y2\n");
+        let changes = BTreeMap::new();
+        let error = Pipeline::new(reader)
+            .apply_changes(changes)
+            .write(OutputFileFormat::Opl, Vec::new())
+            .unwrap_err();
+        assert!(matches!(error, SkywayError::InvalidInput(_)));
+    }
+
+    #[test]
+    fn test_pipeline_threads() {
+        // a reader that only keeps track of the threads it's told to use
+        struct ThreadsReader(Arc<AtomicUsize>);
+
+        impl Reader for ThreadsReader {
+            fn read(
+                &mut self,
+                _sender: ChunkSender,
+                metadata_sender: mpsc::Sender<Metadata>,
+            ) -> Result<(), SkywayError> {
+                metadata_sender.send(Metadata::default())?;
+                Ok(())
+            }
+
+            fn set_threads(&mut self, threads: usize) {
+                self.0.store(threads, Ordering::Relaxed);
+            }
+        }
+
+        let threads = Arc::new(AtomicUsize::new(0));
+        Pipeline::new(Box::new(ThreadsReader(threads.clone())))
+            .threads(3)
+            .write(OutputFileFormat::Pbf, Vec::new())
+            .unwrap();
+        assert_eq!(threads.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,11 @@
+//! Spinners that show what each stage of a conversion is doing.
+
+use indicatif::ProgressBar;
+use std::time::Duration;
+
+/// Shows `message` on a stage's spinner, and keeps it spinning
+/// until the stage finishes it.
+pub(crate) fn start_spinner(progress: &ProgressBar, message: &'static str) {
+    progress.set_message(message);
+    progress.enable_steady_tick(Duration::from_millis(100));
+}
@@ -1,11 +1,19 @@
-use itertools::Itertools;
-use serde::{Deserialize, Deserializer};
-use serde_json::from_str;
+use log::warn;
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::sync::mpsc::Sender;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::mem;
+use std::sync::mpsc::{SendError, Sender};
+use std::sync::Arc;
 
-use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
-use crate::readers::Reader;
+use crate::channel::ChunkSender;
+use crate::elements::{Action, Element, ElementType, Member, Metadata, SimpleElementType};
+use crate::readers::{Reader, Rejects};
+use crate::{Position, SkywayError};
 
 fn deserialize_simple_element_type<'de, D>(
     deserializer: D,
@@ -77,6 +85,8 @@ struct ElementDef {
     tags: HashMap<String, String>,
     #[serde(flatten, with = "ElementTypeDef")]
     element_type: ElementType,
+    #[serde(skip)]
+    action: Option<Action>,
 }
 
 fn deserialize_version<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
@@ -98,6 +108,9 @@ where
     }
 }
 
+#[derive(Deserialize)]
+struct Version(#[serde(deserialize_with = "deserialize_version")] Option<String>);
+
 #[derive(Deserialize)]
 struct Osm3s {
     timestamp_osm_base: Option<String>,
@@ -105,88 +118,282 @@ struct Osm3s {
 }
 
 #[derive(Deserialize)]
-#[serde(untagged)]
-enum Osm3sWrapper {
-    Osm3s { osm3s: Osm3s },
-    Flat { copyright: Option<String> },
+struct ElementWrapper(#[serde(with = "ElementDef")] Element);
+
+// serde errors can only carry a message, so when a channel closes (or an element
+// that can't be parsed stops reading), the error is kept in `failure` to tell it
+// apart from an error in the input
+fn check_sent<T, E: de::Error>(
+    sent: Result<(), SendError<T>>,
+    failure: &Cell<Option<SkywayError>>,
+) -> Result<(), E> {
+    sent.map_err(|_| {
+        failure.set(Some(SkywayError::ChannelError));
+        E::custom("channel closed")
+    })
 }
 
-#[derive(Deserialize)]
-struct MetadataDef {
-    #[serde(deserialize_with = "deserialize_version")]
-    version: Option<String>,
-    generator: Option<String>,
-    license: Option<String>,
-    #[serde(flatten)]
-    osm3s: Osm3sWrapper,
-}
-
-impl From<MetadataDef> for Metadata {
-    fn from(wrapper: MetadataDef) -> Self {
-        let (copyright, timestamp) = match wrapper.osm3s {
-            Osm3sWrapper::Flat { copyright } => (copyright, None),
-            Osm3sWrapper::Osm3s { osm3s } => (osm3s.copyright, osm3s.timestamp_osm_base),
-        };
-        Metadata {
-            version: wrapper.version,
-            generator: wrapper.generator,
-            copyright,
-            license: wrapper.license,
-            timestamp,
+// serde_json ends error messages with the position, which is given separately
+fn parse_error(error: &serde_json::Error, line: usize) -> SkywayError {
+    let message = error.to_string();
+    let message = match message.rsplit_once(" at line ") {
+        Some((m, _)) => m.to_owned(),
+        None => message,
+    };
+    SkywayError::ParseError {
+        format: "JSON",
+        position: Position::Line(line),
+        message,
+    }
+}
+
+// counts the line breaks that have been read, so that elements that can't be
+// parsed can be reported with where they are (serde_json reads a byte at a time,
+// so this is exactly as far as it has got)
+struct LineCounter<'a, R> {
+    inner: R,
+    line_breaks: &'a Cell<usize>,
+}
+
+impl<R: Read> Read for LineCounter<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.inner.read(buf)?;
+        let line_breaks = buf[..length].iter().filter(|b| **b == b'\n').count();
+        self.line_breaks.set(self.line_breaks.get() + line_breaks);
+        Ok(length)
+    }
+}
+
+// deserializes the `elements` array, sending chunks of elements as they are read
+struct ElementsSeed<'a> {
+    sender: &'a ChunkSender,
+    failure: &'a Cell<Option<SkywayError>>,
+    line_breaks: &'a Cell<usize>,
+    rejects: &'a Rejects,
+}
+
+impl<'de> DeserializeSeed<'de> for ElementsSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ElementsSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of OSM elements")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut chunk = Vec::with_capacity(1000);
+        // each element is read as it was written first, so that one which can't be
+        // turned into an Element can be dropped without losing track of the array
+        while let Some(raw) = seq.next_element::<Box<RawValue>>()? {
+            match serde_json::from_str(raw.get()) {
+                Ok(ElementWrapper(element)) => chunk.push(element),
+                Err(e) => {
+                    // the element has just been read, so it starts as many
+                    // lines back as it has line breaks
+                    let start = self.line_breaks.get() + 1 - raw.get().matches('\n').count();
+                    let error = parse_error(&e, start + e.line() - 1);
+                    if let Err(error) = self.rejects.reject(error, Some(raw.get().as_bytes())) {
+                        self.failure.set(Some(error));
+                        return Err(de::Error::custom("element could not be parsed"));
+                    }
+                }
+            }
+            if chunk.len() >= 1000 {
+                let full_chunk = mem::replace(&mut chunk, Vec::with_capacity(1000));
+                check_sent(self.sender.send(full_chunk), self.failure)?;
+            }
         }
+        if !chunk.is_empty() {
+            check_sent(self.sender.send(chunk), self.failure)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Deserialize)]
-struct OsmDocument {
-    #[serde(flatten)]
-    metadata: MetadataDef,
-    #[serde(deserialize_with = "element_vec_annotation")]
-    elements: Vec<Element>,
+// visits the top-level object of an OSM JSON or Overpass JSON document
+struct DocumentVisitor<'a> {
+    sender: &'a ChunkSender,
+    metadata_sender: &'a Sender<Metadata>,
+    failure: &'a Cell<Option<SkywayError>>,
+    line_breaks: &'a Cell<usize>,
+    rejects: &'a Rejects,
 }
 
-fn element_vec_annotation<'de, D>(deserializer: D) -> Result<Vec<Element>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    #[derive(Deserialize)]
-    struct Wrapper(#[serde(with = "ElementDef")] Element);
+impl DocumentVisitor<'_> {
+    fn send_metadata<E: de::Error>(&self, metadata: &mut Option<Metadata>) -> Result<(), E> {
+        match metadata.take() {
+            Some(m) => check_sent(self.metadata_sender.send(m), self.failure),
+            None => Ok(()),
+        }
+    }
+}
 
-    let v = Vec::deserialize(deserializer)?;
-    Ok(v.into_iter().map(|Wrapper(a)| a).collect())
+impl<'de> Visitor<'de> for DocumentVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an OSM JSON document")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        // metadata comes before the elements in both OSM JSON and Overpass
+        // output, so it is sent once the elements array is reached, and
+        // any that comes after it is lost
+        let mut metadata = Some(Metadata::default());
+        while let Some(key) = map.next_key::<String>()? {
+            match (key.as_str(), metadata.as_mut()) {
+                ("elements", _) => {
+                    self.send_metadata(&mut metadata)?;
+                    map.next_value_seed(ElementsSeed {
+                        sender: self.sender,
+                        failure: self.failure,
+                        line_breaks: self.line_breaks,
+                        rejects: self.rejects,
+                    })?;
+                }
+                ("version", Some(m)) => m.version = map.next_value::<Version>()?.0,
+                ("generator", Some(m)) => m.generator = map.next_value()?,
+                ("copyright", Some(m)) => m.copyright = map.next_value()?,
+                ("license", Some(m)) => m.license = map.next_value()?,
+                ("osm3s", Some(m)) => {
+                    let osm3s: Osm3s = map.next_value()?;
+                    m.copyright = osm3s.copyright;
+                    m.timestamp = osm3s.timestamp_osm_base;
+                }
+                ("version" | "generator" | "copyright" | "license" | "osm3s", None) => {
+                    warn!("Ignoring \"{key}\", which comes after the elements in the JSON input");
+                    map.next_value::<IgnoredAny>()?;
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        self.send_metadata(&mut metadata)
+    }
 }
 
 pub struct JsonReader {
-    pub src: String,
+    pub src: Box<dyn BufRead + Send>,
+    pub rejects: Arc<Rejects>,
 }
 
 impl Reader for JsonReader {
-    fn read(&mut self, sender: Sender<Vec<Element>>, metadata_sender: Sender<Metadata>) {
-        let osm_json_object: OsmDocument = match from_str(&self.src) {
-            Ok(v) => v,
-            Err(e) => {
-                panic!("ERROR: Could not parse JSON file: {e:?}");
-            }
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
+        let line_breaks = Cell::new(0);
+        let src = LineCounter {
+            inner: &mut self.src,
+            line_breaks: &line_breaks,
+        };
+        let mut deserializer = serde_json::Deserializer::from_reader(src);
+        let failure = Cell::new(None);
+        let visitor = DocumentVisitor {
+            sender: &sender,
+            metadata_sender: &metadata_sender,
+            failure: &failure,
+            line_breaks: &line_breaks,
+            rejects: &self.rejects,
         };
+        match deserializer.deserialize_map(visitor) {
+            Ok(()) => Ok(()),
+            Err(e) => match failure.take() {
+                Some(error) => Err(error),
+                None if e.is_io() => Err(SkywayError::IoError(e.into())),
+                None => Err(parse_error(&e, e.line())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::readers::OnError;
+    use std::sync::mpsc::channel;
+
+    fn read_json(src: &'static str) -> (Metadata, Vec<Element>) {
+        let mut reader = JsonReader {
+            src: Box::new(src.as_bytes()),
+            rejects: Arc::default(),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, metadata_receiver) = channel();
+        reader.read(sender, metadata_sender).unwrap();
+        (
+            metadata_receiver.recv().unwrap(),
+            receiver.iter().flatten().collect(),
+        )
+    }
+
+    #[test]
+    fn test_read_osm_json() {
+        let (metadata, elements) = read_json(
+            r#"{"version":"0.6","generator":"test","copyright":"OSMF","elements":[
+                {"type":"node","id":1,"lat":37.5,"lon":-77.4,"tags":{"amenity":"cafe"}},
+                {"type":"way","id":2,"nodes":[1,1]}
+            ],"license":"ODbL"}"#,
+        );
+        assert_eq!(metadata.version.as_deref(), Some("0.6"));
+        assert_eq!(metadata.copyright.as_deref(), Some("OSMF"));
+        // metadata after the elements has already been sent on without it
+        assert_eq!(metadata.license, None);
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[0].tags["amenity"], "cafe");
+    }
+
+    #[test]
+    fn test_read_overpass_json() {
+        let (metadata, elements) = read_json(
+            r#"{"version":0.6,"generator":"Overpass API",
+                "osm3s":{"timestamp_osm_base":"2024-09-25T14:03:00Z","copyright":"OSMF"},
+                "elements":[{"type":"relation","id":3,"members":[{"type":"way","ref":2,"role":"outer"}]}]}"#,
+        );
+        assert_eq!(metadata.version.as_deref(), Some("0.6"));
+        assert_eq!(metadata.timestamp.as_deref(), Some("2024-09-25T14:03:00Z"));
+        assert_eq!(metadata.copyright.as_deref(), Some("OSMF"));
+        match &elements[0].element_type {
+            ElementType::Relation { members } => assert_eq!(members[0].id, 2),
+            _ => panic!("expected a relation"),
+        }
+    }
 
-        // convert MetadataWrapper to Metadata
-        let metadata = Metadata::from(osm_json_object.metadata);
-
-        // send OSM document metadata to main thread
-        metadata_sender
-            .send(metadata)
-            .expect("Couldn't send metdata to main thread!");
-
-        // send each deserialized element to the next processing step
-        osm_json_object
-            .elements
-            .into_iter()
-            .chunks(1000)
-            .into_iter()
-            .for_each(|e| {
-                sender
-                    .send(e.collect())
-                    .expect("Unable to send element to channel")
-            });
+    #[test]
+    fn test_read_invalid_json() {
+        let mut reader = JsonReader {
+            src: Box::new("{\"elements\":[\n{\"type\":\"node\",\"id\":1}\n]}".as_bytes()),
+            rejects: Arc::default(),
+        };
+        let (sender, _receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = channel();
+        let error = reader.read(sender, metadata_sender).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Could not parse JSON input at line 2: missing field `lat`"
+        );
+
+        // in skip mode, the node is dropped and the rest of the array is read
+        let src = "{\"elements\":[\n{\"type\":\"node\",\"id\":1},\n{\"type\":\"way\",\"id\":2,\"nodes\":[]}\n]}";
+        let mut reader = JsonReader {
+            src: Box::new(src.as_bytes()),
+            rejects: Arc::new(Rejects::new(OnError::Skip, None)),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = channel();
+        reader.read(sender, metadata_sender).unwrap();
+        let ids: Vec<i64> = receiver.iter().flatten().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2]);
+        assert_eq!(reader.rejects.count(), 1);
     }
 }
@@ -1,26 +1,39 @@
 //! Reads OSM data into skyway.
 
 use std::fs;
-use std::io::{stdin, BufReader, Read};
+use std::io::{stdin, BufRead, Read};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
-use crate::elements::{Element, Metadata};
+use crate::channel::ChunkSender;
+use crate::compression::decompress;
+use crate::elements::Metadata;
+use crate::pipeline::DEFAULT_BUFFER_CHUNKS;
+use crate::threadpools::DEFAULT_THREADS;
 use crate::SkywayError;
 
 mod json;
 use json::JsonReader;
 
+mod o5m;
+use o5m::O5mReader;
+
 mod opl;
 use opl::OplReader;
 
+#[cfg(feature = "osmx")]
 mod osmx;
-use osmx::OsmxReader;
+#[cfg(feature = "osmx")]
+use self::osmx::OsmxReader;
 
 mod pbf;
 use pbf::PbfReader;
 
+mod rejects;
+pub use rejects::{OnError, Rejects};
+
 mod xml;
 use xml::XmlReader;
 
@@ -28,7 +41,10 @@ use xml::XmlReader;
 #[derive(Debug)]
 pub enum InputFileFormat {
     Json,
+    O5m,
     Opl,
+    Osc,
+    Osmx,
     Pbf,
     Xml,
 }
@@ -40,8 +56,12 @@ impl FromStr for InputFileFormat {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "json" => Ok(InputFileFormat::Json),
+            "o5c" => Ok(InputFileFormat::O5m),
+            "o5m" => Ok(InputFileFormat::O5m),
             "opl" => Ok(InputFileFormat::Opl),
+            "osc" => Ok(InputFileFormat::Osc),
             "osm" => Ok(InputFileFormat::Xml),
+            "osmx" => Ok(InputFileFormat::Osmx),
             "pbf" => Ok(InputFileFormat::Pbf),
             "xml" => Ok(InputFileFormat::Xml),
             _ => Err(SkywayError::UnknownInputFormat),
@@ -54,55 +74,125 @@ pub trait Reader: Send {
     ///
     /// * `sender`: Sender for a channel of `Element`s.
     /// * `metadata_sender`: Sender for a channel of (1) `Metadata`.
-    fn read(&mut self, sender: Sender<Vec<Element>>, metadata_sender: Sender<Metadata>);
+    ///
+    /// Returns an error if the input can't be read or parsed, or if the channel
+    /// closes early (because whatever was receiving elements failed).
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError>;
+
+    /// Sets how many threads a reader that works in parallel uses, in place of the
+    /// `threads` in its `ReadOptions`. Other readers ignore it.
+    fn set_threads(&mut self, _threads: usize) {}
+}
+
+/// Settings for readers.
+pub struct ReadOptions {
+    /// Whether readers that work in parallel should keep elements in input order.
+    pub preserve_order: bool,
+    /// How many threads readers that work in parallel use (or one per CPU, if it's 0).
+    pub threads: usize,
+    /// How many chunks readers that work in parallel decode ahead of the one
+    /// they're waiting to send on.
+    pub buffer_chunks: usize,
+    /// What to do with elements that can't be parsed.
+    pub rejects: Arc<Rejects>,
 }
 
-fn open_or_stdin(path: Option<PathBuf>) -> Box<dyn Read + Send> {
-    match path {
-        Some(p) => match fs::File::open(p) {
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            preserve_order: true,
+            threads: DEFAULT_THREADS,
+            buffer_chunks: DEFAULT_BUFFER_CHUNKS,
+            rejects: Arc::default(),
+        }
+    }
+}
+
+fn open_or_stdin(path: Option<PathBuf>) -> Result<Box<dyn BufRead + Send>, SkywayError> {
+    let src = match path {
+        Some(p) => match fs::File::open(&p) {
             Ok(f) => Box::new(f) as Box<dyn Read + Send>,
-            Err(e) => panic!("Unable to open input file: {e:?}"),
+            Err(source) => return Err(SkywayError::FileError { path: p, source }),
         },
         None => Box::new(stdin()) as Box<dyn Read + Send>,
-    }
+    };
+    Ok(decompress(src)?)
 }
 
-pub fn generate_reader(from: InputFileFormat, path: Option<PathBuf>) -> Box<dyn Reader> {
-    match from {
-        InputFileFormat::Json => {
-            let mut buffer = String::new();
-            let mut source = open_or_stdin(path);
-            let src = match source.read_to_string(&mut buffer) {
-                Ok(_) => buffer,
-                Err(e) => {
-                    panic!("Error reading input: {e:?}");
-                }
-            };
-            Box::new(JsonReader { src })
-        }
+/// Creates a reader for a file (or stdin, if `path` is `None`).
+///
+/// * `from`: File format to read.
+/// * `path`: Path to the input file.
+/// * `options`: Settings for the reader.
+pub fn generate_reader(
+    from: InputFileFormat,
+    path: Option<PathBuf>,
+    options: ReadOptions,
+) -> Result<Box<dyn Reader>, SkywayError> {
+    let ReadOptions {
+        preserve_order,
+        threads,
+        buffer_chunks,
+        rejects,
+    } = options;
+
+    Ok(match from {
+        InputFileFormat::Json => Box::new(JsonReader {
+            src: open_or_stdin(path)?,
+            rejects,
+        }),
+        InputFileFormat::O5m => Box::new(O5mReader {
+            src: open_or_stdin(path)?,
+            rejects,
+        }),
         InputFileFormat::Opl => Box::new(OplReader {
-            src: Box::new(BufReader::new(open_or_stdin(path))),
+            src: open_or_stdin(path)?,
+            rejects,
         }),
-        InputFileFormat::Pbf => Box::new(PbfReader {
-            src: Box::new(BufReader::new(open_or_stdin(path))),
+        InputFileFormat::Osc => Box::new(XmlReader {
+            src: open_or_stdin(path)?,
+            rejects,
         }),
-        InputFileFormat::Xml => {
-            let mut buffer = String::new();
-            let mut source = open_or_stdin(path);
-            let src = match source.read_to_string(&mut buffer) {
-                Ok(_) => buffer,
-                Err(e) => {
-                    panic!("Error reading input: {e:?}");
-                }
-            };
-            Box::new(XmlReader { src })
+        #[cfg(feature = "osmx")]
+        InputFileFormat::Osmx => match path {
+            Some(p) => Box::new(OsmxReader { path: p }),
+            None => {
+                return Err(SkywayError::InvalidInput(String::from(
+                    "OSM Express databases cannot be read from stdin, please specify an input file.",
+                )))
+            }
+        },
+        #[cfg(not(feature = "osmx"))]
+        InputFileFormat::Osmx => {
+            return Err(SkywayError::InvalidInput(String::from(
+                "skyway was built without OSM Express support, please rebuild it with the `osmx` feature enabled.",
+            )))
         }
-    }
+        InputFileFormat::Pbf => Box::new(PbfReader {
+            src: Box::new(open_or_stdin(path)?),
+            preserve_order,
+            threads,
+            buffer_chunks,
+            rejects,
+        }),
+        InputFileFormat::Xml => Box::new(XmlReader {
+            src: open_or_stdin(path)?,
+            rejects,
+        }),
+    })
 }
 
-pub fn get_reader(input: Option<&str>, from: InputFileFormat) -> Box<dyn Reader> {
+pub fn get_reader(
+    input: Option<&str>,
+    from: InputFileFormat,
+    options: ReadOptions,
+) -> Result<Box<dyn Reader>, SkywayError> {
     match input {
-        None => generate_reader(from, None),
-        Some(a) => generate_reader(from, Some(PathBuf::from(a))),
+        None => generate_reader(from, None, options),
+        Some(a) => generate_reader(from, Some(PathBuf::from(a)), options),
     }
 }
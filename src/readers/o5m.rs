@@ -0,0 +1,544 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{empty, BufRead, ErrorKind, Read};
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use crate::channel::ChunkSender;
+use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
+use crate::readers::{Reader, Rejects};
+use crate::timestamps::format_timestamp;
+use crate::{Position, SkywayError};
+
+// o5m dataset type bytes
+const NODE_DATASET: u8 = 0x10;
+const WAY_DATASET: u8 = 0x11;
+const RELATION_DATASET: u8 = 0x12;
+const FILE_TIMESTAMP_DATASET: u8 = 0xdc;
+const HEADER_DATASET: u8 = 0xe0;
+const END_OF_FILE: u8 = 0xfe;
+const RESET: u8 = 0xff;
+
+// strings (or string pairs) with more characters than this are
+// never added to the string table
+const MAX_CACHED_STRING_LENGTH: usize = 250;
+
+// o5m string tables hold at most this many entries
+const STRING_TABLE_SIZE: usize = 15000;
+
+// read an unsigned, bit-packed o5m number from the start of a slice
+fn read_unsigned(bytes: &[u8], position: &mut usize) -> u64 {
+    let mut output: u64 = 0;
+    let mut shift = 0;
+    while let Some(byte) = bytes.get(*position) {
+        *position += 1;
+        if shift < 64 {
+            output |= u64::from(byte & 0x7f) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    output
+}
+
+// read a signed, bit-packed o5m number, where the least
+// significant bit is the sign
+fn read_signed(bytes: &[u8], position: &mut usize) -> i64 {
+    let unsigned = read_unsigned(bytes, position);
+    (unsigned >> 1) as i64 ^ -((unsigned & 1) as i64)
+}
+
+// read an unsigned number directly from the input stream
+// (used for dataset lengths), counting the bytes read in `offset`
+fn read_length<R: Read>(src: &mut R, offset: &mut u64) -> std::io::Result<u64> {
+    let mut output: u64 = 0;
+    let mut shift = 0;
+    let mut byte = [0u8];
+    loop {
+        src.read_exact(&mut byte)?;
+        *offset += 1;
+        if shift < 64 {
+            output |= u64::from(byte[0] & 0x7f) << shift;
+        }
+        shift += 7;
+        if byte[0] & 0x80 == 0 {
+            return Ok(output);
+        }
+    }
+}
+
+// read the length of a way's or relation's references, returning where they end,
+// which has to be inside the dataset
+fn read_section_end(bytes: &[u8], position: &mut usize) -> Result<usize, String> {
+    let length = read_unsigned(bytes, position);
+    usize::try_from(length)
+        .ok()
+        .and_then(|length| position.checked_add(length))
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| format!("References are {length} bytes long, past the end of the element"))
+}
+
+// split a zero-terminated string off the front of a slice
+fn read_terminated(bytes: &[u8], position: &mut usize) -> String {
+    let rest = &bytes[(*position).min(bytes.len())..];
+    let length = rest.iter().position(|b| *b == 0x00).unwrap_or(rest.len());
+    *position += (length + 1).min(rest.len());
+    String::from_utf8_lossy(&rest[..length]).into_owned()
+}
+
+struct StringTable {
+    cached_strings: VecDeque<Vec<u8>>,
+}
+
+impl StringTable {
+    // read a string (or string pair) that is either written out in full,
+    // or given as a reference to a previously-seen string. `skip_literal`
+    // moves the position past a string that is written out in full.
+    fn read(
+        &mut self,
+        bytes: &[u8],
+        position: &mut usize,
+        skip_literal: fn(&[u8], &mut usize),
+    ) -> Result<Vec<u8>, String> {
+        if bytes.get(*position) == Some(&0x00) {
+            *position += 1;
+            let start = *position;
+            skip_literal(bytes, position);
+            let entry = bytes[start..(*position).min(bytes.len())].to_vec();
+
+            // long strings are written out every time
+            let length = entry.iter().filter(|b| **b != 0x00).count();
+            if length <= MAX_CACHED_STRING_LENGTH {
+                self.cached_strings.push_front(entry.clone());
+                self.cached_strings.truncate(STRING_TABLE_SIZE);
+            }
+            Ok(entry)
+        } else {
+            let index = read_unsigned(bytes, position) as usize;
+            match index
+                .checked_sub(1)
+                .and_then(|i| self.cached_strings.get(i))
+            {
+                Some(entry) => Ok(entry.clone()),
+                None => Err(format!("Invalid string reference: {index}")),
+            }
+        }
+    }
+
+    fn read_string(&mut self, bytes: &[u8], position: &mut usize) -> Result<String, String> {
+        let entry = self.read(bytes, position, |b, p| {
+            read_terminated(b, p);
+        })?;
+        Ok(read_terminated(&entry, &mut 0))
+    }
+
+    fn read_pair(
+        &mut self,
+        bytes: &[u8],
+        position: &mut usize,
+    ) -> Result<(String, String), String> {
+        let entry = self.read(bytes, position, |b, p| {
+            read_terminated(b, p);
+            read_terminated(b, p);
+        })?;
+        let mut entry_position = 0;
+        let key = read_terminated(&entry, &mut entry_position);
+        let value = read_terminated(&entry, &mut entry_position);
+        Ok((key, value))
+    }
+
+    // user ids are stored as a zero-terminated, bit-packed number
+    // in place of a key
+    fn read_user(&mut self, bytes: &[u8], position: &mut usize) -> Result<(u64, String), String> {
+        fn skip_user(bytes: &[u8], position: &mut usize) {
+            read_unsigned(bytes, position);
+            *position += 1;
+            read_terminated(bytes, position);
+        }
+        let entry = self.read(bytes, position, skip_user)?;
+        let mut entry_position = 0;
+        let uid = read_unsigned(&entry, &mut entry_position);
+        entry_position += 1;
+        let user = read_terminated(&entry, &mut entry_position);
+        Ok((uid, user))
+    }
+
+    fn new() -> Self {
+        StringTable {
+            cached_strings: VecDeque::new(),
+        }
+    }
+}
+
+// running values that o5m datasets are delta-coded against,
+// cleared along with the string table by each reset
+struct O5mDecoder {
+    string_table: StringTable,
+    id: i64,
+    timestamp: i64,
+    changeset: i64,
+    lon: i64,
+    lat: i64,
+    // node, way and relation references are delta-coded separately
+    references: [i64; 3],
+}
+
+impl O5mDecoder {
+    fn new() -> Self {
+        O5mDecoder {
+            string_table: StringTable::new(),
+            id: 0,
+            timestamp: 0,
+            changeset: 0,
+            lon: 0,
+            lat: 0,
+            references: [0; 3],
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = O5mDecoder::new();
+    }
+
+    fn delta(previous: &mut i64, bytes: &[u8], position: &mut usize) -> i64 {
+        *previous += read_signed(bytes, position);
+        *previous
+    }
+
+    fn convert_element(&mut self, dataset_type: u8, bytes: &[u8]) -> Result<Element, String> {
+        let mut position = 0;
+        let id = O5mDecoder::delta(&mut self.id, bytes, &mut position);
+
+        let mut element = Element {
+            changeset: None,
+            user: None,
+            version: None,
+            uid: None,
+            id,
+            timestamp: None,
+            visible: None,
+            tags: HashMap::new(),
+            element_type: match dataset_type {
+                NODE_DATASET => ElementType::Node { lat: 0.0, lon: 0.0 },
+                WAY_DATASET => ElementType::Way { nodes: Vec::new() },
+                _ => ElementType::Relation {
+                    members: Vec::new(),
+                },
+            },
+            action: None,
+        };
+
+        // version, timestamp, changeset and author
+        let version = read_unsigned(bytes, &mut position);
+        if version != 0 {
+            element.version = Some(version as i32);
+            let timestamp = O5mDecoder::delta(&mut self.timestamp, bytes, &mut position);
+            if timestamp != 0 {
+                element.timestamp = Some(format_timestamp(timestamp));
+                element.changeset =
+                    Some(O5mDecoder::delta(&mut self.changeset, bytes, &mut position));
+                let (uid, user) = self.string_table.read_user(bytes, &mut position)?;
+                if uid != 0 || !user.is_empty() {
+                    element.uid = Some(uid as i32);
+                    element.user = Some(user);
+                }
+            }
+        }
+
+        // deleted elements (as in o5c change files) end after the version info
+        if position >= bytes.len() {
+            element.visible = Some(false);
+            return Ok(element);
+        }
+
+        match element.element_type {
+            ElementType::Node {
+                ref mut lat,
+                ref mut lon,
+            } => {
+                *lon = O5mDecoder::delta(&mut self.lon, bytes, &mut position) as f64 / 1e7;
+                *lat = O5mDecoder::delta(&mut self.lat, bytes, &mut position) as f64 / 1e7;
+            }
+            ElementType::Way { ref mut nodes } => {
+                let end = read_section_end(bytes, &mut position)?;
+                while position < end {
+                    nodes.push(O5mDecoder::delta(
+                        &mut self.references[0],
+                        bytes,
+                        &mut position,
+                    ));
+                }
+            }
+            ElementType::Relation { ref mut members } => {
+                let end = read_section_end(bytes, &mut position)?;
+                while position < end {
+                    let delta = read_signed(bytes, &mut position);
+
+                    // member type and role share a single string
+                    let role = self.string_table.read_string(bytes, &mut position)?;
+                    let (t, index) = match role.get(..1) {
+                        Some("0") => (SimpleElementType::Node, 0),
+                        Some("1") => (SimpleElementType::Way, 1),
+                        Some("2") => (SimpleElementType::Relation, 2),
+                        _ => return Err(format!("Invalid member type: {role:?}")),
+                    };
+                    self.references[index] += delta;
+                    members.push(Member {
+                        t: Some(t),
+                        id: self.references[index],
+                        role: Some(role[1..].to_owned()),
+                    });
+                }
+            }
+        }
+
+        while position < bytes.len() {
+            let (key, value) = self.string_table.read_pair(bytes, &mut position)?;
+            element.tags.insert(key, value);
+        }
+
+        Ok(element)
+    }
+}
+
+pub struct O5mReader {
+    pub src: Box<dyn BufRead + Send>,
+    pub rejects: Arc<Rejects>,
+}
+
+impl Reader for O5mReader {
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
+        let mut src = mem::replace(&mut self.src, Box::new(empty()));
+
+        // header datasets come before any elements, so metadata
+        // is sent once the first element (or the end of the file)
+        // is reached
+        let mut metadata = Some(Metadata::default());
+
+        let mut decoder = O5mDecoder::new();
+        let mut chunk = Vec::new();
+        let mut dataset_type = [0u8];
+        let mut buffer = Vec::new();
+        // the number of bytes read so far, for error messages
+        let mut offset = 0;
+        // values are delta-coded and strings refer back to earlier ones, so once an
+        // element can't be decoded, the ones after it can't be trusted until a reset
+        let mut desynced = false;
+
+        loop {
+            let dataset_offset = offset;
+            let parse_error = |message| SkywayError::ParseError {
+                format: "o5m",
+                position: Position::Byte(dataset_offset),
+                message,
+            };
+            // running out of input partway through a dataset means the file was cut off
+            let truncated = |e: std::io::Error| match e.kind() {
+                ErrorKind::UnexpectedEof => {
+                    parse_error(String::from("Input ends partway through a dataset"))
+                }
+                _ => SkywayError::from(e),
+            };
+
+            match src.read_exact(&mut dataset_type) {
+                Ok(_) => offset += 1,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            match dataset_type[0] {
+                RESET => {
+                    decoder.reset();
+                    desynced = false;
+                    continue;
+                }
+                END_OF_FILE => break,
+                // other single-byte datasets have no length or contents
+                0xf0..=0xfd => continue,
+                _ => (),
+            }
+
+            let length = read_length(&mut src, &mut offset).map_err(truncated)? as usize;
+            buffer.resize(length, 0);
+            src.read_exact(&mut buffer).map_err(truncated)?;
+            offset += length as u64;
+
+            match dataset_type[0] {
+                NODE_DATASET | WAY_DATASET | RELATION_DATASET => {
+                    if let Some(m) = metadata.take() {
+                        metadata_sender.send(m)?;
+                    }
+                    let element = if desynced {
+                        Err(String::from(
+                            "Comes after an element that couldn't be decoded, before a reset",
+                        ))
+                    } else {
+                        decoder.convert_element(dataset_type[0], &buffer)
+                    };
+                    match element {
+                        Ok(element) => chunk.push(element),
+                        Err(message) => {
+                            self.rejects.reject(parse_error(message), None)?;
+                            desynced = true;
+                        }
+                    }
+                    if chunk.len() >= 1000 {
+                        sender.send(mem::take(&mut chunk))?;
+                    }
+                }
+                HEADER_DATASET if buffer != b"o5m2" && buffer != b"o5c2" => {
+                    return Err(parse_error(format!(
+                        "Unrecognized header: {:?}",
+                        String::from_utf8_lossy(&buffer)
+                    )));
+                }
+                FILE_TIMESTAMP_DATASET => {
+                    if let Some(m) = metadata.as_mut() {
+                        m.timestamp = Some(format_timestamp(read_signed(&buffer, &mut 0)));
+                    }
+                }
+                // skip any other datasets (bounding box, sync, jump, etc.)
+                _ => (),
+            }
+        }
+
+        if let Some(m) = metadata.take() {
+            metadata_sender.send(m)?;
+        }
+
+        if !chunk.is_empty() {
+            sender.send(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_read_numbers() {
+        let bytes = vec![0x05, 0xc3, 0x02, 0x80, 0x80, 0x01];
+        let mut position = 0;
+        assert_eq!(read_unsigned(&bytes, &mut position), 5);
+        assert_eq!(read_unsigned(&bytes, &mut position), 323);
+        assert_eq!(read_unsigned(&bytes, &mut position), 16384);
+        assert_eq!(position, 6);
+
+        let bytes = vec![0x00, 0x01, 0x02, 0x03, 0x81, 0x01];
+        let mut position = 0;
+        assert_eq!(read_signed(&bytes, &mut position), 0);
+        assert_eq!(read_signed(&bytes, &mut position), -1);
+        assert_eq!(read_signed(&bytes, &mut position), 1);
+        assert_eq!(read_signed(&bytes, &mut position), -2);
+        assert_eq!(read_signed(&bytes, &mut position), -65);
+    }
+
+    #[test]
+    fn test_string_table() {
+        let mut string_table = StringTable::new();
+        let bytes = vec![
+            0x00, 0x6f, 0x6e, 0x65, 0x77, 0x61, 0x79, 0x00, 0x79, 0x65, 0x73, 0x00, 0x00, 0x61,
+            0x74, 0x6d, 0x00, 0x6e, 0x6f, 0x00, 0x02, 0x01,
+        ];
+        let mut position = 0;
+        let expected = [
+            ("oneway", "yes"),
+            ("atm", "no"),
+            ("oneway", "yes"),
+            ("atm", "no"),
+        ];
+        for (key, value) in expected {
+            assert_eq!(
+                string_table.read_pair(&bytes, &mut position).unwrap(),
+                (String::from(key), String::from(value))
+            );
+        }
+        assert_eq!(position, bytes.len());
+    }
+
+    #[test]
+    fn test_read_user() {
+        let mut string_table = StringTable::new();
+        let bytes = vec![
+            0x00, 0xfc, 0x07, 0x00, 0x4a, 0x6f, 0x68, 0x6e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        ];
+        let mut position = 0;
+        assert_eq!(
+            string_table.read_user(&bytes, &mut position).unwrap(),
+            (1020, String::from("John"))
+        );
+        assert_eq!(
+            string_table.read_user(&bytes, &mut position).unwrap(),
+            (0, String::new())
+        );
+        assert_eq!(
+            string_table.read_user(&bytes, &mut position).unwrap(),
+            (1020, String::from("John"))
+        );
+        assert_eq!(position, bytes.len());
+    }
+
+    #[test]
+    fn test_convert_element() {
+        let mut decoder = O5mDecoder::new();
+        let bytes = vec![0x0a, 0x00, 0x02, 0x01, 0x00, 0x61, 0x00, 0x62, 0x00];
+        let node = decoder.convert_element(NODE_DATASET, &bytes).unwrap();
+        assert_eq!(node.id, 5);
+        assert_eq!(node.visible, None);
+        assert_eq!(node.tags.get("a"), Some(&String::from("b")));
+        match node.element_type {
+            ElementType::Node { lat, lon } => {
+                assert_eq!(lat, -0.0000001);
+                assert_eq!(lon, 0.0000001);
+            }
+            _ => panic!("expected a node"),
+        }
+
+        // deleted elements only carry an id and version info
+        let bytes = vec![0x02, 0x00];
+        let deleted = decoder.convert_element(NODE_DATASET, &bytes).unwrap();
+        assert_eq!(deleted.id, 6);
+        assert_eq!(deleted.visible, Some(false));
+
+        // references that say they go on past the end of the way
+        let bytes = vec![0x02, 0x00, 0x05];
+        assert_eq!(
+            decoder.convert_element(WAY_DATASET, &bytes).unwrap_err(),
+            "References are 5 bytes long, past the end of the element"
+        );
+
+        // a tag that refers to a string the table doesn't have
+        let bytes = vec![0x02, 0x01, 0x00, 0x00, 0x00, 0x05];
+        assert_eq!(
+            decoder.convert_element(NODE_DATASET, &bytes).unwrap_err(),
+            "Invalid string reference: 5"
+        );
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        // a node dataset that says it's 9 bytes long, cut off after 2
+        let bytes = vec![RESET, NODE_DATASET, 0x09, 0x0a, 0x00];
+        let mut reader = O5mReader {
+            src: Box::new(std::io::Cursor::new(bytes)),
+            rejects: Arc::default(),
+        };
+        let (sender, _receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = channel();
+        let error = reader.read(sender, metadata_sender).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Could not parse o5m input at byte 1: Input ends partway through a dataset"
+        );
+    }
+}
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 use std::io::{empty, BufRead};
 use std::mem;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
-use itertools::Itertools;
-
+use crate::channel::ChunkSender;
 use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
-use crate::readers::Reader;
+use crate::readers::{Reader, Rejects};
+use crate::{Position, SkywayError};
 
 #[derive(Debug)]
 enum OplElementType {
@@ -15,19 +17,22 @@ enum OplElementType {
     Relation { members: Option<Vec<Member>> },
 }
 
-impl From<OplElementType> for ElementType {
-    fn from(value: OplElementType) -> Self {
+impl TryFrom<OplElementType> for ElementType {
+    type Error = String;
+
+    fn try_from(value: OplElementType) -> Result<Self, Self::Error> {
         match value {
-            OplElementType::Node { lat, lon } => ElementType::Node {
-                lat: lat.unwrap(),
-                lon: lon.unwrap(),
-            },
-            OplElementType::Way { nodes } => ElementType::Way {
-                nodes: nodes.unwrap(),
-            },
-            OplElementType::Relation { members } => ElementType::Relation {
-                members: members.unwrap(),
-            },
+            OplElementType::Node {
+                lat: Some(lat),
+                lon: Some(lon),
+            } => Ok(ElementType::Node { lat, lon }),
+            OplElementType::Node { .. } => Err(String::from("Node is missing its location")),
+            OplElementType::Way { nodes } => Ok(ElementType::Way {
+                nodes: nodes.unwrap_or_default(),
+            }),
+            OplElementType::Relation { members } => Ok(ElementType::Relation {
+                members: members.unwrap_or_default(),
+            }),
         }
     }
 }
@@ -45,14 +50,18 @@ struct OplElement {
     element_type: Option<OplElementType>,
 }
 
-impl From<OplElement> for Element {
-    fn from(value: OplElement) -> Self {
-        let id = value.id.unwrap();
-        let tags = value.tags.unwrap();
-        let element_type = ElementType::from(value.element_type.unwrap());
-        Element {
+impl TryFrom<OplElement> for Element {
+    type Error = String;
+
+    fn try_from(value: OplElement) -> Result<Self, Self::Error> {
+        let id = value.id.ok_or("Element is missing its ID")?;
+        let element_type = value
+            .element_type
+            .ok_or("Element is missing its type")?
+            .try_into()?;
+        Ok(Element {
             id,
-            tags,
+            tags: value.tags.unwrap_or_default(),
             element_type,
             changeset: value.changeset,
             visible: value.visible,
@@ -60,7 +69,8 @@ impl From<OplElement> for Element {
             uid: value.user_id,
             user: value.username,
             version: value.version,
-        }
+            action: None,
+        })
     }
 }
 
@@ -90,36 +100,48 @@ fn unescape_str(input: &str) -> String {
     output
 }
 
-fn add_field(field: &str, opl_element: &mut OplElement) {
-    let (flag, value) = field.split_at(1);
+fn parse_value<T: FromStr>(field: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value in field {field:?}"))
+}
+
+fn add_field(field: &str, opl_element: &mut OplElement) -> Result<(), String> {
+    let (flag, value) = field.split_at(field.chars().next().map_or(0, char::len_utf8));
     match flag {
         "n" => {
-            opl_element.id = Some(value.parse::<i64>().unwrap());
+            opl_element.id = Some(parse_value(field, value)?);
+            opl_element.element_type = Some(OplElementType::Node {
+                lat: None,
+                lon: None,
+            });
         }
         "w" => {
-            opl_element.id = Some(value.parse::<i64>().unwrap());
+            opl_element.id = Some(parse_value(field, value)?);
+            opl_element.element_type = Some(OplElementType::Way { nodes: None });
         }
         "r" => {
-            opl_element.id = Some(value.parse::<i64>().unwrap());
+            opl_element.id = Some(parse_value(field, value)?);
+            opl_element.element_type = Some(OplElementType::Relation { members: None });
         }
         "v" => {
-            opl_element.version = Some(value.parse::<i32>().unwrap());
+            opl_element.version = Some(parse_value(field, value)?);
         }
         "d" => match value {
             "V" => opl_element.visible = Some(true),
             "D" => opl_element.visible = Some(false),
             _ => {
-                panic!("Deleted field value not recognized: {field}");
+                return Err(format!("Deleted field value not recognized: {field}"));
             }
         },
         "c" => {
-            opl_element.changeset = Some(value.parse::<i64>().unwrap());
+            opl_element.changeset = Some(parse_value(field, value)?);
         }
         "t" => {
             opl_element.timestamp = Some(value.to_string());
         }
         "i" => {
-            opl_element.user_id = Some(value.parse::<i32>().unwrap());
+            opl_element.user_id = Some(parse_value(field, value)?);
         }
         "u" => {
             opl_element.username = Some(unescape_str(value));
@@ -133,117 +155,132 @@ fn add_field(field: &str, opl_element: &mut OplElement) {
             opl_element.tags = Some(tags);
         }
         "x" => match opl_element.element_type {
-            Some(OplElementType::Node { lat, .. }) => {
-                opl_element.element_type = Some(OplElementType::Node {
-                    lat,
-                    lon: Some(value.parse::<f64>().unwrap()),
-                });
-            }
-            None => {
-                opl_element.element_type = Some(OplElementType::Node {
-                    lat: None,
-                    lon: Some(value.parse::<f64>().unwrap()),
-                });
+            Some(OplElementType::Node { ref mut lon, .. }) => {
+                *lon = Some(parse_value(field, value)?);
             }
             _ => {
-                panic!("Longitude set for a non-node element!");
+                return Err(String::from("Longitude set for a non-node element"));
             }
         },
         "y" => match opl_element.element_type {
-            Some(OplElementType::Node { lon, .. }) => {
-                opl_element.element_type = Some(OplElementType::Node {
-                    lat: Some(value.parse::<f64>().unwrap()),
-                    lon,
-                });
-            }
-            None => {
-                opl_element.element_type = Some(OplElementType::Node {
-                    lat: Some(value.parse::<f64>().unwrap()),
-                    lon: None,
-                });
+            Some(OplElementType::Node { ref mut lat, .. }) => {
+                *lat = Some(parse_value(field, value)?);
             }
             _ => {
-                panic!("Latitude set for a non-node element!");
+                return Err(String::from("Latitude set for a non-node element"));
             }
         },
         "N" => {
-            let nodes: Vec<i64> = value
+            let nodes = value
                 .split(',')
+                .filter(|n| !n.is_empty())
                 .map(|node_entry| {
-                    let parts: Vec<&str> = node_entry.split(|c| c == 'x' || c == 'y').collect();
-                    parts[0][1..].parse::<i64>().unwrap()
+                    // node references may have locations after them (e.g. n1x2.5y3.5)
+                    let reference = node_entry.split(['x', 'y']).next().unwrap_or_default();
+                    match reference.strip_prefix('n') {
+                        Some(id) => parse_value(field, id),
+                        None => Err(format!("Invalid node reference: {node_entry}")),
+                    }
                 })
-                .collect();
-
-            opl_element.element_type = Some(OplElementType::Way { nodes: Some(nodes) });
+                .collect::<Result<Vec<i64>, String>>()?;
+            match opl_element.element_type {
+                Some(OplElementType::Way { nodes: ref mut n }) => *n = Some(nodes),
+                _ => return Err(String::from("Nodes set for a non-way element")),
+            }
         }
         "M" => {
-            let members: Vec<Member> = value
+            let members = value
                 .split(',')
-                .filter_map(|member| {
-                    let (ref_part, role) = member.split_once('@').unwrap();
-                    let (type_char, member_id) = ref_part.split_at(1);
+                .filter(|m| !m.is_empty())
+                .map(|member| {
+                    let (ref_part, role) = member
+                        .split_once('@')
+                        .ok_or_else(|| format!("Invalid member: {member}"))?;
+                    let (type_char, member_id) =
+                        ref_part.split_at(ref_part.chars().next().map_or(0, char::len_utf8));
                     let member_type = match type_char {
                         "n" => SimpleElementType::Node,
                         "w" => SimpleElementType::Way,
                         "r" => SimpleElementType::Relation,
-                        _ => return None,
+                        _ => return Err(format!("Invalid member type: {member}")),
                     };
-                    Some(Member {
+                    Ok(Member {
                         t: Some(member_type),
-                        id: member_id.parse().ok().unwrap(),
+                        id: parse_value(field, member_id)?,
                         role: Some(unescape_str(role)),
                     })
                 })
-                .collect();
-            opl_element.element_type = Some(OplElementType::Relation {
-                members: Some(members),
-            });
+                .collect::<Result<Vec<Member>, String>>()?;
+            match opl_element.element_type {
+                Some(OplElementType::Relation { members: ref mut m }) => *m = Some(members),
+                _ => return Err(String::from("Members set for a non-relation element")),
+            }
         }
         _ => {
-            panic!("Unrecognized field: {field}");
+            return Err(format!("Unrecognized field: {field}"));
         }
     }
+    Ok(())
 }
 
-fn convert_element(line: String) -> Element {
+fn convert_element(line: &str) -> Result<Element, String> {
     let mut opl_element = OplElement::default();
-    line.split_whitespace()
-        .for_each(|x| add_field(x, &mut opl_element));
-    Element::from(opl_element)
+    for field in line.split_whitespace() {
+        add_field(field, &mut opl_element)?;
+    }
+    Element::try_from(opl_element)
 }
 
 pub struct OplReader {
     pub src: Box<dyn BufRead + Send>,
+    pub rejects: Arc<Rejects>,
 }
 
 impl Reader for OplReader {
-    fn read(&mut self, sender: Sender<Vec<Element>>, metadata_sender: Sender<Metadata>) {
-        // create an empty Metadata object
-        let metadata = Metadata::default();
-
-        // send metadata to main thread
-        metadata_sender
-            .send(metadata)
-            .expect("Couldn't send metdata to main thread!");
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
+        // OPL files have no header, so send an empty Metadata object
+        metadata_sender.send(Metadata::default())?;
 
         let src = mem::replace(&mut self.src, Box::new(empty()));
-        src.lines()
-            .take_while(|l| l.is_ok())
-            .map(|l| convert_element(l.unwrap()))
-            .chunks(1000)
-            .into_iter()
-            .for_each(|e| {
-                sender
-                    .send(e.collect())
-                    .expect("Unable to send element to channel")
-            });
+        let mut chunk = Vec::new();
+        for (i, line) in src.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let element = match convert_element(&line) {
+                Ok(element) => element,
+                Err(message) => {
+                    let error = SkywayError::ParseError {
+                        format: "OPL",
+                        position: Position::Line(i + 1),
+                        message,
+                    };
+                    self.rejects.reject(error, Some(line.as_bytes()))?;
+                    continue;
+                }
+            };
+            chunk.push(element);
+            if chunk.len() >= 1000 {
+                sender.send(mem::take(&mut chunk))?;
+            }
+        }
+        if !chunk.is_empty() {
+            sender.send(chunk)?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::channel::chunk_channel;
+    use crate::readers::OnError;
 
     #[test]
     fn test_unescape_str() {
@@ -253,4 +290,36 @@ mod tests {
         assert_eq!(unescape_str("%1f631%"), String::from("😱"));
         assert_eq!(unescape_str("%12108%"), String::from("𒄈"));
     }
+
+    #[test]
+    fn test_read_errors() {
+        let mut reader = OplReader {
+            src: Box::new("n1 v1 x1 y2\n\nw2 v1 Nn1,n1\nr3 v1 Mw2@outer,q4@\n".as_bytes()),
+            rejects: Arc::default(),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = std::sync::mpsc::channel();
+        let error = reader.read(sender, metadata_sender).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Could not parse OPL input at line 4: Invalid member type: q4@"
+        );
+        // the chunk that was being read when the error was found isn't sent
+        assert!(receiver.iter().flatten().next().is_none());
+        assert!(convert_element("n1 v1 x1").is_err());
+        assert!(convert_element("n1 v1 x1 y2 z3").is_err());
+        assert!(convert_element("w1 vX").is_err());
+
+        // in skip mode, the bad line is dropped and the rest are read
+        let mut reader = OplReader {
+            src: Box::new("n1 v1 x1 y2\nn2 v1 xA y2\nw3 v1 Nn1\n".as_bytes()),
+            rejects: Arc::new(Rejects::new(OnError::Skip, None)),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = std::sync::mpsc::channel();
+        reader.read(sender, metadata_sender).unwrap();
+        let ids: Vec<i64> = receiver.iter().flatten().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(reader.rejects.count(), 1);
+    }
 }
@@ -1,37 +1,229 @@
-use osmx::Database;
+use osmx::{Database, ElementId, Transaction};
+use std::collections::HashMap;
+use std::mem;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 
-use crate::elements::{Element, Metadata};
+use crate::channel::ChunkSender;
+use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
 use crate::readers::Reader;
+use crate::timestamps::format_timestamp;
+use crate::{Position, SkywayError};
 
 pub struct OsmxReader {
     pub path: PathBuf,
 }
 
-fn extract_elements(database: Database) -> Vec<Vec<Element>> {
-    unimplemented!()
+fn convert_tags<'a, I>(tag_iter: I) -> HashMap<String, String>
+where
+    I: Iterator<Item = (&'a str, &'a str)>,
+{
+    tag_iter
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect()
+}
+
+fn convert_member(member: osmx::RelationMember) -> Member {
+    let (t, id) = match member.id() {
+        ElementId::Node(id) => (SimpleElementType::Node, id),
+        ElementId::Way(id) => (SimpleElementType::Way, id),
+        ElementId::Relation(id) => (SimpleElementType::Relation, id),
+    };
+    Member {
+        t: Some(t),
+        id: id as i64,
+        role: Some(member.role().to_owned()),
+    }
+}
+
+fn create_element(id: u64, metadata: osmx::Metadata, element_type: ElementType) -> Element {
+    // osmx stores zeroes (or an empty string) for missing metadata
+    let user = metadata.user().to_owned();
+    Element {
+        changeset: Some(metadata.changeset())
+            .filter(|c| *c != 0)
+            .map(i64::from),
+        user: Some(user).filter(|u| !u.is_empty()),
+        version: Some(metadata.version())
+            .filter(|v| *v != 0)
+            .map(|v| v as i32),
+        uid: Some(metadata.uid()).filter(|u| *u != 0).map(|u| u as i32),
+        id: id as i64,
+        timestamp: Some(metadata.timestamp())
+            .filter(|t| *t != 0)
+            .map(|t| format_timestamp(t as i64)),
+        visible: Some(true),
+        tags: HashMap::new(),
+        element_type,
+        action: None,
+    }
+}
+
+fn database_error(message: String) -> SkywayError {
+    SkywayError::ParseError {
+        format: "OSM Express",
+        position: Position::Unknown,
+        message,
+    }
+}
+
+// send elements down the channel in chunks, as they are read from the database
+struct ChunkBuffer<'a> {
+    sender: &'a ChunkSender,
+    chunk: Vec<Element>,
+}
+
+impl ChunkBuffer<'_> {
+    fn push(&mut self, element: Element) -> Result<(), SkywayError> {
+        self.chunk.push(element);
+        if self.chunk.len() >= 1000 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SkywayError> {
+        if !self.chunk.is_empty() {
+            self.sender.send(mem::take(&mut self.chunk))?;
+        }
+        Ok(())
+    }
+}
+
+fn extract_elements(database: &Database, sender: &ChunkSender) -> Result<(), SkywayError> {
+    let transaction = Transaction::begin(database)
+        .map_err(|e| database_error(format!("Unable to read database: {e:?}")))?;
+    let (locations, nodes, ways, relations) = match (
+        transaction.locations(),
+        transaction.nodes(),
+        transaction.ways(),
+        transaction.relations(),
+    ) {
+        (Ok(l), Ok(n), Ok(w), Ok(r)) => (l, n, w, r),
+        _ => {
+            return Err(database_error(String::from(
+                "Unable to open tables in database",
+            )))
+        }
+    };
+
+    let mut chunk_buffer = ChunkBuffer {
+        sender,
+        chunk: Vec::new(),
+    };
+
+    // every node has a location, but only tagged nodes have
+    // an entry (with their metadata) in the nodes table
+    for (id, location) in locations.iter() {
+        let element_type = ElementType::Node {
+            lat: location.lat(),
+            lon: location.lon(),
+        };
+        let element = match nodes.get(id) {
+            Some(node) => {
+                let mut element = create_element(id, node.metadata(), element_type);
+                element.tags = convert_tags(node.tags());
+                element
+            }
+            None => Element {
+                changeset: None,
+                user: None,
+                version: Some(location.version() as i32).filter(|v| *v != 0),
+                uid: None,
+                id: id as i64,
+                timestamp: None,
+                visible: Some(true),
+                tags: HashMap::new(),
+                element_type,
+                action: None,
+            },
+        };
+        chunk_buffer.push(element)?;
+    }
+
+    for (id, way) in ways.iter() {
+        let element_type = ElementType::Way {
+            nodes: way.nodes().map(|n| n as i64).collect(),
+        };
+        let mut element = create_element(id, way.metadata(), element_type);
+        element.tags = convert_tags(way.tags());
+        chunk_buffer.push(element)?;
+    }
+
+    for (id, relation) in relations.iter() {
+        let element_type = ElementType::Relation {
+            members: relation.members().map(convert_member).collect(),
+        };
+        let mut element = create_element(id, relation.metadata(), element_type);
+        element.tags = convert_tags(relation.tags());
+        chunk_buffer.push(element)?;
+    }
+
+    chunk_buffer.flush()
 }
 
 impl Reader for OsmxReader {
-    fn read(&mut self, sender: Sender<Vec<Element>>, metadata_sender: Sender<Metadata>) {
-        // create an empty Metadata object
-        let metadata = Metadata::default();
-
-        // send metadata to main thread
-        metadata_sender
-            .send(metadata)
-            .expect("Couldn't send metdata to main thread!");
-
-        let osmx_database = match Database::open(&self.path) {
-            Ok(v) => v,
-            Err(e) => panic!("Unable to open OSM Express database: {e:?}"),
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
+        // send an empty Metadata object to the main thread
+        metadata_sender.send(Metadata::default())?;
+
+        let osmx_database = Database::open(&self.path)
+            .map_err(|e| database_error(format!("Unable to open database: {e:?}")))?;
+
+        extract_elements(&osmx_database, &sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::fixtures;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_chunk_buffer() {
+        let (sender, receiver) = chunk_channel(16);
+        let mut chunk_buffer = ChunkBuffer {
+            sender: &sender,
+            chunk: Vec::new(),
         };
+        for id in 0..1001 {
+            let element_type = ElementType::Node { lat: 0.0, lon: 0.0 };
+            chunk_buffer
+                .push(fixtures::element(id, element_type, &[]))
+                .unwrap();
+        }
+        chunk_buffer.flush().unwrap();
+        // flushing an empty buffer sends nothing
+        chunk_buffer.flush().unwrap();
+        drop(sender);
+        let lengths: Vec<usize> = receiver.iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(lengths, vec![1000, 1]);
+    }
 
-        extract_elements(osmx_database).into_iter().for_each(|c| {
-            sender
-                .send(c)
-                .expect("Unable to send chunk of elements to channel.")
-        })
+    #[test]
+    fn test_read_invalid_database() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut reader = OsmxReader {
+            path: file.path().to_path_buf(),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, metadata_receiver) = channel();
+        let error = reader.read(sender, metadata_sender).unwrap_err();
+        assert!(matches!(
+            error,
+            SkywayError::ParseError {
+                format: "OSM Express",
+                ..
+            }
+        ));
+        // the metadata is sent before the database is opened
+        assert!(metadata_receiver.recv().is_ok());
+        assert_eq!(receiver.iter().count(), 0);
     }
 }
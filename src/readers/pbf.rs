@@ -1,13 +1,15 @@
-use osmpbf::{BlobDecode, BlobReader};
-use rayon::prelude::*;
+use osmpbf::{Blob, BlobDecode, BlobReader, ErrorKind, HeaderBlock, PrimitiveBlock};
 use std::collections::HashMap;
 use std::io::{empty, Read};
 use std::mem;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
-use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
-use crate::readers::Reader;
-use crate::threadpools::READER_THREAD_POOL;
+use crate::channel::{encode_in_parallel, ChunkSender};
+use crate::elements::{Bounds, Element, ElementType, Member, Metadata, SimpleElementType};
+use crate::readers::{Reader, Rejects};
+use crate::timestamps::format_timestamp;
+use crate::{Position, SkywayError};
 
 fn get_tags(tag_iter: osmpbf::elements::TagIter) -> HashMap<String, String> {
     let mut tag_map = HashMap::new();
@@ -18,25 +20,48 @@ fn get_tags(tag_iter: osmpbf::elements::TagIter) -> HashMap<String, String> {
 }
 
 fn get_dense_tags(tag_iter: osmpbf::dense::DenseTagIter) -> HashMap<String, String> {
-    let mut tag_map = HashMap::new();
-    let _ = tag_iter.map(|(k, v)| tag_map.insert(k.to_owned(), v.to_owned()));
-    tag_map
+    tag_iter
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect()
+}
+
+// osmpbf scales timestamps by the block's date_granularity, returning milliseconds
+fn convert_timestamp(milli_timestamp: i64) -> String {
+    format_timestamp(milli_timestamp.div_euclid(1000))
+}
+
+// an empty user string (string table index 0) means there is no user. user names
+// that aren't valid UTF-8 are kept, with the invalid bytes replaced
+fn convert_user(
+    user: Option<osmpbf::Result<&str>>,
+    stringtable: &[Vec<u8>],
+) -> osmpbf::Result<Option<String>> {
+    match user {
+        None => Ok(None),
+        Some(Ok(u)) => Ok(Some(u).filter(|u| !u.is_empty()).map(str::to_owned)),
+        Some(Err(e)) => match e.kind() {
+            ErrorKind::StringtableUtf8 { index, .. } if *index < stringtable.len() => Ok(Some(
+                String::from_utf8_lossy(&stringtable[*index]).into_owned(),
+            )),
+            _ => Err(e),
+        },
+    }
 }
 
-fn convert_member(member: osmpbf::elements::RelMember) -> Member {
-    Member {
+fn convert_member(member: osmpbf::elements::RelMember) -> osmpbf::Result<Member> {
+    Ok(Member {
         t: Some(match member.member_type {
             osmpbf::RelMemberType::Node => SimpleElementType::Node,
             osmpbf::RelMemberType::Way => SimpleElementType::Way,
             osmpbf::RelMemberType::Relation => SimpleElementType::Relation,
         }),
         id: member.member_id,
-        role: Some(member.role().unwrap().to_owned()),
-    }
+        role: Some(member.role()?.to_owned()),
+    })
 }
 
-fn convert_element(element: osmpbf::Element) -> Element {
-    match element {
+fn convert_element(element: osmpbf::Element, stringtable: &[Vec<u8>]) -> osmpbf::Result<Element> {
+    Ok(match element {
         osmpbf::Element::Node(node) => {
             let node_info = node.info();
             Element {
@@ -47,11 +72,12 @@ fn convert_element(element: osmpbf::Element) -> Element {
                     lon: node.lon(),
                 },
                 changeset: node_info.changeset(),
-                user: None, // TODO
+                user: convert_user(node_info.user(), stringtable)?,
                 uid: node_info.uid(),
-                timestamp: None, // TODO
+                timestamp: node_info.milli_timestamp().map(convert_timestamp),
                 visible: Some(node_info.visible()),
                 version: node_info.version(),
+                action: None,
             }
         }
         osmpbf::Element::DenseNode(dense_node) => {
@@ -64,11 +90,15 @@ fn convert_element(element: osmpbf::Element) -> Element {
                         lon: dense_node.lon(),
                     },
                     changeset: Some(dense_node_info.changeset()),
-                    user: None, // TODO
+                    user: convert_user(Some(dense_node_info.user()), stringtable)?,
                     uid: Some(dense_node_info.uid()),
-                    timestamp: None, // TODO
+                    // dense nodes always have a timestamp field, zero means it is unset
+                    timestamp: Some(dense_node_info.milli_timestamp())
+                        .filter(|t| *t != 0)
+                        .map(convert_timestamp),
                     visible: Some(dense_node_info.visible()),
                     version: Some(dense_node_info.version()),
+                    action: None,
                 }
             } else {
                 Element {
@@ -84,6 +114,7 @@ fn convert_element(element: osmpbf::Element) -> Element {
                     timestamp: None,
                     visible: None,
                     version: None,
+                    action: None,
                 }
             }
         }
@@ -96,11 +127,12 @@ fn convert_element(element: osmpbf::Element) -> Element {
                     nodes: way.refs().collect(),
                 },
                 changeset: way_info.changeset(),
-                user: None, // TODO
+                user: convert_user(way_info.user(), stringtable)?,
                 uid: way_info.uid(),
-                timestamp: None, // TODO
+                timestamp: way_info.milli_timestamp().map(convert_timestamp),
                 visible: Some(way_info.visible()),
                 version: way_info.version(),
+                action: None,
             }
         }
         osmpbf::Element::Relation(relation) => {
@@ -109,52 +141,294 @@ fn convert_element(element: osmpbf::Element) -> Element {
                 id: relation.id(),
                 tags: get_tags(relation.tags()),
                 element_type: ElementType::Relation {
-                    members: relation.members().map(convert_member).collect(),
+                    members: relation
+                        .members()
+                        .map(convert_member)
+                        .collect::<osmpbf::Result<_>>()?,
                 },
                 changeset: relation_info.changeset(),
-                user: None, // TODO
+                user: convert_user(relation_info.user(), stringtable)?,
                 uid: relation_info.uid(),
-                timestamp: None, // TODO
+                timestamp: relation_info.milli_timestamp().map(convert_timestamp),
                 visible: Some(relation_info.visible()),
                 version: relation_info.version(),
+                action: None,
             }
         }
+    })
+}
+
+// PBF blocks are decoded without keeping track of where they are in the input
+fn parse_error(error: osmpbf::Error) -> SkywayError {
+    SkywayError::ParseError {
+        format: "PBF",
+        position: Position::Unknown,
+        message: error.to_string(),
+    }
+}
+
+// convert a block's elements, leaving out any that are rejected
+fn convert_block(block: &PrimitiveBlock, rejects: &Rejects) -> Result<Vec<Element>, SkywayError> {
+    let mut chunk = Vec::new();
+    for element in block.elements() {
+        match convert_element(element, block.raw_stringtable()) {
+            Ok(element) => chunk.push(element),
+            Err(e) => rejects.reject(parse_error(e), None)?,
+        }
+    }
+    Ok(chunk)
+}
+
+// decode a data blob into a chunk of elements, skipping any other kind of blob
+fn convert_blob(
+    blob: osmpbf::Result<Blob>,
+    rejects: &Rejects,
+) -> Result<Option<Vec<Element>>, SkywayError> {
+    match blob.map_err(parse_error)?.decode().map_err(parse_error)? {
+        BlobDecode::OsmData(block) => convert_block(&block, rejects).map(Some),
+        BlobDecode::OsmHeader(_) | BlobDecode::Unknown(_) => Ok(None),
+    }
+}
+
+fn convert_header(header: &HeaderBlock) -> Metadata {
+    Metadata {
+        generator: header.writing_program().map(str::to_owned),
+        timestamp: header.osmosis_replication_timestamp().map(format_timestamp),
+        bbox: header.bbox().map(|b| Bounds {
+            min_lat: b.bottom,
+            min_lon: b.left,
+            max_lat: b.top,
+            max_lon: b.right,
+        }),
+        replication_sequence_number: header.osmosis_replication_sequence_number(),
+        replication_base_url: header.osmosis_replication_base_url().map(str::to_owned),
+        required_features: header.required_features().to_vec(),
+        optional_features: header.optional_features().to_vec(),
+        ..Default::default()
     }
 }
 
 pub struct PbfReader {
     pub src: Box<dyn Read + Send>,
+    pub preserve_order: bool,
+    pub threads: usize,
+    pub buffer_chunks: usize,
+    pub rejects: Arc<Rejects>,
 }
 
 impl Reader for PbfReader {
-    fn read(&mut self, sender: Sender<Vec<Element>>, metadata_sender: Sender<Metadata>) {
-        metadata_sender
-            .send(Metadata {
-                version: None,
-                generator: None,
-                copyright: None,
-                license: None,
-                timestamp: None, // TODO: see if this is available?
-            })
-            .expect("Couldn't send metdata to main thread!");
-
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
         let src = mem::replace(&mut self.src, Box::new(empty()));
-        let reader = BlobReader::new(src);
-        READER_THREAD_POOL.install(|| {
-            reader
-                .par_bridge()
-                .filter_map(|blob| match blob.unwrap().decode() {
-                    Ok(BlobDecode::OsmData(block)) => {
-                        Some(block.elements().map(convert_element).collect())
-                    }
-                    Ok(BlobDecode::OsmHeader(_)) | Ok(BlobDecode::Unknown(_)) => None,
-                    Err(e) => panic!("ERROR: unable to read PBF input: {e:?}"),
-                })
-                .for_each(|b| {
-                    sender
-                        .send(b)
-                        .expect("Unable to send chunk of elements to channel.")
-                })
-        });
+        let mut reader = BlobReader::new(src);
+
+        // the header block comes first, so read it before
+        // handing the remaining blobs to the thread pool
+        let mut first_chunk = None;
+        let metadata = match reader.next() {
+            Some(blob) => match blob.map_err(parse_error)?.decode().map_err(parse_error)? {
+                BlobDecode::OsmHeader(header) => convert_header(&header),
+                BlobDecode::OsmData(block) => {
+                    first_chunk = Some(convert_block(&block, &self.rejects)?);
+                    Metadata::default()
+                }
+                BlobDecode::Unknown(_) => Metadata::default(),
+            },
+            None => Metadata::default(),
+        };
+
+        metadata_sender.send(metadata)?;
+
+        if let Some(chunk) = first_chunk {
+            sender.send(chunk)?;
+        }
+
+        // blobs are decoded in parallel, and put back in order
+        // (unless that isn't needed) before being sent on
+        let rejects = &self.rejects;
+        encode_in_parallel(
+            reader,
+            self.threads,
+            self.buffer_chunks,
+            self.preserve_order,
+            |blob| convert_blob(blob, rejects),
+            |chunk| {
+                if let Some(chunk) = chunk? {
+                    sender.send(chunk)?;
+                }
+                Ok(())
+            },
+        )
+    }
+
+    fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::fixtures;
+    use crate::writers::{write_file, OutputFileFormat, WriteOptions};
+    use indicatif::ProgressBar;
+    use std::io::Cursor;
+    use std::sync::mpsc::channel;
+
+    fn read_pbf(bytes: Vec<u8>) -> (Metadata, Vec<Element>) {
+        let mut reader = PbfReader {
+            src: Box::new(Cursor::new(bytes)),
+            preserve_order: true,
+            threads: 1,
+            buffer_chunks: 16,
+            rejects: Arc::default(),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, metadata_receiver) = channel();
+        reader.read(sender, metadata_sender).unwrap();
+        (
+            metadata_receiver.recv().unwrap(),
+            receiver.iter().flatten().collect(),
+        )
+    }
+
+    // write elements with the PBF writer, and read them back
+    fn round_trip(chunk: Vec<Element>, metadata: Metadata) -> (Metadata, Vec<Element>) {
+        let (sender, receiver) = channel();
+        sender.send(chunk).unwrap();
+        drop(sender);
+        let mut bytes = Vec::new();
+        write_file(
+            receiver,
+            metadata,
+            OutputFileFormat::Pbf,
+            &mut bytes,
+            WriteOptions::default(),
+            ProgressBar::hidden(),
+        )
+        .unwrap();
+        read_pbf(bytes)
+    }
+
+    #[test]
+    fn test_read_user_and_timestamp() {
+        let element = |id, element_type, user: Option<&str>, uid| Element {
+            changeset: Some(7),
+            user: user.map(str::to_owned),
+            uid,
+            timestamp: user.map(|_| String::from("2024-09-25T14:03:00Z")),
+            ..fixtures::element(id, element_type, &[])
+        };
+        let (_, elements) = round_trip(
+            vec![
+                element(
+                    1,
+                    ElementType::Node { lat: 1.0, lon: 2.0 },
+                    Some("Jöhn"),
+                    Some(42),
+                ),
+                element(2, ElementType::Node { lat: 1.0, lon: 2.0 }, None, None),
+                element(
+                    3,
+                    ElementType::Way { nodes: vec![1, 2] },
+                    Some("Jane"),
+                    Some(43),
+                ),
+                element(4, ElementType::Way { nodes: vec![1, 2] }, None, None),
+                element(
+                    5,
+                    ElementType::Relation { members: vec![] },
+                    Some("Jane"),
+                    Some(43),
+                ),
+            ],
+            Metadata::default(),
+        );
+
+        let info: Vec<_> = elements
+            .iter()
+            .map(|e| (e.id, e.user.as_deref(), e.timestamp.as_deref()))
+            .collect();
+        let timestamp = Some("2024-09-25T14:03:00Z");
+        assert_eq!(
+            info,
+            vec![
+                (1, Some("Jöhn"), timestamp),
+                (2, None, None),
+                (3, Some("Jane"), timestamp),
+                (4, None, None),
+                (5, Some("Jane"), timestamp),
+            ]
+        );
+        assert_eq!(elements[0].uid, Some(42));
+        assert_eq!(elements[2].uid, Some(43));
+        assert_eq!(elements[3].uid, None);
+        assert_eq!(elements[4].uid, Some(43));
+    }
+
+    #[test]
+    fn test_read_invalid_user() {
+        // a block with one way, whose user name ("Jo\xffn") isn't valid UTF-8
+        let block = [
+            0x0a, 0x08, 0x0a, 0x00, 0x0a, 0x04, b'J', b'o', 0xff, b'n', // string table
+            0x12, 0x08, 0x1a, 0x06, 0x08, 0x01, 0x22, 0x02, 0x28, 0x01, // way 1, user 1
+        ];
+        let mut blob = vec![0x0a, block.len() as u8];
+        blob.extend(block);
+        let mut blob_header = vec![0x0a, 0x07];
+        blob_header.extend(b"OSMData");
+        blob_header.extend([0x18, blob.len() as u8]);
+        let mut bytes = (blob_header.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(blob_header);
+        bytes.extend(blob);
+
+        let (_, elements) = read_pbf(bytes);
+        assert_eq!(elements[0].user.as_deref(), Some("Jo\u{fffd}n"));
+    }
+
+    #[test]
+    fn test_read_header() {
+        let bbox = Bounds {
+            min_lat: 37.5,
+            min_lon: -77.5,
+            max_lat: 37.6,
+            max_lon: -77.4,
+        };
+        let (metadata, elements) = round_trip(
+            vec![fixtures::element(
+                1,
+                ElementType::Node { lat: 1.0, lon: 2.0 },
+                &[],
+            )],
+            Metadata {
+                bbox: Some(bbox),
+                timestamp: Some(String::from("2024-09-25T14:03:00Z")),
+                replication_sequence_number: Some(4230),
+                replication_base_url: Some(String::from("https://example.com/replication")),
+                optional_features: vec![String::from("Sort.Type_then_ID")],
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(metadata.bbox, Some(bbox));
+        assert_eq!(metadata.required_features, ["OsmSchema-V0.6", "DenseNodes"]);
+        assert_eq!(metadata.optional_features, ["Sort.Type_then_ID"]);
+        assert!(metadata.generator.unwrap().starts_with("skyway "));
+        assert_eq!(metadata.timestamp.as_deref(), Some("2024-09-25T14:03:00Z"));
+        assert_eq!(metadata.replication_sequence_number, Some(4230));
+        assert_eq!(
+            metadata.replication_base_url.as_deref(),
+            Some("https://example.com/replication")
+        );
+        assert_eq!(elements.len(), 1);
+
+        // without a header block, there's no metadata
+        let (metadata, _) = read_pbf(Vec::new());
+        assert!(metadata.bbox.is_none());
+        assert!(metadata.required_features.is_empty());
     }
 }
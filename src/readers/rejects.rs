@@ -0,0 +1,144 @@
+//! Keeps track of the elements that readers can't parse.
+
+use log::{info, warn};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::SkywayError;
+
+/// Enum that represents what readers do when they find an element they can't parse.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnError {
+    /// Stop reading, returning the error.
+    #[default]
+    Abort,
+    /// Drop the element without a warning. Where it was and why is logged at the
+    /// info level, which the skyway binary only prints if `RUST_LOG` asks for it.
+    Skip,
+    /// Drop the element, logging where it was and why as a warning.
+    Warn,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "abort" => Ok(OnError::Abort),
+            "skip" => Ok(OnError::Skip),
+            "warn" => Ok(OnError::Warn),
+            _ => Err(format!(
+                "Unknown error mode: {s} (expected abort, skip or warn)"
+            )),
+        }
+    }
+}
+
+/// Handles the elements that readers can't parse, counting the ones that are dropped
+/// and writing their raw text to a rejects file, if there is one. It is shared between
+/// readers (and the threads they decode with), so it can be used through a shared reference.
+#[derive(Default)]
+pub struct Rejects {
+    on_error: OnError,
+    count: AtomicUsize,
+    file: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Rejects {
+    /// * `on_error`: What to do with elements that can't be parsed.
+    /// * `file`: Where to write the raw text of those elements, one after another.
+    pub fn new(on_error: OnError, file: Option<Box<dyn Write + Send>>) -> Self {
+        Rejects {
+            on_error,
+            count: AtomicUsize::new(0),
+            file: file.map(Mutex::new),
+        }
+    }
+
+    /// Deals with an element that couldn't be parsed, given the error that says where and
+    /// why, and the element's raw text if the format has any (binary formats don't).
+    ///
+    /// Returns the error if reading should stop, or `Ok` if the element should be dropped
+    /// and reading should go on.
+    pub fn reject(&self, error: SkywayError, raw: Option<&[u8]>) -> Result<(), SkywayError> {
+        if let (Some(file), Some(raw)) = (&self.file, raw) {
+            let mut file = file.lock().expect("Rejects file lock is poisoned");
+            file.write_all(raw)?;
+            if !raw.ends_with(b"\n") {
+                file.write_all(b"\n")?;
+            }
+        }
+        match self.on_error {
+            OnError::Abort => return Err(error),
+            OnError::Skip => info!("Skipped element: {error}"),
+            OnError::Warn => warn!("Skipped element: {error}"),
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether there is a rejects file, so readers know to keep the raw text of elements.
+    pub(crate) fn keeps_raw(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// The number of elements that have been dropped.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Writes out anything the rejects file is holding on to.
+    pub fn flush(&self) -> Result<(), SkywayError> {
+        if let Some(file) = &self.file {
+            file.lock()
+                .expect("Rejects file lock is poisoned")
+                .flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Position;
+    use std::sync::Arc;
+
+    // a Write that can still be read from after it's been handed to Rejects
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rejects() {
+        let error = || SkywayError::ParseError {
+            format: "OPL",
+            position: Position::Line(2),
+            message: String::from("Invalid field"),
+        };
+
+        let buffer = SharedBuffer::default();
+        let rejects = Rejects::new(OnError::Skip, Some(Box::new(buffer.clone())));
+        assert!(rejects.reject(error(), Some(b"n1 q")).is_ok());
+        assert!(rejects.reject(error(), None).is_ok());
+        assert!(rejects.reject(error(), Some(b"n2 q\n")).is_ok());
+        assert_eq!(rejects.count(), 3);
+        assert_eq!(*buffer.0.lock().unwrap(), b"n1 q\nn2 q\n");
+
+        let rejects = Rejects::new(OnError::Abort, None);
+        assert!(rejects.reject(error(), Some(b"n1 q")).is_err());
+        assert_eq!(rejects.count(), 0);
+        assert!("ignore".parse::<OnError>().is_err());
+    }
+}
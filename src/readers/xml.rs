@@ -1,275 +1,563 @@
-use itertools::Itertools;
-use quick_xml::de::from_str;
-use serde::{Deserialize, Deserializer};
-use serde_aux::field_attributes::{
-    deserialize_bool_from_anything, deserialize_number_from_string,
-    deserialize_option_number_from_string,
-};
+use quick_xml::events::attributes::Attributes;
+use quick_xml::events::{BytesStart, Event};
 use std::collections::HashMap;
+use std::io::BufRead;
+use std::mem;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
-use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
-use crate::readers::Reader;
+use crate::channel::ChunkSender;
+use crate::elements::{Action, Bounds, Element, ElementType, Member, Metadata, SimpleElementType};
+use crate::readers::{Reader, Rejects};
+use crate::{Position, SkywayError};
 
-fn deserialize_simple_element_type<'de, D>(
-    deserializer: D,
-) -> Result<Option<SimpleElementType>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: Option<String> = Option::deserialize(deserializer)?;
-    match s.as_deref() {
-        Some("node") => Ok(Some(SimpleElementType::Node)),
-        Some("way") => Ok(Some(SimpleElementType::Way)),
-        Some("relation") => Ok(Some(SimpleElementType::Relation)),
-        None => Ok(None),
-        Some(other) => Err(serde::de::Error::custom(format!(
-            "Invalid element type: {}",
-            other
-        ))),
-    }
+// iterate over an XML tag's attributes as (name, unescaped value) pairs
+fn attribute_pairs(
+    attributes: Attributes<'_>,
+) -> impl Iterator<Item = Result<(Vec<u8>, String), String>> + '_ {
+    attributes.map(|a| {
+        let a = a.map_err(|e| e.to_string())?;
+        let value = a.unescape_value().map_err(|e| e.to_string())?;
+        Ok((a.key.as_ref().to_vec(), value.into_owned()))
+    })
 }
 
-#[derive(Deserialize)]
-#[serde(remote = "Member", rename = "member")]
-struct MemberDef {
-    #[serde(rename = "@type", deserialize_with = "deserialize_simple_element_type")]
-    t: Option<SimpleElementType>,
-    #[serde(rename = "@ref", deserialize_with = "deserialize_number_from_string")]
-    id: i64,
-    #[serde(rename = "@role")]
-    role: Option<String>,
+fn parse_value<T: FromStr>(key: &[u8], value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| {
+        format!(
+            "Invalid value \"{value}\" for attribute \"{}\"",
+            String::from_utf8_lossy(key)
+        )
+    })
 }
 
-#[derive(Deserialize)]
-#[serde(remote = "Metadata")]
-struct MetadataDef {
-    #[serde(rename = "@version")]
-    version: Option<String>,
-    #[serde(rename = "@generator")]
-    generator: Option<String>,
-    #[serde(rename = "@copyright")]
-    copyright: Option<String>,
-    #[serde(rename = "@license")]
-    license: Option<String>,
-    #[serde(rename = "@timestamp")]
-    timestamp: Option<String>,
+// optional attributes may be present but empty, e.g. uid=""
+fn parse_optional_value<T: FromStr>(key: &[u8], value: &str) -> Result<Option<T>, String> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        parse_value(key, value).map(Some)
+    }
 }
 
-#[derive(Deserialize)]
-struct XmlTags {
-    #[serde(rename = "@k")]
-    k: String,
-    #[serde(rename = "@v")]
-    v: String,
+fn missing_attribute(tag: &BytesStart, key: &str) -> String {
+    format!(
+        "Missing attribute \"{key}\" for {}",
+        String::from_utf8_lossy(tag.name().as_ref())
+    )
 }
 
-#[derive(Deserialize)]
-pub struct XmlElementMeta {
-    #[serde(rename = "@id", deserialize_with = "deserialize_number_from_string")]
-    id: i64,
-    #[serde(rename = "@user")]
-    user: Option<String>,
-    #[serde(
-        rename = "@uid",
-        deserialize_with = "deserialize_option_number_from_string"
-    )]
-    uid: Option<i32>,
-    #[serde(
-        rename = "@visible",
-        deserialize_with = "deserialize_bool_from_anything"
-    )]
-    visible: bool,
-    #[serde(
-        rename = "@version",
-        deserialize_with = "deserialize_option_number_from_string"
-    )]
-    version: Option<i32>,
-    #[serde(
-        rename = "@changeset",
-        deserialize_with = "deserialize_option_number_from_string"
-    )]
-    changeset: Option<i64>,
-    #[serde(rename = "@timestamp")]
-    timestamp: Option<String>,
+fn parse_simple_element_type(value: &str) -> Result<SimpleElementType, String> {
+    match value {
+        "node" => Ok(SimpleElementType::Node),
+        "way" => Ok(SimpleElementType::Way),
+        "relation" => Ok(SimpleElementType::Relation),
+        other => Err(format!("Invalid element type: {other}")),
+    }
 }
 
-#[derive(Deserialize)]
-struct XmlNode {
-    #[serde(rename = "@lat", deserialize_with = "deserialize_number_from_string")]
-    lat: f64,
-    #[serde(rename = "@lon", deserialize_with = "deserialize_number_from_string")]
-    lon: f64,
-    #[serde(flatten)]
-    meta: XmlElementMeta,
-    #[serde(default, rename = "tag")]
-    tags: Vec<XmlTags>,
+fn parse_visible(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        other => Err(format!("Invalid visible value \"{other}\"")),
+    }
 }
 
-#[derive(Deserialize)]
-#[serde(rename = "nd")]
-struct XmlWayNode {
-    #[serde(rename = "@ref", deserialize_with = "deserialize_number_from_string")]
-    nd_ref: i64,
+// create an element from the attributes of a <node>, <way> or <relation> tag, within
+// an osmChange's <create>, <modify> or <delete> block if `action` is given
+fn start_element(tag: &BytesStart, action: Option<Action>) -> Result<Option<Element>, String> {
+    let element_type = match tag.name().as_ref() {
+        b"node" => ElementType::Node { lat: 0.0, lon: 0.0 },
+        b"way" => ElementType::Way { nodes: Vec::new() },
+        b"relation" => ElementType::Relation {
+            members: Vec::new(),
+        },
+        _ => return Ok(None),
+    };
+    let mut element = Element {
+        changeset: None,
+        user: None,
+        version: None,
+        uid: None,
+        id: 0,
+        timestamp: None,
+        visible: None,
+        tags: HashMap::new(),
+        element_type,
+        action: None,
+    };
+    let mut id = None;
+    let mut lat = None;
+    let mut lon = None;
+    for pair in attribute_pairs(tag.attributes()) {
+        let (key, value) = pair?;
+        match key.as_slice() {
+            b"id" => id = Some(parse_value(&key, &value)?),
+            b"user" => element.user = Some(value),
+            b"uid" => element.uid = parse_optional_value(&key, &value)?,
+            b"version" => element.version = parse_optional_value(&key, &value)?,
+            b"changeset" => element.changeset = parse_optional_value(&key, &value)?,
+            b"timestamp" => element.timestamp = Some(value),
+            b"visible" => element.visible = Some(parse_visible(&value)?),
+            b"lat" => lat = Some(parse_value(&key, &value)?),
+            b"lon" => lon = Some(parse_value(&key, &value)?),
+            _ => (),
+        }
+    }
+    element.id = id.ok_or_else(|| missing_attribute(tag, "id"))?;
+    if let ElementType::Node { .. } = element.element_type {
+        // nodes that are being deleted don't need a location
+        element.element_type = match (lat, lon) {
+            (Some(lat), Some(lon)) => ElementType::Node { lat, lon },
+            _ if action == Some(Action::Delete) => ElementType::Node { lat: 0.0, lon: 0.0 },
+            _ => return Err(String::from("Node is missing its location")),
+        };
+    }
+    Ok(Some(element))
 }
 
-#[derive(Deserialize)]
-struct XmlWay {
-    #[serde(flatten)]
-    meta: XmlElementMeta,
-    nd: Vec<XmlWayNode>,
-    #[serde(default, rename = "tag")]
-    tags: Vec<XmlTags>,
+// add a <tag>, <nd> or <member> to the element it belongs to
+fn add_child(element: &mut Element, tag: &BytesStart) -> Result<(), String> {
+    match tag.name().as_ref() {
+        b"tag" => {
+            let mut k = None;
+            let mut v = None;
+            for pair in attribute_pairs(tag.attributes()) {
+                let (key, value) = pair?;
+                match key.as_slice() {
+                    b"k" => k = Some(value),
+                    b"v" => v = Some(value),
+                    _ => (),
+                }
+            }
+            if let (Some(k), Some(v)) = (k, v) {
+                element.tags.insert(k, v);
+            }
+        }
+        b"nd" => {
+            if let ElementType::Way { ref mut nodes } = element.element_type {
+                let mut node = None;
+                for pair in attribute_pairs(tag.attributes()) {
+                    let (key, value) = pair?;
+                    if key == b"ref" {
+                        node = Some(parse_value(&key, &value)?);
+                    }
+                }
+                nodes.push(node.ok_or_else(|| missing_attribute(tag, "ref"))?);
+            }
+        }
+        b"member" => {
+            if let ElementType::Relation { ref mut members } = element.element_type {
+                let mut t = None;
+                let mut id = None;
+                let mut role = None;
+                for pair in attribute_pairs(tag.attributes()) {
+                    let (key, value) = pair?;
+                    match key.as_slice() {
+                        b"type" => t = Some(parse_simple_element_type(&value)?),
+                        b"ref" => id = Some(parse_value(&key, &value)?),
+                        b"role" => role = Some(value),
+                        _ => (),
+                    }
+                }
+                // other formats can't store members without a type
+                members.push(Member {
+                    t: Some(t.ok_or_else(|| missing_attribute(tag, "type"))?),
+                    id: id.ok_or_else(|| missing_attribute(tag, "ref"))?,
+                    role,
+                });
+            }
+        }
+        _ => (),
+    }
+    Ok(())
 }
 
-fn member_vec_annotation<'de, D>(deserializer: D) -> Result<Vec<Member>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    #[derive(Deserialize)]
-    struct Wrapper(#[serde(with = "MemberDef")] Member);
+// read document-level metadata from the <osm> (or <osmChange>), <bounds> and <meta> tags
+fn add_metadata(metadata: &mut Metadata, tag: &BytesStart) -> Result<(), String> {
+    match tag.name().as_ref() {
+        b"osm" | b"osmChange" => {
+            for pair in attribute_pairs(tag.attributes()) {
+                let (key, value) = pair?;
+                match key.as_slice() {
+                    b"version" => metadata.version = Some(value),
+                    b"generator" => metadata.generator = Some(value),
+                    b"copyright" => metadata.copyright = Some(value),
+                    b"license" => metadata.license = Some(value),
+                    b"timestamp" => metadata.timestamp = Some(value),
+                    _ => (),
+                }
+            }
+        }
+        b"bounds" => {
+            let mut bounds = Bounds {
+                min_lat: 0.0,
+                min_lon: 0.0,
+                max_lat: 0.0,
+                max_lon: 0.0,
+            };
+            for pair in attribute_pairs(tag.attributes()) {
+                let (key, value) = pair?;
+                match key.as_slice() {
+                    b"minlat" => bounds.min_lat = parse_value(&key, &value)?,
+                    b"minlon" => bounds.min_lon = parse_value(&key, &value)?,
+                    b"maxlat" => bounds.max_lat = parse_value(&key, &value)?,
+                    b"maxlon" => bounds.max_lon = parse_value(&key, &value)?,
+                    _ => (),
+                }
+            }
+            metadata.bbox = Some(bounds);
+        }
+        // Overpass API output puts the data timestamp in <meta osm_base="...">
+        b"meta" => {
+            for pair in attribute_pairs(tag.attributes()) {
+                let (key, value) = pair?;
+                if key == b"osm_base" {
+                    metadata.timestamp = Some(value);
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
 
-    let v = Vec::deserialize(deserializer)?;
-    Ok(v.into_iter().map(|Wrapper(a)| a).collect())
+fn parse_action(name: &[u8]) -> Option<Action> {
+    match name {
+        b"create" => Some(Action::Create),
+        b"modify" => Some(Action::Modify),
+        b"delete" => Some(Action::Delete),
+        _ => None,
+    }
 }
 
-#[derive(Deserialize)]
-struct XmlRelation {
-    #[serde(flatten)]
-    meta: XmlElementMeta,
-    #[serde(deserialize_with = "member_vec_annotation")]
-    member: Vec<Member>,
-    #[serde(default, rename = "tag")]
-    tags: Vec<XmlTags>,
+fn send_metadata(
+    metadata: &mut Option<Metadata>,
+    metadata_sender: &Sender<Metadata>,
+) -> Result<(), SkywayError> {
+    if let Some(m) = metadata.take() {
+        metadata_sender.send(m)?;
+    }
+    Ok(())
 }
 
-// for now, bounds are not converted
-// #[derive(Deserialize)]
-// struct Bounds {
-//     #[serde(rename = "@minlat")]
-//     minlat: String,
-//     #[serde(rename = "@minlon")]
-//     minlon: String,
-//     #[serde(rename = "@maxlat")]
-//     maxlat: String,
-//     #[serde(rename = "@maxlon")]
-//     maxlon: String,
-// }
+fn parse_error(position: u64, message: String) -> SkywayError {
+    SkywayError::ParseError {
+        format: "XML",
+        position: Position::Byte(position),
+        message,
+    }
+}
 
-#[derive(Deserialize)]
-#[serde(rename = "osm")]
-struct OsmXmlDocument {
-    #[serde(flatten, with = "MetadataDef")]
-    metadata: Metadata,
-    // bounds: Bounds,
-    #[serde(default)]
-    node: Vec<XmlNode>,
-    #[serde(default)]
-    way: Vec<XmlWay>,
-    #[serde(default)]
-    relation: Vec<XmlRelation>,
+// a tag as it was written, for the rejects file
+fn raw_tag(tag: &BytesStart, is_empty: bool) -> Vec<u8> {
+    let mut raw = vec![b'<'];
+    raw.extend_from_slice(tag);
+    raw.extend_from_slice(if is_empty { b"/>" } else { b">" });
+    raw
 }
 
-enum XmlElement {
-    Node(XmlNode),
-    Way(XmlWay),
-    Relation(XmlRelation),
+// the state of a document as it is being read
+struct XmlDocument {
+    // document metadata is read from the tags before the first element,
+    // so it is sent once that element starts (or at the end of the input)
+    metadata: Option<Metadata>,
+    // the OsmChange block (<create>, <modify> or <delete>) being read, if any
+    action: Option<Action>,
+    current: Option<Element>,
+    // the error an element that is still being read will be rejected with once it
+    // ends, so that all of its raw text can be written to the rejects file
+    rejected: Option<SkywayError>,
+    // the raw text of the element being read, if there is a rejects file
+    raw: Vec<u8>,
+    chunk: Vec<Element>,
+    rejects: Arc<Rejects>,
 }
 
-fn convert_tags(xml_tags: Vec<XmlTags>) -> HashMap<String, String> {
-    let mut tags_hashmap = HashMap::new();
-    for tag in xml_tags {
-        tags_hashmap.insert(tag.k, tag.v);
+impl XmlDocument {
+    // `position` is where the tag starts, for error messages
+    fn start_tag(
+        &mut self,
+        tag: &BytesStart,
+        is_empty: bool,
+        position: u64,
+        metadata_sender: &Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
+        if let Some(ref mut element) = self.current {
+            if let Err(e) = add_child(element, tag) {
+                // the element is dropped, and without a current element
+                // the rest of its child tags are ignored
+                self.current = None;
+                self.rejected = Some(parse_error(position, e));
+            }
+        } else if self.rejected.is_some() {
+            // a child tag of an element that will be rejected
+        } else if let Some(action) = parse_action(tag.name().as_ref()) {
+            if !is_empty {
+                self.action = Some(action);
+            }
+        } else {
+            match start_element(tag, self.action) {
+                Ok(Some(mut element)) => {
+                    send_metadata(&mut self.metadata, metadata_sender)?;
+                    element.action = self.action;
+                    // deleted elements don't always say that they are no longer visible
+                    if self.action == Some(Action::Delete) && element.visible.is_none() {
+                        element.visible = Some(false);
+                    }
+                    if is_empty {
+                        self.chunk.push(element);
+                    } else {
+                        self.current = Some(element);
+                    }
+                }
+                Ok(None) => {
+                    if let Some(ref mut m) = self.metadata {
+                        add_metadata(m, tag).map_err(|e| parse_error(position, e))?;
+                    }
+                }
+                Err(e) if is_empty => {
+                    let raw = raw_tag(tag, is_empty);
+                    self.rejects.reject(parse_error(position, e), Some(&raw))?;
+                }
+                Err(e) => self.rejected = Some(parse_error(position, e)),
+            }
+        }
+        Ok(())
     }
-    tags_hashmap
-}
 
-fn convert_element(xml_element: XmlElement) -> Element {
-    match xml_element {
-        XmlElement::Node(node) => Element {
-            changeset: node.meta.changeset,
-            user: node.meta.user,
-            version: node.meta.version,
-            uid: node.meta.uid,
-            id: node.meta.id,
-            timestamp: node.meta.timestamp,
-            visible: Some(node.meta.visible),
-            tags: convert_tags(node.tags),
-            element_type: ElementType::Node {
-                lat: node.lat,
-                lon: node.lon,
-            },
-        },
-        XmlElement::Way(way) => Element {
-            changeset: way.meta.changeset,
-            user: way.meta.user,
-            version: way.meta.version,
-            uid: way.meta.uid,
-            id: way.meta.id,
-            timestamp: way.meta.timestamp,
-            visible: Some(way.meta.visible),
-            tags: convert_tags(way.tags),
-            element_type: ElementType::Way {
-                nodes: way.nd.iter().map(|n| n.nd_ref).collect(),
-            },
-        },
-        XmlElement::Relation(rel) => Element {
-            changeset: rel.meta.changeset,
-            user: rel.meta.user,
-            version: rel.meta.version,
-            uid: rel.meta.uid,
-            id: rel.meta.id,
-            timestamp: rel.meta.timestamp,
-            visible: Some(rel.meta.visible),
-            tags: convert_tags(rel.tags),
-            element_type: ElementType::Relation {
-                members: rel.member,
-            },
-        },
+    // keep the raw text of an event that is part of an element, which
+    // includes the element's start and end tags
+    fn record(&mut self, event: &Event) {
+        if (self.current.is_some() || self.rejected.is_some()) && self.rejects.keeps_raw() {
+            quick_xml::Writer::new(&mut self.raw)
+                .write_event(event.borrow())
+                .expect("Unable to write to a Vec");
+        }
+    }
+
+    fn end_tag(&mut self, name: &[u8]) -> Result<(), SkywayError> {
+        if matches!(name, b"node" | b"way" | b"relation") {
+            if let Some(element) = self.current.take() {
+                self.chunk.push(element);
+            } else if let Some(error) = self.rejected.take() {
+                self.rejects.reject(error, Some(&self.raw))?;
+            }
+            self.raw.clear();
+        } else if parse_action(name).is_some() {
+            self.action = None;
+        }
+        Ok(())
     }
 }
 
 pub struct XmlReader {
-    pub src: String,
+    pub src: Box<dyn BufRead + Send>,
+    pub rejects: Arc<Rejects>,
 }
 
 impl Reader for XmlReader {
-    fn read(&mut self, sender: Sender<Vec<Element>>, metadata_sender: Sender<Metadata>) {
-        let osm_xml_object: OsmXmlDocument = match from_str(&self.src) {
-            Ok(v) => v,
-            Err(e) => {
-                panic!("ERROR: Could not parse XML file: {e:?}");
+    fn read(
+        &mut self,
+        sender: ChunkSender,
+        metadata_sender: Sender<Metadata>,
+    ) -> Result<(), SkywayError> {
+        let mut reader = quick_xml::Reader::from_reader(&mut self.src);
+        let mut buffer = Vec::new();
+        let mut document = XmlDocument {
+            metadata: Some(Metadata::default()),
+            action: None,
+            current: None,
+            rejected: None,
+            raw: Vec::new(),
+            chunk: Vec::new(),
+            rejects: self.rejects.clone(),
+        };
+
+        loop {
+            let position = reader.buffer_position();
+            let event = match reader.read_event_into(&mut buffer) {
+                Ok(Event::Eof) => break,
+                Ok(event) => event,
+                Err(quick_xml::Error::Io(e)) => {
+                    return Err(SkywayError::IoError(std::io::Error::new(e.kind(), e)))
+                }
+                Err(e) => return Err(parse_error(reader.error_position(), e.to_string())),
+            };
+            match &event {
+                Event::Start(tag) => document.start_tag(tag, false, position, &metadata_sender)?,
+                Event::Empty(tag) => document.start_tag(tag, true, position, &metadata_sender)?,
+                _ => (),
+            }
+            document.record(&event);
+            if let Event::End(tag) = &event {
+                document.end_tag(tag.name().as_ref())?;
+            }
+
+            // send elements in chunks as they are read
+            if document.chunk.len() >= 1000 {
+                sender.send(mem::take(&mut document.chunk))?;
+            }
+            buffer.clear();
+        }
+
+        send_metadata(&mut document.metadata, &metadata_sender)?;
+        if !document.chunk.is_empty() {
+            sender.send(document.chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::readers::OnError;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_read_xml() {
+        let src = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="test">
+  <bounds minlat="37.5" minlon="-77.5" maxlat="37.6" maxlon="-77.4"/>
+  <node id="1" lat="37.55" lon="-77.45" version="2" user="John &amp; Jane" uid="42"/>
+  <node id="2" lat="37.56" lon="-77.46" visible="false">
+    <tag k="amenity" v="cafe"/>
+  </node>
+  <way id="3"><nd ref="1"/><nd ref="2"/><tag k="highway" v="path"/></way>
+  <relation id="4"><member type="way" ref="3" role="outer"/></relation>
+</osm>"#;
+        let mut reader = XmlReader {
+            src: Box::new(src.as_bytes()),
+            rejects: Arc::default(),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, metadata_receiver) = channel();
+        reader.read(sender, metadata_sender).unwrap();
+
+        let metadata = metadata_receiver.recv().unwrap();
+        assert_eq!(metadata.generator.as_deref(), Some("test"));
+        assert_eq!(metadata.bbox.map(|b| b.max_lon), Some(-77.4));
+
+        let elements: Vec<Element> = receiver.iter().flatten().collect();
+        assert_eq!(elements.len(), 4);
+        assert_eq!(elements[0].user.as_deref(), Some("John & Jane"));
+        assert!(matches!(
+            elements[0].element_type,
+            ElementType::Node { lat, lon } if lat == 37.55 && lon == -77.45
+        ));
+        assert_eq!(elements[1].visible, Some(false));
+        assert_eq!(elements[1].tags["amenity"], "cafe");
+        assert!(matches!(
+            &elements[2].element_type,
+            ElementType::Way { nodes } if nodes == &vec![1, 2]
+        ));
+        match &elements[3].element_type {
+            ElementType::Relation { members } => {
+                assert_eq!(members[0].id, 3);
+                assert_eq!(members[0].role.as_deref(), Some("outer"));
             }
+            _ => panic!("expected a relation"),
+        }
+    }
+
+    #[test]
+    fn test_read_osm_change() {
+        let src = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6" generator="test">
+  <create><node id="-1" lat="37.5" lon="-77.4" version="1"/></create>
+  <modify><way id="2" version="3"><nd ref="-1"/></way></modify>
+  <delete if-unused="true"><node id="3" version="4"/></delete>
+  <node id="4" lat="0" lon="0"/>
+</osmChange>"#;
+        let mut reader = XmlReader {
+            src: Box::new(src.as_bytes()),
+            rejects: Arc::default(),
         };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, metadata_receiver) = channel();
+        reader.read(sender, metadata_sender).unwrap();
+
+        assert_eq!(
+            metadata_receiver.recv().unwrap().generator.as_deref(),
+            Some("test")
+        );
+        let elements: Vec<Element> = receiver.iter().flatten().collect();
+        let actions: Vec<_> = elements.iter().map(|e| e.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                Some(Action::Create),
+                Some(Action::Modify),
+                Some(Action::Delete),
+                None
+            ]
+        );
+        assert_eq!(elements[2].visible, Some(false));
+    }
 
-        // send OSM document metadata to main thread
-        metadata_sender
-            .send(osm_xml_object.metadata)
-            .expect("Couldn't send metdata to main thread!");
+    #[test]
+    fn test_read_invalid_xml() {
+        let src = r#"<osm version="0.6">
+  <node id="1" lat="37.5" lon="-77.4"/>
+  <node id="2" lat="north" lon="-77.4"/>
+</osm>"#;
+        let mut reader = XmlReader {
+            src: Box::new(src.as_bytes()),
+            rejects: Arc::default(),
+        };
+        let (sender, _receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = channel();
+        let error = reader.read(sender, metadata_sender).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Could not parse XML input at byte 62: Invalid value \"north\" for attribute \"lat\""
+        );
 
-        // send each deserialized element to the next processing step
-        osm_xml_object
-            .node
-            .into_iter()
-            .map(|n| convert_element(XmlElement::Node(n)))
-            .chain(
-                osm_xml_object
-                    .way
-                    .into_iter()
-                    .map(|w| convert_element(XmlElement::Way(w))),
-            )
-            .chain(
-                osm_xml_object
-                    .relation
-                    .into_iter()
-                    .map(|r| convert_element(XmlElement::Relation(r))),
-            )
-            .chunks(1000)
-            .into_iter()
-            .for_each(|e| {
-                sender
-                    .send(e.collect())
-                    .expect("Unable to send element to channel")
-            });
+        // in skip mode, the nodes are dropped, along with a way with a bad child tag,
+        // and the whole of each element is written to the rejects file
+        let src = r#"<osm version="0.6">
+  <node id="1" lat="37.5" lon="-77.4"/>
+  <node id="2" lat="north" lon="-77.4"/>
+  <node id="6" lat="north" lon="-77.4">
+    <tag k="amenity" v="cafe"/>
+  </node>
+  <way id="3"><nd ref="x"/><tag k="highway" v="path"/></way>
+  <way id="4"><nd ref="1"/></way>
+  <relation id="5"><member ref="4" role="x"/></relation>
+  <node lat="37.5" lon="-77.4"/>
+  <way id="7"><nd/></way>
+  <node id="8" lat="37.5"/>
+</osm>"#;
+        let rejects_file = tempfile::NamedTempFile::new().unwrap();
+        let mut reader = XmlReader {
+            src: Box::new(src.as_bytes()),
+            rejects: Arc::new(Rejects::new(
+                OnError::Skip,
+                Some(Box::new(rejects_file.reopen().unwrap())),
+            )),
+        };
+        let (sender, receiver) = chunk_channel(16);
+        let (metadata_sender, _metadata_receiver) = channel();
+        reader.read(sender, metadata_sender).unwrap();
+        let ids: Vec<i64> = receiver.iter().flatten().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 4]);
+        assert_eq!(reader.rejects.count(), 7);
+        assert_eq!(
+            std::fs::read_to_string(rejects_file.path()).unwrap(),
+            r#"<node id="2" lat="north" lon="-77.4"/>
+<node id="6" lat="north" lon="-77.4">
+    <tag k="amenity" v="cafe"/>
+  </node>
+<way id="3"><nd ref="x"/><tag k="highway" v="path"/></way>
+<relation id="5"><member ref="4" role="x"/></relation>
+<node lat="37.5" lon="-77.4"/>
+<way id="7"><nd/></way>
+<node id="8" lat="37.5"/>
+"#
+        );
     }
 }
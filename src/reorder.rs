@@ -0,0 +1,94 @@
+//! Restores the order of chunks that were processed in parallel.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::Receiver;
+
+/// Buffers numbered items that arrive out of order, releasing them in sequence.
+/// If order doesn't need to be preserved, items are released as soon as they arrive.
+pub(crate) struct ReorderBuffer<T> {
+    preserve_order: bool,
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub(crate) fn new(preserve_order: bool) -> Self {
+        ReorderBuffer {
+            preserve_order,
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, sequence: usize, item: T) {
+        self.pending.insert(sequence, item);
+    }
+
+    /// Returns the next item that is ready to be released, if any.
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        if !self.preserve_order {
+            return self.pending.pop_first().map(|(_, item)| item);
+        }
+        let item = self.pending.remove(&self.next)?;
+        self.next += 1;
+        Some(item)
+    }
+}
+
+/// An iterator over the items received from a channel of numbered items, in order.
+pub(crate) struct Reordered<T> {
+    receiver: Receiver<(usize, T)>,
+    buffer: ReorderBuffer<T>,
+}
+
+impl<T> Iterator for Reordered<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.buffer.pop() {
+                return Some(item);
+            }
+            match self.receiver.recv() {
+                Ok((sequence, item)) => self.buffer.push(sequence, item),
+                // once every sender is gone, release whatever is left
+                Err(_) => return self.buffer.pending.pop_first().map(|(_, item)| item),
+            }
+        }
+    }
+}
+
+/// Reorders a channel of items numbered by their position in the input.
+pub(crate) fn reorder<T>(receiver: Receiver<(usize, T)>, preserve_order: bool) -> Reordered<T> {
+    Reordered {
+        receiver,
+        buffer: ReorderBuffer::new(preserve_order),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_reorder() {
+        let (sender, receiver) = channel();
+        for sequence in [2, 0, 3, 1, 4] {
+            sender.send((sequence, sequence)).unwrap();
+        }
+        drop(sender);
+        assert_eq!(
+            reorder(receiver, true).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_reorder_buffer_unordered() {
+        let mut buffer = ReorderBuffer::new(false);
+        buffer.push(3, "c");
+        assert_eq!(buffer.pop(), Some("c"));
+        assert_eq!(buffer.pop(), None);
+    }
+}
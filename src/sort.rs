@@ -0,0 +1,273 @@
+//! Sorts OSM data by type, then ID.
+
+use indicatif::ProgressBar;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
+use std::mem;
+use std::sync::mpsc::Receiver;
+
+use crate::channel::ChunkSender;
+use crate::elements::{Element, ElementType};
+use crate::progress::start_spinner;
+use crate::spool::{read_element, write_element};
+use crate::SkywayError;
+
+/// The optional PBF header feature for files sorted by type, then ID.
+pub const SORT_TYPE_THEN_ID: &str = "Sort.Type_then_ID";
+
+// the number of elements sorted in memory at once; anything
+// beyond this is sorted in runs that are spilled to temporary files
+const RUN_SIZE: usize = 1_000_000;
+
+// the most run files that are merged (and open) at once
+const MERGE_FAN_IN: usize = 64;
+
+/// The order of elements in a file sorted by type, then ID: nodes, then ways, then relations.
+pub fn sort_key(element: &Element) -> (u8, i64) {
+    let type_order = match element.element_type {
+        ElementType::Node { .. } => 0,
+        ElementType::Way { .. } => 1,
+        ElementType::Relation { .. } => 2,
+    };
+    (type_order, element.id)
+}
+
+// write sorted elements to an anonymous temporary file, which is
+// removed automatically once it's closed
+fn write_run<I: Iterator<Item = io::Result<Element>>>(elements: I) -> io::Result<BufReader<File>> {
+    let mut writer = BufWriter::new(tempfile::tempfile()?);
+    for element in elements {
+        write_element(&mut writer, &element?)?;
+    }
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(BufReader::new(file))
+}
+
+// sort a run of elements and spill it to a temporary file
+fn spill_run(run: &mut Vec<Element>) -> io::Result<BufReader<File>> {
+    run.sort_by_key(sort_key);
+    write_run(run.drain(..).map(Ok))
+}
+
+fn send_sorted<I: Iterator<Item = io::Result<Element>>>(
+    elements: I,
+    sender: &ChunkSender,
+) -> Result<(), SkywayError> {
+    let mut output = Vec::new();
+    for element in elements {
+        output.push(element?);
+        if output.len() >= 1000 {
+            sender.send(mem::take(&mut output))?;
+        }
+    }
+    if !output.is_empty() {
+        sender.send(output)?;
+    }
+    Ok(())
+}
+
+// merges sorted run files, taking the smallest element from any run each time.
+// ties go to the earliest run, so elements with the same key keep their input order
+struct Merge {
+    runs: Vec<BufReader<File>>,
+    heads: Vec<Option<Element>>,
+    heap: BinaryHeap<Reverse<((u8, i64), usize)>>,
+}
+
+impl Merge {
+    fn new(mut runs: Vec<BufReader<File>>) -> io::Result<Self> {
+        let heads: Vec<Option<Element>> = runs
+            .iter_mut()
+            .map(read_element)
+            .collect::<io::Result<_>>()?;
+        let heap = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|e| Reverse((sort_key(e), i))))
+            .collect();
+        Ok(Merge { runs, heads, heap })
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<Element>;
+
+    fn next(&mut self) -> Option<io::Result<Element>> {
+        let Reverse((_, i)) = self.heap.pop()?;
+        let next = match read_element(&mut self.runs[i]) {
+            Ok(next) => next,
+            Err(e) => return Some(Err(e)),
+        };
+        if let Some(e) = &next {
+            self.heap.push(Reverse((sort_key(e), i)));
+        }
+        mem::replace(&mut self.heads[i], next).map(Ok)
+    }
+}
+
+// merge the runs, at most `fan_in` at a time so that a large input doesn't open thousands
+// of files at once. each pass merges neighbouring runs, which keeps ties in input order
+fn merge_runs(
+    mut runs: Vec<BufReader<File>>,
+    sender: &ChunkSender,
+    fan_in: usize,
+) -> Result<(), SkywayError> {
+    while runs.len() > fan_in {
+        let mut merged = Vec::new();
+        let mut remaining = runs.into_iter().peekable();
+        while remaining.peek().is_some() {
+            let group: Vec<_> = remaining.by_ref().take(fan_in).collect();
+            merged.push(write_run(Merge::new(group)?)?);
+        }
+        runs = merged;
+    }
+    send_sorted(Merge::new(runs)?, sender)
+}
+
+fn external_sort(
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    run_size: usize,
+    fan_in: usize,
+) -> Result<(), SkywayError> {
+    let mut run = Vec::new();
+    let mut runs = Vec::new();
+    for element in receiver.iter().flatten() {
+        run.push(element);
+        if run.len() >= run_size {
+            runs.push(spill_run(&mut run)?);
+        }
+    }
+
+    // if everything fit in memory, there's nothing to merge
+    if runs.is_empty() {
+        run.sort_by_key(sort_key);
+        return send_sorted(run.into_iter().map(Ok), &sender);
+    }
+    if !run.is_empty() {
+        runs.push(spill_run(&mut run)?);
+    }
+    merge_runs(runs, &sender, fan_in)
+}
+
+/// Sorts elements by type, then ID. Elements with the same type and ID stay in input order.
+/// Input that doesn't fit in memory is sorted in runs, which are written to temporary
+/// files and merged, at most 64 at a time.
+///
+/// * `receiver`: Receiver for a channel of `Element`s.
+/// * `sender`: Sender for a channel of `Element`s.
+/// * `progress`: The ProgressBar for this operation.
+pub fn sort_elements(
+    receiver: Receiver<Vec<Element>>,
+    sender: ChunkSender,
+    progress: ProgressBar,
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Sorting elements...");
+
+    external_sort(receiver, sender, RUN_SIZE, MERGE_FAN_IN)?;
+
+    progress.finish_with_message("Sorting elements...done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::chunk_channel;
+    use crate::elements::{Member, SimpleElementType};
+    use crate::fixtures;
+    use std::sync::mpsc::channel;
+
+    fn element(id: i64, element_type: ElementType) -> Element {
+        Element {
+            changeset: Some(3),
+            user: Some(String::from("John")),
+            version: Some(2),
+            timestamp: Some(String::from("2024-09-25T14:03:00Z")),
+            visible: Some(true),
+            ..fixtures::element(id, element_type, &[("highway", "path")])
+        }
+    }
+
+    fn node(id: i64) -> Element {
+        element(
+            id,
+            ElementType::Node {
+                lat: 1.5,
+                lon: -2.25,
+            },
+        )
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                element(
+                    1,
+                    ElementType::Relation {
+                        members: vec![Member {
+                            t: Some(SimpleElementType::Way),
+                            id: 5,
+                            role: Some(String::from("outer")),
+                        }],
+                    },
+                ),
+                node(3),
+                element(5, ElementType::Way { nodes: vec![3, 2] }),
+                node(2),
+            ])
+            .unwrap();
+        sender.send(vec![node(-1), node(4), node(1)]).unwrap();
+        drop(sender);
+
+        // a run size smaller than the input forces runs to be merged from disk
+        let (output_sender, output_receiver) = chunk_channel(16);
+        external_sort(receiver, output_sender, 3, 64).unwrap();
+        let output: Vec<Element> = output_receiver.iter().flatten().collect();
+
+        assert_eq!(
+            output.iter().map(sort_key).collect::<Vec<_>>(),
+            vec![(0, -1), (0, 1), (0, 2), (0, 3), (0, 4), (1, 5), (2, 1)]
+        );
+        assert_eq!(format!("{:?}", output[1]), format!("{:?}", node(1)));
+        assert_eq!(
+            format!("{:?}", output[6].element_type),
+            "Relation { members: [Member { t: Some(Way), id: 5, role: Some(\"outer\") }] }"
+        );
+    }
+
+    #[test]
+    fn test_external_sort_in_passes() {
+        // equal keys have to come out in input order after several merge passes
+        let (sender, receiver) = channel();
+        sender
+            .send(
+                (0..20)
+                    .map(|i| Element {
+                        version: Some(i),
+                        ..node(i64::from(i % 3))
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        drop(sender);
+
+        let (output_sender, output_receiver) = chunk_channel(16);
+        external_sort(receiver, output_sender, 2, 2).unwrap();
+        let output: Vec<(i64, Option<i32>)> = output_receiver
+            .iter()
+            .flatten()
+            .map(|e| (e.id, e.version))
+            .collect();
+
+        let mut expected: Vec<(i64, Option<i32>)> =
+            (0..20).map(|i| (i64::from(i % 3), Some(i))).collect();
+        expected.sort_by_key(|(id, _)| *id);
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,226 @@
+//! Temporary files for stages that need to hold on to (or re-read) more data than fits in memory.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::elements::{Action, Element, ElementType, Member, SimpleElementType};
+
+fn write_u8<W: Write>(w: &mut W, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+fn write_i64<W: Write>(w: &mut W, value: i64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_string<W: Write>(w: &mut W, value: &str) -> io::Result<()> {
+    write_i64(w, value.len() as i64)?;
+    w.write_all(value.as_bytes())
+}
+
+// optional values are preceded by a flag saying whether they're present
+fn write_option<W: Write, T>(
+    w: &mut W,
+    value: Option<T>,
+    write_value: impl FnOnce(&mut W, T) -> io::Result<()>,
+) -> io::Result<()> {
+    match value {
+        Some(v) => {
+            write_u8(w, 1)?;
+            write_value(w, v)
+        }
+        None => write_u8(w, 0),
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buffer = [0; 1];
+    r.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_i64<R: Read>(r: &mut R) -> io::Result<i64> {
+    let mut buffer = [0; 8];
+    r.read_exact(&mut buffer)?;
+    Ok(i64::from_le_bytes(buffer))
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut buffer = vec![0; read_i64(r)? as usize];
+    r.read_exact(&mut buffer)?;
+    String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_option<R: Read, T>(
+    r: &mut R,
+    read_value: impl FnOnce(&mut R) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    match read_u8(r)? {
+        0 => Ok(None),
+        _ => read_value(r).map(Some),
+    }
+}
+
+// serialize an element to a temporary file, in a format that is only
+// meant to be read back by `read_element` within the same process
+pub(crate) fn write_element<W: Write>(w: &mut W, element: &Element) -> io::Result<()> {
+    write_i64(w, element.id)?;
+    match &element.element_type {
+        ElementType::Node { lat, lon } => {
+            write_u8(w, 0)?;
+            w.write_all(&lat.to_le_bytes())?;
+            w.write_all(&lon.to_le_bytes())?;
+        }
+        ElementType::Way { nodes } => {
+            write_u8(w, 1)?;
+            write_i64(w, nodes.len() as i64)?;
+            for node in nodes {
+                write_i64(w, *node)?;
+            }
+        }
+        ElementType::Relation { members } => {
+            write_u8(w, 2)?;
+            write_i64(w, members.len() as i64)?;
+            for member in members {
+                write_u8(
+                    w,
+                    match member.t {
+                        None => 0,
+                        Some(SimpleElementType::Node) => 1,
+                        Some(SimpleElementType::Way) => 2,
+                        Some(SimpleElementType::Relation) => 3,
+                    },
+                )?;
+                write_i64(w, member.id)?;
+                write_option(w, member.role.as_deref(), write_string)?;
+            }
+        }
+    }
+    write_option(w, element.changeset, write_i64)?;
+    write_option(w, element.user.as_deref(), write_string)?;
+    write_option(w, element.version, |w, v| write_i64(w, v.into()))?;
+    write_option(w, element.uid, |w, v| write_i64(w, v.into()))?;
+    write_option(w, element.timestamp.as_deref(), write_string)?;
+    write_option(w, element.visible, |w, v| write_u8(w, v.into()))?;
+    write_i64(w, element.tags.len() as i64)?;
+    for (k, v) in &element.tags {
+        write_string(w, k)?;
+        write_string(w, v)?;
+    }
+    write_option(w, element.action, |w, a| {
+        write_u8(
+            w,
+            match a {
+                Action::Create => 0,
+                Action::Modify => 1,
+                Action::Delete => 2,
+            },
+        )
+    })
+}
+
+// read the next element from a temporary file, or None at the end of the file
+pub(crate) fn read_element<R: Read>(r: &mut R) -> io::Result<Option<Element>> {
+    let id = match read_i64(r) {
+        Ok(id) => id,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let element_type = match read_u8(r)? {
+        0 => {
+            let lat = f64::from_bits(read_i64(r)? as u64);
+            let lon = f64::from_bits(read_i64(r)? as u64);
+            ElementType::Node { lat, lon }
+        }
+        1 => {
+            let count = read_i64(r)?;
+            let nodes = (0..count).map(|_| read_i64(r)).collect::<io::Result<_>>()?;
+            ElementType::Way { nodes }
+        }
+        _ => {
+            let count = read_i64(r)?;
+            let mut members = Vec::new();
+            for _ in 0..count {
+                let t = match read_u8(r)? {
+                    1 => Some(SimpleElementType::Node),
+                    2 => Some(SimpleElementType::Way),
+                    3 => Some(SimpleElementType::Relation),
+                    _ => None,
+                };
+                let id = read_i64(r)?;
+                let role = read_option(r, read_string)?;
+                members.push(Member { t, id, role });
+            }
+            ElementType::Relation { members }
+        }
+    };
+    let changeset = read_option(r, read_i64)?;
+    let user = read_option(r, read_string)?;
+    let version = read_option(r, |r| read_i64(r).map(|v| v as i32))?;
+    let uid = read_option(r, |r| read_i64(r).map(|v| v as i32))?;
+    let timestamp = read_option(r, read_string)?;
+    let visible = read_option(r, |r| read_u8(r).map(|v| v != 0))?;
+    let tag_count = read_i64(r)?;
+    let mut tags = HashMap::new();
+    for _ in 0..tag_count {
+        tags.insert(read_string(r)?, read_string(r)?);
+    }
+    let action = read_option(r, |r| {
+        read_u8(r).map(|a| match a {
+            0 => Action::Create,
+            1 => Action::Modify,
+            _ => Action::Delete,
+        })
+    })?;
+    Ok(Some(Element {
+        changeset,
+        user,
+        version,
+        uid,
+        id,
+        timestamp,
+        visible,
+        tags,
+        element_type,
+        action,
+    }))
+}
+
+/// Elements written to an anonymous temporary file, which can be read back any number
+/// of times. The file is removed automatically once the spool is dropped.
+pub(crate) struct Spool(BufWriter<File>);
+
+impl Spool {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Spool(BufWriter::new(tempfile::tempfile()?)))
+    }
+
+    pub(crate) fn push(&mut self, element: &Element) -> io::Result<()> {
+        write_element(&mut self.0, element)
+    }
+
+    /// Appends an element that was already serialized with `write_element`.
+    pub(crate) fn push_encoded(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)
+    }
+
+    /// Reads back every element in the spool, in the order they were pushed.
+    /// Nothing should be pushed after the spool has been read.
+    pub(crate) fn iter(&mut self) -> io::Result<SpoolIter<'_>> {
+        self.0.flush()?;
+        let file = self.0.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SpoolIter(BufReader::new(file)))
+    }
+}
+
+pub(crate) struct SpoolIter<'a>(BufReader<&'a mut File>);
+
+impl Iterator for SpoolIter<'_> {
+    type Item = io::Result<Element>;
+
+    fn next(&mut self) -> Option<io::Result<Element>> {
+        read_element(&mut self.0).transpose()
+    }
+}
@@ -0,0 +1,263 @@
+//! Reads and writes OSM data one element at a time, for using skyway as a library.
+//!
+//! Readers and writers work on channels of element chunks, on their own threads.
+//! This wraps them so that elements can be read from an iterator and written
+//! with a method call, without setting up channels or threads.
+
+use indicatif::ProgressBar;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::vec;
+
+use crate::channel::{chunk_channel, ChunkSender};
+use crate::elements::{Element, Metadata};
+use crate::pipeline::DEFAULT_BUFFER_CHUNKS;
+use crate::readers::{generate_reader, InputFileFormat, ReadOptions, Reader};
+use crate::writers::{write_file, OutputFileFormat, WriteOptions};
+use crate::SkywayError;
+
+/// Opens a file, returning its metadata and an iterator over its elements.
+///
+/// ```no_run
+/// use skyway::readers::InputFileFormat;
+///
+/// let (metadata, elements) = skyway::open("richmond.pbf", InputFileFormat::Pbf)?;
+/// for element in elements {
+///     println!("{}", element?.id);
+/// }
+/// # Ok::<(), skyway::SkywayError>(())
+/// ```
+pub fn open<P: AsRef<Path>>(
+    path: P,
+    format: InputFileFormat,
+) -> Result<(Metadata, Elements), SkywayError> {
+    open_with_options(path, format, ReadOptions::default())
+}
+
+/// Opens a file like [`open`], with settings for the reader.
+pub fn open_with_options<P: AsRef<Path>>(
+    path: P,
+    format: InputFileFormat,
+    options: ReadOptions,
+) -> Result<(Metadata, Elements), SkywayError> {
+    let reader = generate_reader(format, Some(path.as_ref().to_path_buf()), options)?;
+    read_elements(reader)
+}
+
+/// Starts a reader on its own thread, returning the input's metadata and an iterator
+/// over its elements. If the input can't be read at all, the error is returned here.
+pub fn read_elements(mut reader: Box<dyn Reader>) -> Result<(Metadata, Elements), SkywayError> {
+    let (sender, receiver) = chunk_channel(DEFAULT_BUFFER_CHUNKS);
+    let (metadata_sender, metadata_receiver) = mpsc::channel();
+    let thread = thread::spawn(move || reader.read(sender, metadata_sender));
+
+    // readers send metadata before anything else, so if
+    // there isn't any, reading has already failed
+    match metadata_receiver.recv() {
+        Ok(metadata) => Ok((
+            metadata,
+            Elements {
+                receiver,
+                chunk: Vec::new().into_iter(),
+                thread: Some(thread),
+            },
+        )),
+        Err(_) => {
+            thread.join().expect("Couldn't join on read thread!!")?;
+            Err(SkywayError::ChannelError)
+        }
+    }
+}
+
+/// Iterator over the elements of an input, returned by [`open`].
+///
+/// If reading fails, the error is the last item. Dropping the iterator early stops the reader.
+pub struct Elements {
+    receiver: Receiver<Vec<Element>>,
+    chunk: vec::IntoIter<Element>,
+    thread: Option<JoinHandle<Result<(), SkywayError>>>,
+}
+
+impl Elements {
+    /// Returns the channel the reader sends chunks of elements to, and its thread, for
+    /// passing the elements on to other stages. Elements already taken from the iterator
+    /// are skipped, along with the rest of the chunk they were in.
+    pub fn into_chunks(mut self) -> (Receiver<Vec<Element>>, JoinHandle<Result<(), SkywayError>>) {
+        let thread = self.thread.take().expect("Read thread was already joined");
+        (self.receiver, thread)
+    }
+}
+
+impl Iterator for Elements {
+    type Item = Result<Element, SkywayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(element) = self.chunk.next() {
+                return Some(Ok(element));
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = chunk.into_iter(),
+                // the channel closes when the reader is done, which
+                // is when it can be asked whether anything went wrong
+                Err(_) => {
+                    let thread = self.thread.take()?;
+                    return thread
+                        .join()
+                        .expect("Couldn't join on read thread!!")
+                        .err()
+                        .map(Err);
+                }
+            }
+        }
+    }
+}
+
+/// Writes elements out one at a time, in any output format.
+///
+/// ```no_run
+/// use skyway::elements::Metadata;
+/// use skyway::writers::{OutputFileFormat, WriteOptions};
+/// use skyway::Writer;
+///
+/// let file = std::fs::File::create("richmond.opl")?;
+/// let mut writer = Writer::new(file, OutputFileFormat::Opl, Metadata::default(), WriteOptions::default());
+/// # let elements: Vec<skyway::elements::Element> = Vec::new();
+/// for element in elements {
+///     writer.write_element(element)?;
+/// }
+/// writer.finish()?;
+/// # Ok::<(), skyway::SkywayError>(())
+/// ```
+pub struct Writer<D> {
+    sender: Option<ChunkSender>,
+    chunk: Vec<Element>,
+    thread: Option<JoinHandle<Result<D, SkywayError>>>,
+}
+
+impl<D: Write + Send + 'static> Writer<D> {
+    /// Starts writing to `destination` on its own thread.
+    ///
+    /// * `destination`: Where to write the output.
+    /// * `to`: File format to write.
+    /// * `metadata`: Document-level metadata, written before the elements.
+    /// * `options`: Settings for the writers that use them.
+    pub fn new(
+        mut destination: D,
+        to: OutputFileFormat,
+        metadata: Metadata,
+        options: WriteOptions,
+    ) -> Self {
+        let (sender, receiver) = chunk_channel(options.buffer_chunks);
+        let thread = thread::spawn(move || {
+            write_file(
+                receiver,
+                metadata,
+                to,
+                &mut destination,
+                options,
+                ProgressBar::hidden(),
+            )?;
+            Ok(destination)
+        });
+        Writer {
+            sender: Some(sender),
+            chunk: Vec::with_capacity(1000),
+            thread: Some(thread),
+        }
+    }
+
+    /// Writes an element. Elements are passed to the writer in chunks, so an error
+    /// writing one may only be returned by a later call, or by [`Writer::finish`].
+    pub fn write_element(&mut self, element: Element) -> Result<(), SkywayError> {
+        self.chunk.push(element);
+        if self.chunk.len() >= 1000 {
+            let full_chunk = mem::replace(&mut self.chunk, Vec::with_capacity(1000));
+            self.send(full_chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the rest of the elements and finishes the output, returning the destination.
+    pub fn finish(mut self) -> Result<D, SkywayError> {
+        let last_chunk = mem::take(&mut self.chunk);
+        if !last_chunk.is_empty() {
+            self.send(last_chunk)?;
+        }
+        self.join()
+    }
+
+    // the writer only stops taking chunks if it failed,
+    // so its error is returned instead of a ChannelError
+    fn send(&mut self, chunk: Vec<Element>) -> Result<(), SkywayError> {
+        let sender = self.sender.as_ref().expect("Writer was already finished");
+        if sender.send(chunk).is_err() {
+            self.join()?;
+            return Err(SkywayError::ChannelError);
+        }
+        Ok(())
+    }
+
+    fn join(&mut self) -> Result<D, SkywayError> {
+        self.sender = None;
+        match self.thread.take() {
+            Some(thread) => thread.join().expect("Couldn't join on write thread!!"),
+            None => Err(SkywayError::ChannelError),
+        }
+    }
+}
+
+impl<D> Drop for Writer<D> {
+    // a writer that wasn't finished still writes what it was given,
+    // but any error is lost
+    fn drop(&mut self) {
+        if !self.chunk.is_empty() {
+            if let Some(sender) = &self.sender {
+                let _ = sender.send(mem::take(&mut self.chunk));
+            }
+        }
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_open_and_write() {
+        let mut file = tempfile::Builder::new().suffix(".opl").tempfile().unwrap();
+        let input = "n1 v1 dV c0 t i0 u Tname=A x-77.4 y37.5\nn2 v1 dV c0 t i0 u T x-77.5 y37.6\nw3 v1 dV c0 t i0 u T Nn1,n2\n";
+        file.write_all(input.as_bytes()).unwrap();
+
+        let (metadata, elements) = open(file.path(), InputFileFormat::Opl).unwrap();
+        let mut writer = Writer::new(
+            Vec::new(),
+            OutputFileFormat::Opl,
+            metadata,
+            WriteOptions::default(),
+        );
+        for element in elements {
+            writer.write_element(element.unwrap()).unwrap();
+        }
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.starts_with("n1 v1 "));
+        assert!(output.lines().nth(2).unwrap().ends_with("Nn1,n2"));
+
+        // errors come after the elements read before them
+        file.write_all(b"n4 v1 xA y2\n").unwrap();
+        let (_, elements) = open(file.path(), InputFileFormat::Opl).unwrap();
+        let results: Vec<_> = elements.collect();
+        assert!(results.last().unwrap().is_err());
+
+        assert!(open("missing.opl", InputFileFormat::Opl).is_err());
+    }
+}
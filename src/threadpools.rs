@@ -1,16 +1,15 @@
-use once_cell::sync::Lazy;
-use rayon::ThreadPool;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
-pub static READER_THREAD_POOL: Lazy<ThreadPool> = Lazy::new(|| {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(6)
-        .build()
-        .unwrap()
-});
+use crate::SkywayError;
+
+/// How many threads readers and writers that work in parallel use, unless told otherwise.
+pub(crate) const DEFAULT_THREADS: usize = 6;
 
-pub static WRITER_THREAD_POOL: Lazy<ThreadPool> = Lazy::new(|| {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(6)
+/// Starts a pool of `threads` threads for a reader or writer
+/// to work on chunks with (or one per CPU, if it's 0).
+pub(crate) fn thread_pool(threads: usize) -> Result<ThreadPool, SkywayError> {
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
         .build()
-        .unwrap()
-});
+        .map_err(|e| SkywayError::InvalidInput(format!("Unable to start {threads} threads: {e}")))
+}
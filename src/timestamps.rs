@@ -0,0 +1,89 @@
+//! Conversions between ISO 8601 timestamps and seconds since the Unix epoch.
+
+// number of days between 0000-03-01 and 1970-01-01 in the proleptic
+// Gregorian calendar (see Howard Hinnant's date algorithms)
+const EPOCH_SHIFT: i64 = 719468;
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - EPOCH_SHIFT
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + EPOCH_SHIFT;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Formats seconds since the Unix epoch as an ISO 8601 timestamp, e.g. `2024-09-25T14:03:00Z`.
+pub(crate) fn format_timestamp(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let seconds_of_day = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Parses an ISO 8601 timestamp in UTC (as written by OSM tools) into seconds since the
+/// Unix epoch. Fractional seconds are truncated.
+pub(crate) fn parse_timestamp(input: &str) -> Option<i64> {
+    let input = input.trim();
+    let (date, time) = input.split_once('T')?;
+    let time = time.strip_suffix('Z').unwrap_or(time);
+    let time = time.split_once('.').map_or(time, |(t, _)| t);
+
+    let mut date_parts = date.splitn(3, '-').map(|p| p.parse::<i64>());
+    let year = date_parts.next()?.ok()?;
+    let month = date_parts.next()?.ok()?;
+    let day = date_parts.next()?.ok()?;
+
+    let mut time_parts = time.splitn(3, ':').map(|p| p.parse::<i64>());
+    let hour = time_parts.next()?.ok()?;
+    let minute = time_parts.next()?.ok()?;
+    let second = time_parts.next().unwrap_or(Ok(0)).ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_timestamp(1727272980), "2024-09-25T14:03:00Z");
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Some(951782400));
+        assert_eq!(parse_timestamp("2024-09-25T14:03:00Z"), Some(1727272980));
+        assert_eq!(parse_timestamp("2024-09-25T14:03:00.5Z"), Some(1727272980));
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+}
@@ -0,0 +1,237 @@
+use lexical;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+
+use crate::channel::encode_in_parallel;
+use crate::elements::{Element, ElementType, Metadata};
+use crate::SkywayError;
+
+use super::WriteOptions;
+
+/// A column of CSV or TSV output: one of an element's attributes, or the value of a tag.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CsvColumn {
+    Id,
+    Type,
+    Version,
+    User,
+    Timestamp,
+    Lat,
+    Lon,
+    Tag(String),
+}
+
+/// The columns written when none are given.
+pub const DEFAULT_CSV_COLUMNS: &str = "@id,@type,@version,@user,@timestamp,@lat,@lon";
+
+impl FromStr for CsvColumn {
+    type Err = String;
+
+    /// Parses a column name: attributes start with `@` (e.g. `@id`), and anything else
+    /// is a tag key.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "@id" => Ok(CsvColumn::Id),
+            "@type" => Ok(CsvColumn::Type),
+            "@version" => Ok(CsvColumn::Version),
+            "@user" => Ok(CsvColumn::User),
+            "@timestamp" => Ok(CsvColumn::Timestamp),
+            "@lat" => Ok(CsvColumn::Lat),
+            "@lon" => Ok(CsvColumn::Lon),
+            "" => Err(String::from("Column name is empty")),
+            _ if s.starts_with('@') => Err(format!(
+                "Unknown column: {s} (expected @id, @type, @version, @user, @timestamp, @lat, @lon or a tag key)"
+            )),
+            _ => Ok(CsvColumn::Tag(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for CsvColumn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsvColumn::Id => f.write_str("@id"),
+            CsvColumn::Type => f.write_str("@type"),
+            CsvColumn::Version => f.write_str("@version"),
+            CsvColumn::User => f.write_str("@user"),
+            CsvColumn::Timestamp => f.write_str("@timestamp"),
+            CsvColumn::Lat => f.write_str("@lat"),
+            CsvColumn::Lon => f.write_str("@lon"),
+            CsvColumn::Tag(key) => f.write_str(key),
+        }
+    }
+}
+
+// fields are quoted (RFC 4180) if they contain a comma, quote or line break,
+// with quotes inside them doubled
+fn push_csv_field(base: &mut String, input: &str) {
+    if !input.contains([',', '"', '\n', '\r']) {
+        base.push_str(input);
+        return;
+    }
+    base.push('"');
+    for c in input.chars() {
+        if c == '"' {
+            base.push('"');
+        }
+        base.push(c);
+    }
+    base.push('"');
+}
+
+// TSV fields can't contain tabs or line breaks, so they're escaped with
+// backslashes, the way PostgreSQL and most other tools read them
+fn push_tsv_field(base: &mut String, input: &str) {
+    for c in input.chars() {
+        match c {
+            '\t' => base.push_str("\\t"),
+            '\n' => base.push_str("\\n"),
+            '\r' => base.push_str("\\r"),
+            '\\' => base.push_str("\\\\"),
+            _ => base.push(c),
+        }
+    }
+}
+
+fn push_row<'a>(base: &mut String, fields: impl Iterator<Item = Option<&'a str>>, tsv: bool) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            base.push(if tsv { '\t' } else { ',' });
+        }
+        // missing values are left empty
+        if let Some(field) = field {
+            if tsv {
+                push_tsv_field(base, field);
+            } else {
+                push_csv_field(base, field);
+            }
+        }
+    }
+    base.push('\n');
+}
+
+fn serialize_field(element: &Element, column: &CsvColumn) -> Option<String> {
+    match column {
+        CsvColumn::Id => Some(lexical::to_string(element.id)),
+        CsvColumn::Type => Some(String::from(match element.element_type {
+            ElementType::Node { .. } => "node",
+            ElementType::Way { .. } => "way",
+            ElementType::Relation { .. } => "relation",
+        })),
+        CsvColumn::Version => element.version.map(lexical::to_string),
+        CsvColumn::User => element.user.clone(),
+        CsvColumn::Timestamp => element.timestamp.clone(),
+        CsvColumn::Lat => match element.element_type {
+            ElementType::Node { lat, .. } => Some(lexical::to_string(lat)),
+            _ => None,
+        },
+        CsvColumn::Lon => match element.element_type {
+            ElementType::Node { lon, .. } => Some(lexical::to_string(lon)),
+            _ => None,
+        },
+        CsvColumn::Tag(key) => element.tags.get(key).cloned(),
+    }
+}
+
+fn serialize_chunk(chunk: Vec<Element>, columns: &[CsvColumn], tsv: bool) -> String {
+    let mut output = String::new();
+    for element in chunk {
+        let fields: Vec<Option<String>> = columns
+            .iter()
+            .map(|c| serialize_field(&element, c))
+            .collect();
+        push_row(&mut output, fields.iter().map(|f| f.as_deref()), tsv);
+    }
+    output
+}
+
+/// Writes one row per element, with a header row of column names. With `tsv`, fields
+/// are separated by tabs instead of commas.
+pub fn write_csv<D: std::io::Write>(
+    receiver: Receiver<Vec<Element>>,
+    _metadata: Metadata,
+    mut dest: D,
+    tsv: bool,
+    options: &WriteOptions,
+) -> Result<(), SkywayError> {
+    let columns = &options.columns;
+    let mut header = String::new();
+    let names: Vec<String> = columns.iter().map(CsvColumn::to_string).collect();
+    push_row(&mut header, names.iter().map(|n| Some(n.as_str())), tsv);
+    dest.write_all(header.as_bytes())?;
+
+    encode_in_parallel(
+        receiver.into_iter(),
+        options.threads,
+        options.buffer_chunks,
+        options.preserve_order,
+        |chunk| serialize_chunk(chunk, columns, tsv),
+        |output_string| Ok(dest.write_all(output_string.as_bytes())?),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_write_csv() {
+        let element = |id, element_type, user: &str, tags: &[(&str, &str)]| Element {
+            user: Some(user.to_owned()),
+            version: Some(2),
+            ..fixtures::element(id, element_type, tags)
+        };
+        let columns: Vec<CsvColumn> = "@type,@id,@user,@lat,@lon,name,@timestamp"
+            .split(',')
+            .map(|c| c.parse().unwrap())
+            .collect();
+        let write = |tsv| {
+            let (sender, receiver) = channel();
+            sender
+                .send(vec![
+                    element(
+                        1,
+                        ElementType::Node {
+                            lat: 37.5,
+                            lon: -77.25,
+                        },
+                        "jo\tsmith",
+                        &[("name", "Cafe \"Java\", Richmond")],
+                    ),
+                    element(
+                        10,
+                        ElementType::Way { nodes: vec![1] },
+                        "a\\b",
+                        &[("name", "Line\nbreak")],
+                    ),
+                ])
+                .unwrap();
+            drop(sender);
+            let mut output = Vec::new();
+            let options = WriteOptions {
+                threads: 2,
+                columns: columns.clone(),
+                ..Default::default()
+            };
+            write_csv(receiver, Metadata::default(), &mut output, tsv, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            write(false),
+            "@type,@id,@user,@lat,@lon,name,@timestamp\n\
+             node,1,jo\tsmith,37.5,-77.25,\"Cafe \"\"Java\"\", Richmond\",\n\
+             way,10,a\\b,,,\"Line\nbreak\",\n"
+        );
+        assert_eq!(
+            write(true),
+            "@type\t@id\t@user\t@lat\t@lon\tname\t@timestamp\n\
+             node\t1\tjo\\tsmith\t37.5\t-77.25\tCafe \"Java\", Richmond\t\n\
+             way\t10\ta\\\\b\t\t\tLine\\nbreak\t\n"
+        );
+        assert!("@foo".parse::<CsvColumn>().is_err());
+    }
+}
@@ -0,0 +1,414 @@
+use json::stringify;
+use lexical;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufWriter, Write};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, RwLock};
+
+use crate::areas::{assemble_areas, is_area, ring_role, Area};
+use crate::elements::{Element, ElementType, Metadata};
+use crate::locations::{LocationIndex, SharedLocationIndex, SparseLocationIndex};
+use crate::spool::Spool;
+use crate::SkywayError;
+
+// positions are (lon, lat), in the order GeoJSON uses
+fn append_position(base: &mut String, (lon, lat): (f64, f64)) {
+    base.push('[');
+    base.push_str(&lexical::to_string(lon));
+    base.push(',');
+    base.push_str(&lexical::to_string(lat));
+    base.push(']');
+}
+
+fn append_positions(base: &mut String, positions: &[(f64, f64)]) {
+    base.push('[');
+    for (i, position) in positions.iter().enumerate() {
+        if i > 0 {
+            base.push(',');
+        }
+        append_position(base, *position);
+    }
+    base.push(']');
+}
+
+fn append_area(base: &mut String, area: &Area) {
+    base.push('[');
+    append_positions(base, &area.outer);
+    for inner in &area.inners {
+        base.push(',');
+        append_positions(base, inner);
+    }
+    base.push(']');
+}
+
+// build the geometry for an element, or None if it doesn't have one
+fn serialize_geometry(
+    element: &Element,
+    locations: &dyn LocationIndex,
+    way_nodes: &HashMap<i64, Vec<i64>>,
+) -> Option<String> {
+    let mut geometry = String::from("{\"type\":");
+    match &element.element_type {
+        ElementType::Node { lat, lon } => {
+            geometry.push_str("\"Point\",\"coordinates\":");
+            append_position(&mut geometry, (*lon, *lat));
+        }
+        ElementType::Way { nodes } => {
+            // leaving out nodes that can't be found would change the way's shape
+            // (or open up an area), so the way is skipped instead
+            let positions: Vec<(f64, f64)> = nodes
+                .iter()
+                .map(|n| locations.get(*n).map(|(lat, lon)| (lon, lat)))
+                .collect::<Option<_>>()?;
+            if positions.len() < 2 {
+                return None;
+            }
+            if is_area(element) {
+                geometry.push_str("\"Polygon\",\"coordinates\":[");
+                append_positions(&mut geometry, &positions);
+                geometry.push(']');
+            } else {
+                geometry.push_str("\"LineString\",\"coordinates\":");
+                append_positions(&mut geometry, &positions);
+            }
+        }
+        ElementType::Relation { members } => {
+            if !is_area(element) {
+                return None;
+            }
+            let areas = assemble_areas(members, way_nodes, locations)?;
+            if let [area] = &areas[..] {
+                geometry.push_str("\"Polygon\",\"coordinates\":");
+                append_area(&mut geometry, area);
+            } else {
+                geometry.push_str("\"MultiPolygon\",\"coordinates\":[");
+                for (i, area) in areas.iter().enumerate() {
+                    if i > 0 {
+                        geometry.push(',');
+                    }
+                    append_area(&mut geometry, area);
+                }
+                geometry.push(']');
+            }
+        }
+    }
+    geometry.push('}');
+    Some(geometry)
+}
+
+fn serialize_feature(element: &Element, geometry: String) -> String {
+    let mut feature = String::from("{\"type\":\"Feature\",\"id\":");
+    let id = match element.element_type {
+        ElementType::Node { .. } => format!("node/{}", element.id),
+        ElementType::Way { .. } => format!("way/{}", element.id),
+        ElementType::Relation { .. } => format!("relation/{}", element.id),
+    };
+    feature.push_str(&stringify(id));
+    feature.push_str(",\"properties\":{");
+    for (i, (k, v)) in element.tags.iter().enumerate() {
+        if i > 0 {
+            feature.push(',');
+        }
+        feature.push_str(&stringify(k.as_str()));
+        feature.push(':');
+        feature.push_str(&stringify(v.as_str()));
+    }
+    feature.push_str("},\"geometry\":");
+    feature.push_str(&geometry);
+    feature.push('}');
+    feature
+}
+
+// write a feature, either as a line of a text sequence or as part of a FeatureCollection
+fn write_feature<W: Write>(
+    writer: &mut W,
+    feature: &str,
+    seq: bool,
+    first_feature_written: &mut bool,
+) -> io::Result<()> {
+    if seq {
+        writer.write_all(b"\x1e")?;
+        writer.write_all(feature.as_bytes())?;
+        writer.write_all(b"\n")?;
+    } else {
+        if *first_feature_written {
+            writer.write_all(b",\n")?;
+        }
+        writer.write_all(feature.as_bytes())?;
+    }
+    *first_feature_written = true;
+    Ok(())
+}
+
+/// Writes tagged nodes and ways, and multipolygon and boundary relations, as GeoJSON
+/// features, either as one FeatureCollection or, with `seq`, as a GeoJSON text sequence
+/// (RFC 8142) with one feature per line. Way geometries are built from the nodes that
+/// have already been received, so nodes must come before the ways that use them.
+///
+/// Every way's nodes, and the area relations, are held in temporary files. Once the
+/// input ends, the nodes of the ways that make up areas are read back, and the
+/// relations are written last.
+///
+/// Node locations are looked up in `locations` if it is given (and filled in by an earlier
+/// stage), or otherwise stored in a sparse index as nodes are received.
+pub fn write_geojson<D: Write>(
+    receiver: Receiver<Vec<Element>>,
+    _metadata: Metadata,
+    dest: D,
+    seq: bool,
+    locations: Option<SharedLocationIndex>,
+) -> Result<(), SkywayError> {
+    let mut writer = BufWriter::new(dest);
+    let index_nodes = locations.is_none();
+    let locations = locations.unwrap_or_else(|| {
+        Arc::new(RwLock::new(
+            Box::new(SparseLocationIndex::new()) as Box<dyn LocationIndex>
+        ))
+    });
+    let no_way_nodes = HashMap::new();
+    let mut ways = Spool::new()?;
+    let mut areas = Spool::new()?;
+    let mut area_ways = HashSet::new();
+    let mut first_feature_written = false;
+    let mut incomplete_ways = 0;
+    let mut incomplete_areas = 0;
+
+    if !seq {
+        writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+    }
+
+    for chunk in receiver {
+        if index_nodes {
+            let mut index = locations.write().expect("Location index lock is poisoned");
+            for element in &chunk {
+                if let ElementType::Node { lat, lon } = element.element_type {
+                    index.insert(element.id, lat, lon)?;
+                }
+            }
+        }
+        let index = locations.read().expect("Location index lock is poisoned");
+
+        for mut element in chunk {
+            // relations are assembled once every way they may need has been seen
+            if let ElementType::Relation { members } = &element.element_type {
+                if is_area(&element) {
+                    area_ways.extend(
+                        members
+                            .iter()
+                            .filter(|m| ring_role(m).is_some())
+                            .map(|m| m.id),
+                    );
+                    areas.push(&element)?;
+                }
+                continue;
+            }
+            // untagged elements are only there to make up the geometries of others
+            if !element.tags.is_empty() {
+                match serialize_geometry(&element, index.as_ref(), &no_way_nodes) {
+                    Some(geometry) => {
+                        let feature = serialize_feature(&element, geometry);
+                        write_feature(&mut writer, &feature, seq, &mut first_feature_written)?;
+                    }
+                    None if matches!(element.element_type, ElementType::Way { .. }) => {
+                        incomplete_ways += 1;
+                    }
+                    None => {}
+                }
+            }
+            // every way may turn out to be part of a multipolygon, and only its nodes are needed
+            if let ElementType::Way { .. } = element.element_type {
+                element.tags.clear();
+                ways.push(&element)?;
+            }
+        }
+    }
+
+    if !area_ways.is_empty() {
+        let mut way_nodes = HashMap::new();
+        for way in ways.iter()? {
+            let way = way?;
+            if let ElementType::Way { nodes } = way.element_type {
+                if area_ways.contains(&way.id) {
+                    way_nodes.insert(way.id, nodes);
+                }
+            }
+        }
+        let index = locations.read().expect("Location index lock is poisoned");
+        for element in areas.iter()? {
+            let element = element?;
+            match serialize_geometry(&element, index.as_ref(), &way_nodes) {
+                Some(geometry) => {
+                    let feature = serialize_feature(&element, geometry);
+                    write_feature(&mut writer, &feature, seq, &mut first_feature_written)?;
+                }
+                None => incomplete_areas += 1,
+            }
+        }
+    }
+
+    if !seq {
+        writer.write_all(b"\n]}\n")?;
+    }
+    writer.flush()?;
+
+    if incomplete_ways > 0 {
+        warn!(
+            "Skipped {} ways with nodes missing from the input, or with fewer than two nodes",
+            incomplete_ways
+        );
+    }
+    if incomplete_areas > 0 {
+        warn!(
+            "Skipped {} multipolygon or boundary relations that couldn't be assembled",
+            incomplete_areas
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elements::{Member, SimpleElementType};
+    use crate::fixtures::element;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_write_geojson_seq() {
+        let node = |id, lat, lon, tags| element(id, ElementType::Node { lat, lon }, tags);
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                node(1, 37.5, -77.5, &[("amenity", "cafe")]),
+                node(2, 37.5, -77.4, &[]),
+                node(3, 37.6, -77.4, &[]),
+                element(
+                    10,
+                    ElementType::Way { nodes: vec![1, 2] },
+                    &[("highway", "path")],
+                ),
+                element(
+                    11,
+                    ElementType::Way {
+                        nodes: vec![1, 2, 3, 1],
+                    },
+                    &[("building", "yes")],
+                ),
+                element(
+                    12,
+                    ElementType::Way { nodes: vec![1, 9] },
+                    &[("highway", "path")],
+                ),
+                // missing a node, so neither a polygon nor a shorter line
+                element(
+                    13,
+                    ElementType::Way {
+                        nodes: vec![1, 2, 9, 1],
+                    },
+                    &[("building", "yes")],
+                ),
+                element(
+                    14,
+                    ElementType::Way {
+                        nodes: vec![1, 2, 9],
+                    },
+                    &[("highway", "path")],
+                ),
+            ])
+            .unwrap();
+        drop(sender);
+
+        let mut output = Vec::new();
+        write_geojson(receiver, Metadata::default(), &mut output, true, None).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output.split('\n').collect::<Vec<_>>(),
+            vec![
+                "\x1e{\"type\":\"Feature\",\"id\":\"node/1\",\"properties\":{\"amenity\":\"cafe\"},\"geometry\":{\"type\":\"Point\",\"coordinates\":[-77.5,37.5]}}",
+                "\x1e{\"type\":\"Feature\",\"id\":\"way/10\",\"properties\":{\"highway\":\"path\"},\"geometry\":{\"type\":\"LineString\",\"coordinates\":[[-77.5,37.5],[-77.4,37.5]]}}",
+                "\x1e{\"type\":\"Feature\",\"id\":\"way/11\",\"properties\":{\"building\":\"yes\"},\"geometry\":{\"type\":\"Polygon\",\"coordinates\":[[[-77.5,37.5],[-77.4,37.5],[-77.4,37.6],[-77.5,37.5]]]}}",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_geojson_feature_collection() {
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![element(
+                1,
+                ElementType::Node {
+                    lat: 37.5,
+                    lon: -77.5,
+                },
+                &[("amenity", "cafe")],
+            )])
+            .unwrap();
+        drop(sender);
+
+        let mut output = Vec::new();
+        write_geojson(receiver, Metadata::default(), &mut output, false, None).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(value["type"], "FeatureCollection");
+        assert_eq!(value["features"][0]["geometry"]["coordinates"][1], 37.5);
+    }
+
+    #[test]
+    fn test_write_geojson_areas() {
+        let node = |id, lat, lon| element(id, ElementType::Node { lat, lon }, &[]);
+        let member = |t, id, role: &str| Member {
+            t: Some(t),
+            id,
+            role: Some(role.to_owned()),
+        };
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                node(1, 0.0, 0.0),
+                node(2, 0.0, 1.0),
+                node(3, 1.0, 1.0),
+                element(
+                    10,
+                    ElementType::Way {
+                        nodes: vec![1, 2, 3, 1],
+                    },
+                    &[],
+                ),
+                element(
+                    20,
+                    ElementType::Relation {
+                        members: vec![
+                            member(SimpleElementType::Way, 10, "outer"),
+                            member(SimpleElementType::Node, 3, "label"),
+                        ],
+                    },
+                    &[("type", "boundary")],
+                ),
+                // an inner way that isn't in the input
+                element(
+                    21,
+                    ElementType::Relation {
+                        members: vec![
+                            member(SimpleElementType::Way, 10, "outer"),
+                            member(SimpleElementType::Way, 11, "inner"),
+                        ],
+                    },
+                    &[("type", "multipolygon")],
+                ),
+            ])
+            .unwrap();
+        drop(sender);
+
+        let mut output = Vec::new();
+        write_geojson(receiver, Metadata::default(), &mut output, false, None).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(value["features"].as_array().unwrap().len(), 1);
+        assert_eq!(value["features"][0]["id"], "relation/20");
+        assert_eq!(
+            value["features"][0]["geometry"]["coordinates"],
+            serde_json::json!([[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 0.0]]])
+        );
+    }
+}
@@ -1,24 +1,12 @@
 use json::stringify;
 use lexical;
-use rayon::prelude::*;
-use std::fmt::{Error, Write};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::Receiver;
 
+use crate::channel::encode_in_parallel;
 use crate::elements::{Element, ElementType, Metadata, SimpleElementType};
-use crate::threadpools::WRITER_THREAD_POOL;
-
-// wrapper struct that implements std::fmt::Write for any type
-// that implements std::io::Write
-struct ToFmtWrite<T>(pub T);
-
-impl<T> Write for ToFmtWrite<T>
-where
-    T: std::io::Write,
-{
-    fn write_str(&mut self, s: &str) -> std::fmt::Result {
-        self.0.write_all(s.as_bytes()).map_err(|_| std::fmt::Error)
-    }
-}
+use crate::SkywayError;
+
+use super::WriteOptions;
 
 fn create_header(metadata: Metadata, overpass: bool) -> String {
     let mut header = String::from("{");
@@ -187,7 +175,7 @@ fn append_serialized_element(base: &mut String, element: Element) {
     base.push('}');
 }
 
-fn serialize_chunk(chunk: Vec<Element>) -> Result<String, Error> {
+fn serialize_chunk(chunk: Vec<Element>) -> Result<String, SkywayError> {
     let mut output = String::new();
     let mut first_element_appended = false;
     for element in chunk {
@@ -205,35 +193,22 @@ pub fn write_json<D: std::io::Write>(
     metadata: Metadata,
     dest: D,
     overpass: bool,
-) {
-    let mut writer = ToFmtWrite(dest);
-
-    let (output_sender, output_reciever) = channel();
-    WRITER_THREAD_POOL.install(move || {
-        receiver
-            .into_iter()
-            .par_bridge()
-            .map(serialize_chunk)
-            .map(|result| result.expect("Failed to serialize chunk"))
-            .for_each(|s| match output_sender.clone().send(s) {
-                Ok(_) => (),
-                Err(e) => panic!("Error passing output chunk between threads: {e:?}"),
-            });
-    });
+    options: &WriteOptions,
+) -> Result<(), SkywayError> {
+    let mut writer = dest;
 
     let header = create_header(metadata, overpass);
-
-    writer
-        .write_str(&header)
-        .expect("Couldn't write opening metadata to output.");
-
-    for output_string in output_reciever {
-        writer
-            .write_str(&output_string)
-            .expect("Failed to write to output");
-    }
-
-    writer
-        .write_str("]}")
-        .expect("Couldn't write final closing curly brace to output.");
+    writer.write_all(header.as_bytes())?;
+
+    encode_in_parallel(
+        receiver.into_iter(),
+        options.threads,
+        options.buffer_chunks,
+        options.preserve_order,
+        serialize_chunk,
+        |output_string| Ok(writer.write_all(output_string?.as_bytes())?),
+    )?;
+
+    writer.write_all(b"]}")?;
+    Ok(())
 }
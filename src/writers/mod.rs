@@ -5,9 +5,20 @@ use std::io::Write;
 use std::str::FromStr;
 use std::sync::mpsc::Receiver;
 
-use crate::elements::{Element, Metadata};
+use crate::elements::{Element, Member, Metadata, SimpleElementType};
+use crate::locations::SharedLocationIndex;
+use crate::pipeline::DEFAULT_BUFFER_CHUNKS;
+use crate::progress::start_spinner;
+use crate::threadpools::DEFAULT_THREADS;
 use crate::SkywayError;
 
+mod csv;
+use csv::write_csv;
+pub use csv::{CsvColumn, DEFAULT_CSV_COLUMNS};
+
+mod geojson;
+use geojson::write_geojson;
+
 mod json;
 use json::write_json;
 
@@ -17,16 +28,28 @@ use o5m::write_o5m;
 mod opl;
 use opl::write_opl;
 
+mod osc;
+use osc::write_osc;
+
+mod pbf;
+use pbf::write_pbf;
+
 mod xml;
 use xml::write_xml;
 
 /// Enum that represents the different output file formats skyway supports.
 #[derive(Debug)]
 pub enum OutputFileFormat {
+    Csv,
+    GeoJson,
+    GeoJsonSeq,
     Json,
-    // O5m,
+    O5m,
     Opl,
+    Osc,
     Overpass,
+    Pbf,
+    Tsv,
     Xml,
 }
 
@@ -36,48 +59,111 @@ impl FromStr for OutputFileFormat {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             // TODO: recognize JSON, but warn user that it may be confused for Overpass JSON
+            "csv" => Ok(OutputFileFormat::Csv),
+            "geojson" => Ok(OutputFileFormat::GeoJson),
+            "geojsonseq" => Ok(OutputFileFormat::GeoJsonSeq),
             "json" => Ok(OutputFileFormat::Json),
-            // "o5m" => Ok(OutputFileFormat::O5m),
+            "o5m" => Ok(OutputFileFormat::O5m),
             "opl" => Ok(OutputFileFormat::Opl),
+            "osc" => Ok(OutputFileFormat::Osc),
             "osm" => Ok(OutputFileFormat::Xml),
             "overpass" => Ok(OutputFileFormat::Overpass),
+            "pbf" => Ok(OutputFileFormat::Pbf),
+            "tsv" => Ok(OutputFileFormat::Tsv),
             "xml" => Ok(OutputFileFormat::Xml),
             _ => Err(SkywayError::UnknownOutputFormat),
         }
     }
 }
 
+impl OutputFileFormat {
+    /// Whether the format builds geometries, and so needs to look up node locations.
+    pub fn needs_locations(&self) -> bool {
+        matches!(
+            self,
+            OutputFileFormat::GeoJson | OutputFileFormat::GeoJsonSeq
+        )
+    }
+}
+
+/// Settings that only some writers use.
+pub struct WriteOptions {
+    /// Whether writers that work in parallel should keep elements in input order.
+    pub preserve_order: bool,
+    /// How many threads writers that work in parallel use (or one per CPU, if it's 0).
+    pub threads: usize,
+    /// How many chunks writers that work in parallel hold on to after serializing
+    /// them, while they wait to be written.
+    pub buffer_chunks: usize,
+    /// The columns to write, for CSV and TSV.
+    pub columns: Vec<CsvColumn>,
+    /// A location index filled in by an earlier stage, for writers that build geometries.
+    /// If it isn't given, those writers index the nodes they receive themselves.
+    pub locations: Option<SharedLocationIndex>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            preserve_order: true,
+            threads: DEFAULT_THREADS,
+            buffer_chunks: DEFAULT_BUFFER_CHUNKS,
+            columns: DEFAULT_CSV_COLUMNS
+                .split(',')
+                .map(|c| c.parse().expect("Default CSV column is invalid"))
+                .collect(),
+            locations: None,
+        }
+    }
+}
+
+// members can be read without a type (which OSM XML allows), but
+// most formats can't write them without one
+fn member_type(relation_id: i64, member: &Member) -> Result<&SimpleElementType, SkywayError> {
+    member.t.as_ref().ok_or_else(|| {
+        SkywayError::InvalidInput(format!(
+            "Member {} of relation {} has no type",
+            member.id, relation_id
+        ))
+    })
+}
+
 /// Writes data out.
 ///
 /// * `receiver`: Receiver for a channel of `Element`s.
 /// * `metadata_sender`: Document-level metadata.
 /// * `to`: File format to write.
 /// * `destination`: Output data destination.
+/// * `options`: Settings for the writers that use them.
 /// * `progress`: The ProgressBar for this write operation.
 pub fn write_file<D: Write>(
     receiver: Receiver<Vec<Element>>,
     metadata: Metadata,
     to: OutputFileFormat,
     destination: D,
+    options: WriteOptions,
     progress: ProgressBar,
-) {
-    progress.set_message("Writing output...");
-    let progress_clone = progress.clone();
-    std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        progress_clone.tick();
-        if progress_clone.is_finished() {
-            break;
-        }
-    });
+) -> Result<(), SkywayError> {
+    start_spinner(&progress, "Writing output...");
 
-    match to {
-        OutputFileFormat::Json => write_json(receiver, metadata, destination, false),
-        // OutputFileFormat::O5m => write_o5m(reciever, metadata, destination),
-        OutputFileFormat::Opl => write_opl(receiver, metadata, destination),
-        OutputFileFormat::Overpass => write_json(receiver, metadata, destination, true),
+    let result = match to {
+        OutputFileFormat::Csv => write_csv(receiver, metadata, destination, false, &options),
+        OutputFileFormat::GeoJson => {
+            write_geojson(receiver, metadata, destination, false, options.locations)
+        }
+        OutputFileFormat::GeoJsonSeq => {
+            write_geojson(receiver, metadata, destination, true, options.locations)
+        }
+        OutputFileFormat::Json => write_json(receiver, metadata, destination, false, &options),
+        OutputFileFormat::O5m => write_o5m(receiver, metadata, destination),
+        OutputFileFormat::Opl => write_opl(receiver, metadata, destination, &options),
+        OutputFileFormat::Osc => write_osc(receiver, metadata, destination),
+        OutputFileFormat::Overpass => write_json(receiver, metadata, destination, true, &options),
+        OutputFileFormat::Pbf => write_pbf(receiver, metadata, destination, &options),
+        OutputFileFormat::Tsv => write_csv(receiver, metadata, destination, true, &options),
         OutputFileFormat::Xml => write_xml(receiver, metadata, destination),
-    }
+    };
 
     progress.finish_with_message("Writing output...done");
+    result
 }
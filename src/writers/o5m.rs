@@ -1,8 +1,29 @@
 use bit_vec::BitVec;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::sync::mpsc::Receiver;
 
-use crate::elements::{Element, ElementType, Metadata};
+use crate::elements::{Bounds, Element, ElementType, Metadata, SimpleElementType};
+use crate::timestamps::parse_timestamp;
+use crate::SkywayError;
+
+use super::member_type;
+
+// o5m dataset type bytes
+const NODE_DATASET: u8 = 0x10;
+const WAY_DATASET: u8 = 0x11;
+const RELATION_DATASET: u8 = 0x12;
+const BOUNDING_BOX_DATASET: u8 = 0xdb;
+const FILE_TIMESTAMP_DATASET: u8 = 0xdc;
+const HEADER_DATASET: u8 = 0xe0;
+const END_OF_FILE: u8 = 0xfe;
+const RESET: u8 = 0xff;
+
+// strings (or string pairs) with more characters than this are
+// never added to the string table
+const MAX_CACHED_STRING_LENGTH: usize = 250;
+
+// o5m string tables hold at most this many entries
+const STRING_TABLE_SIZE: usize = 15000;
 
 // convert a single string (surround with zero-bytes)
 fn convert_string(input: &str) -> Vec<u8> {
@@ -24,8 +45,10 @@ fn convert_tag(key: &str, value: &str) -> Vec<u8> {
     output
 }
 
-fn convert_f64(input: f64) -> Vec<u8> {
-    unimplemented!()
+// convert a coordinate (in degrees) to the fixed-point
+// representation used by o5m, in units of 100 nanodegrees
+fn convert_f64(input: f64) -> i64 {
+    (input * 1e7).round() as i64
 }
 
 fn convert_number(bytes: &[u8]) -> Vec<u8> {
@@ -60,6 +83,11 @@ fn convert_number(bytes: &[u8]) -> Vec<u8> {
                 .collect(),
         );
     }
+
+    // zero is still written out as a single byte
+    if output.is_empty() {
+        output.push(0x00);
+    }
     output
 }
 
@@ -69,6 +97,12 @@ fn convert_i64_as_unsigned(input: i64) -> Vec<u8> {
     convert_number(&input.to_be_bytes())
 }
 
+// convert a signed 64-bit integer (i64) into the signed bit-packed
+// specification for o5m, where the least significant bit is the sign
+fn convert_i64_as_signed(input: i64) -> Vec<u8> {
+    convert_number(&((input << 1) ^ (input >> 63)).to_be_bytes())
+}
+
 // convert a user id (i32) and username (String) into the
 // bit-packed specification for o5m, returned as a Vec of bytes (u8)
 fn convert_user(uid: i32, username: String) -> Vec<u8> {
@@ -108,8 +142,12 @@ impl StringTable {
             // increment by 1 to accomodate zero-indexed vector
             Some(v) => convert_index(v + 1),
             None => {
-                self.cached_tags.insert(0, str_pair.clone());
-                self.cached_tags.truncate(15000);
+                // long strings are written out every time
+                let length = str_pair.0.iter().filter(|b| **b != 0x00).count();
+                if length <= MAX_CACHED_STRING_LENGTH {
+                    self.cached_tags.insert(0, str_pair.clone());
+                    self.cached_tags.truncate(STRING_TABLE_SIZE);
+                }
                 str_pair.0
             }
         }
@@ -121,34 +159,173 @@ impl StringTable {
     }
 }
 
-fn convert_element(element: Element) -> Vec<u8> {
-    let mut output: Vec<u8> = Vec::new();
+// running values that o5m datasets are delta-coded against,
+// cleared along with the string table by each reset
+struct O5mEncoder {
+    string_table: StringTable,
+    id: i64,
+    timestamp: i64,
+    changeset: i64,
+    lon: i64,
+    lat: i64,
+    // node, way and relation references are delta-coded separately
+    references: [i64; 3],
+}
 
-    // special code for element type
-    match element.element_type {
-        ElementType::Node { .. } => output.push(0x10),
-        ElementType::Way { .. } => output.push(0x11),
-        ElementType::Relation { .. } => output.push(0x12),
+impl O5mEncoder {
+    fn new() -> Self {
+        O5mEncoder {
+            string_table: StringTable::new(),
+            id: 0,
+            timestamp: 0,
+            changeset: 0,
+            lon: 0,
+            lat: 0,
+            references: [0; 3],
+        }
     }
 
-    // write element id to output
-    output.extend(convert_i64_as_unsigned(element.id));
+    fn reset(&mut self) {
+        *self = O5mEncoder::new();
+    }
 
-    // TODO: output version info instead of 0x00, if it is available
-    output.push(0x00);
+    fn delta(previous: &mut i64, current: i64) -> Vec<u8> {
+        let output = convert_i64_as_signed(current - *previous);
+        *previous = current;
+        output
+    }
 
-    // TODO: if NODE, lon then lat
-    // TODO: if WAY, length of references (unsigned), then node references
-    // TODO: if RELATION, length of references (unsigned), then member references
+    fn convert_reference(&mut self, t: &SimpleElementType, id: i64) -> Vec<u8> {
+        let index = match t {
+            SimpleElementType::Node => 0,
+            SimpleElementType::Way => 1,
+            SimpleElementType::Relation => 2,
+        };
+        O5mEncoder::delta(&mut self.references[index], id)
+    }
 
-    // TODO: push tags to output
-    // for tag in element.tags {
-    //     output.extend(convert_tag(&tag.0, &tag.1));
-    // }
+    // version, timestamp, changeset and author; a lone zero
+    // byte means none of this information is available
+    fn convert_version(&mut self, element: &mut Element) -> Vec<u8> {
+        let mut output = Vec::new();
+        let version = match element.version {
+            Some(v) if v > 0 => v,
+            _ => {
+                output.push(0x00);
+                return output;
+            }
+        };
+        output.extend(convert_i64_as_unsigned(version.into()));
+
+        let timestamp = element
+            .timestamp
+            .as_deref()
+            .and_then(parse_timestamp)
+            .unwrap_or(0);
+        output.extend(O5mEncoder::delta(&mut self.timestamp, timestamp));
+
+        // changeset and author are only written if there is a timestamp
+        if timestamp != 0 {
+            output.extend(O5mEncoder::delta(
+                &mut self.changeset,
+                element.changeset.unwrap_or(0),
+            ));
+            output.extend(self.string_table.hit_cache(convert_user(
+                element.uid.unwrap_or(0),
+                element.user.take().unwrap_or_default(),
+            )));
+        }
+        output
+    }
+
+    fn convert_element(&mut self, mut element: Element) -> Result<Vec<u8>, SkywayError> {
+        let mut body: Vec<u8> = Vec::new();
+
+        // write element id and version info to body
+        body.extend(O5mEncoder::delta(&mut self.id, element.id));
+        body.extend(self.convert_version(&mut element));
+
+        // deleted elements (as in o5c change files) end after the version info
+        if element.visible != Some(false) {
+            match element.element_type {
+                ElementType::Node { lat, lon } => {
+                    body.extend(O5mEncoder::delta(&mut self.lon, convert_f64(lon)));
+                    body.extend(O5mEncoder::delta(&mut self.lat, convert_f64(lat)));
+                }
+                ElementType::Way { ref nodes } => {
+                    let mut references = Vec::new();
+                    for node in nodes {
+                        references.extend(self.convert_reference(&SimpleElementType::Node, *node));
+                    }
+                    body.extend(convert_index(references.len()));
+                    body.extend(references);
+                }
+                ElementType::Relation { ref members } => {
+                    let mut references = Vec::new();
+                    for member in members {
+                        let t = member_type(element.id, member)?;
+                        references.extend(self.convert_reference(t, member.id));
+
+                        // member type and role share a single string
+                        let mut role = String::from(match t {
+                            SimpleElementType::Node => "0",
+                            SimpleElementType::Way => "1",
+                            SimpleElementType::Relation => "2",
+                        });
+                        role.push_str(member.role.as_deref().unwrap_or_default());
+                        references.extend(self.string_table.hit_cache(convert_string(&role)));
+                    }
+                    body.extend(convert_index(references.len()));
+                    body.extend(references);
+                }
+            }
+
+            // tags are sorted so that the same input always gives the same bytes
+            let mut tags: Vec<_> = element.tags.iter().collect();
+            tags.sort_unstable();
+            for (key, value) in tags {
+                body.extend(self.string_table.hit_cache(convert_tag(key, value)));
+            }
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+
+        // special code for element type
+        match element.element_type {
+            ElementType::Node { .. } => output.push(NODE_DATASET),
+            ElementType::Way { .. } => output.push(WAY_DATASET),
+            ElementType::Relation { .. } => output.push(RELATION_DATASET),
+        }
+
+        // the length of each dataset precedes its contents
+        output.extend(convert_index(body.len()));
+        output.extend(body);
+        Ok(output)
+    }
+}
 
+// the contents of a dataset, preceded by its type and length
+fn convert_dataset(dataset_type: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut output = vec![dataset_type];
+    output.extend(convert_index(body.len()));
+    output.extend(body);
     output
 }
 
+// a bounding box is its corners, as signed numbers that aren't delta-coded
+fn convert_bbox(bounds: &Bounds) -> Vec<u8> {
+    let mut body = Vec::new();
+    for corner in [
+        bounds.min_lon,
+        bounds.min_lat,
+        bounds.max_lon,
+        bounds.max_lat,
+    ] {
+        body.extend(convert_i64_as_signed(convert_f64(corner)));
+    }
+    convert_dataset(BOUNDING_BOX_DATASET, body)
+}
+
 // struct to hold elements that need to be held
 // before writing because the output format
 // requires that they are sorted
@@ -159,9 +336,9 @@ struct WaitingElements {
 }
 
 impl WaitingElements {
-    fn from(receiver: Receiver<Element>) -> Self {
+    fn from(receiver: Receiver<Vec<Element>>) -> Self {
         let mut new_instance = WaitingElements::new();
-        for element in receiver {
+        for element in receiver.into_iter().flatten() {
             match element.element_type {
                 ElementType::Node { .. } => new_instance.nodes.push(element),
                 ElementType::Way { .. } => new_instance.ways.push(element),
@@ -172,56 +349,76 @@ impl WaitingElements {
     }
 
     fn new() -> Self {
-        return WaitingElements {
+        WaitingElements {
             nodes: Vec::new(),
             ways: Vec::new(),
             relations: Vec::new(),
-        };
+        }
     }
 
     // sort each of the Vecs by element ID, ascending order
     fn sort(&mut self) {
-        self.nodes.sort_by(|a, b| a.id.cmp(&b.id));
-        self.ways.sort_by(|a, b| a.id.cmp(&b.id));
-        self.relations.sort_by(|a, b| a.id.cmp(&b.id));
+        self.nodes.sort_by_key(|e| e.id);
+        self.ways.sort_by_key(|e| e.id);
+        self.relations.sort_by_key(|e| e.id);
     }
-}
 
-impl Iterator for WaitingElements {
-    type Item = Element;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.nodes.is_empty() {
-            self.nodes.pop()
-        } else if !self.ways.is_empty() {
-            self.ways.pop()
-        } else if !self.relations.is_empty() {
-            self.relations.pop()
-        } else {
-            None
-        }
+    // split the elements back out, one Vec per element type
+    fn into_sections(self) -> [Vec<Element>; 3] {
+        [self.nodes, self.ways, self.relations]
     }
 }
 
-pub fn write_o5m<D: Write>(receiver: Receiver<Element>, metadata: Metadata, mut dest: D) {
+pub fn write_o5m<D: Write>(
+    receiver: Receiver<Vec<Element>>,
+    metadata: Metadata,
+    dest: D,
+) -> Result<(), SkywayError> {
     let mut waiting_elements = WaitingElements::from(receiver);
 
     // sort our container of waiting elements
     waiting_elements.sort();
 
-    // TODO: write timestamp to dest
-    // TODO: write bounding box to dest?
-    // TODO: write header to dest?
+    let mut writer = BufWriter::new(dest);
+    let mut encoder = O5mEncoder::new();
+
+    // every o5m file starts with a reset, followed by the header
+    writer
+        .write_all(&[RESET, HEADER_DATASET, 0x04])
+        .and_then(|_| writer.write_all(b"o5m2"))?;
+
+    if let Some(timestamp) = metadata.timestamp.as_deref().and_then(parse_timestamp) {
+        let body = convert_i64_as_signed(timestamp);
+        writer.write_all(&convert_dataset(FILE_TIMESTAMP_DATASET, body))?;
+    }
 
-    for element in waiting_elements {
-        dest.write(&convert_element(element))
-            .expect("Error while writing o5m output.");
+    if let Some(bounds) = metadata.bbox {
+        writer.write_all(&convert_bbox(&bounds))?;
     }
+
+    for section in waiting_elements.into_sections() {
+        if section.is_empty() {
+            continue;
+        }
+
+        // each element type starts from a fresh string table and delta values
+        encoder.reset();
+        writer.write_all(&[RESET])?;
+
+        for element in section {
+            writer.write_all(&encoder.convert_element(element)?)?;
+        }
+    }
+
+    writer.write_all(&[END_OF_FILE])?;
+    writer.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fixtures;
 
     #[test]
     fn test_convert_string() {
@@ -260,12 +457,97 @@ mod tests {
         assert_eq!(convert_i64_as_unsigned(input4), expected4);
     }
     #[test]
+    fn test_convert_signed() {
+        assert_eq!(convert_i64_as_signed(0), vec![0x00]);
+        assert_eq!(convert_i64_as_signed(-1), vec![0x01]);
+        assert_eq!(convert_i64_as_signed(1), vec![0x02]);
+        assert_eq!(convert_i64_as_signed(-2), vec![0x03]);
+        assert_eq!(convert_i64_as_signed(64), vec![0x80, 0x01]);
+        assert_eq!(convert_i64_as_signed(-65), vec![0x81, 0x01]);
+    }
+    #[test]
+    fn test_convert_f64() {
+        assert_eq!(convert_f64(0.0), 0);
+        assert_eq!(convert_f64(-77.4360481), -774360481);
+        assert_eq!(convert_f64(37.5385087), 375385087);
+    }
+    #[test]
     fn test_convert_user() {
         let input1: (i32, String) = (1020, String::from("John"));
         let expected1 = vec![0x00, 0xfc, 0x07, 0x00, 0x4a, 0x6f, 0x68, 0x6e, 0x00];
         assert_eq!(convert_user(input1.0, input1.1), expected1);
     }
 
+    #[test]
+    fn test_convert_element() {
+        let mut encoder = O5mEncoder::new();
+        let node = |id| Element {
+            version: None,
+            ..fixtures::element(
+                id,
+                ElementType::Node {
+                    lat: -0.0000001,
+                    lon: 0.0000001,
+                },
+                &[("a", "b")],
+            )
+        };
+        let expected1 = vec![
+            0x10, 0x09, 0x0a, 0x00, 0x02, 0x01, 0x00, 0x61, 0x00, 0x62, 0x00,
+        ];
+        assert_eq!(encoder.convert_element(node(5)).unwrap(), expected1);
+
+        // deltas and string table references for the second node
+        let expected2 = vec![0x10, 0x05, 0x02, 0x00, 0x00, 0x00, 0x01];
+        assert_eq!(encoder.convert_element(node(6)).unwrap(), expected2);
+
+        // tags are written in key order
+        let mut tagged = node(7);
+        tagged.tags.insert(String::from("0"), String::from("1"));
+        let expected3 = vec![
+            0x10, 0x0a, 0x02, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, 0x31, 0x00, 0x02,
+        ];
+        assert_eq!(encoder.convert_element(tagged).unwrap(), expected3);
+    }
+
+    #[test]
+    fn test_write_bbox() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        drop(sender);
+        let metadata = Metadata {
+            bbox: Some(Bounds {
+                min_lat: -0.0000001,
+                min_lon: 0.0000001,
+                max_lat: 0.0000064,
+                max_lon: -0.0000065,
+            }),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        write_o5m(receiver, metadata, &mut output).unwrap();
+        assert_eq!(
+            output,
+            vec![
+                RESET,
+                HEADER_DATASET,
+                0x04,
+                b'o',
+                b'5',
+                b'm',
+                b'2',
+                BOUNDING_BOX_DATASET,
+                0x06,
+                0x02,
+                0x01,
+                0x81,
+                0x01,
+                0x80,
+                0x01,
+                END_OF_FILE,
+            ]
+        );
+    }
+
     #[test]
     fn test_string_table() {
         let mut string_table = StringTable::new();
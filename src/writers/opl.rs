@@ -1,23 +1,11 @@
 use lexical;
-use rayon::prelude::*;
-use std::fmt::{Error, Write};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::Receiver;
 
+use crate::channel::encode_in_parallel;
 use crate::elements::{Element, ElementType, Metadata, SimpleElementType};
-use crate::threadpools::WRITER_THREAD_POOL;
-
-// wrapper struct that implements std::fmt::Write for any type
-// that implements std::io::Write
-struct ToFmtWrite<T>(pub T);
-
-impl<T> Write for ToFmtWrite<T>
-where
-    T: std::io::Write,
-{
-    fn write_str(&mut self, s: &str) -> std::fmt::Result {
-        self.0.write_all(s.as_bytes()).map_err(|_| std::fmt::Error)
-    }
-}
+use crate::SkywayError;
+
+use super::{member_type, WriteOptions};
 
 // this list is from the Osmium OPL implementation
 fn should_escape_char(input: char) -> bool {
@@ -59,7 +47,7 @@ fn push_escaped_string(base: &mut String, input: &str) {
     }
 }
 
-fn serialize_chunk(chunk: Vec<Element>) -> Result<String, Error> {
+fn serialize_chunk(chunk: Vec<Element>) -> Result<String, SkywayError> {
     let mut output = String::new();
     for element in chunk {
         match element.element_type {
@@ -147,11 +135,10 @@ fn serialize_chunk(chunk: Vec<Element>) -> Result<String, Error> {
                         output.push(',');
                     }
                     first_member_written = true;
-                    output.push(match m.t {
-                        Some(SimpleElementType::Node) => 'n',
-                        Some(SimpleElementType::Way) => 'w',
-                        Some(SimpleElementType::Relation) => 'r',
-                        None => panic!("Member type is None"),
+                    output.push(match member_type(element.id, &m)? {
+                        SimpleElementType::Node => 'n',
+                        SimpleElementType::Way => 'w',
+                        SimpleElementType::Relation => 'r',
                     });
                     output.push_str(&lexical::to_string(m.id));
                     output.push('@');
@@ -167,27 +154,20 @@ fn serialize_chunk(chunk: Vec<Element>) -> Result<String, Error> {
     Ok(output)
 }
 
-#[allow(unused_variables)]
-pub fn write_opl<D: std::io::Write>(receiver: Receiver<Vec<Element>>, metadata: Metadata, dest: D) {
-    let mut writer = ToFmtWrite(dest);
-    let (output_sender, output_reciever) = channel();
-    WRITER_THREAD_POOL.install(move || {
-        receiver
-            .into_iter()
-            .par_bridge()
-            .map(serialize_chunk)
-            .map(|result| result.expect("Failed to serialize chunk"))
-            .for_each(|s| match output_sender.clone().send(s) {
-                Ok(_) => (),
-                Err(e) => panic!("Error passing output chunk between threads."),
-            });
-    });
-
-    for output_string in output_reciever {
-        writer
-            .write_str(&output_string)
-            .expect("Failed to write to output");
-    }
+pub fn write_opl<D: std::io::Write>(
+    receiver: Receiver<Vec<Element>>,
+    _metadata: Metadata,
+    mut dest: D,
+    options: &WriteOptions,
+) -> Result<(), SkywayError> {
+    encode_in_parallel(
+        receiver.into_iter(),
+        options.threads,
+        options.buffer_chunks,
+        options.preserve_order,
+        serialize_chunk,
+        |output_string| Ok(dest.write_all(output_string?.as_bytes())?),
+    )
 }
 
 #[cfg(test)]
@@ -217,7 +197,7 @@ mod tests {
     fn test_should_escape_char() {
         let test_chars = vec![' ', '\n', ',', '=', '@', '%', '😱'];
         for c in test_chars {
-            assert_eq!(should_escape_char(c), true);
+            assert!(should_escape_char(c));
         }
     }
 
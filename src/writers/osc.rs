@@ -0,0 +1,128 @@
+use quick_xml::escape::escape;
+use std::fmt::Write;
+use std::io::BufWriter;
+use std::sync::mpsc::Receiver;
+
+use crate::elements::{Action, Element, Metadata};
+use crate::writers::xml::{write_element, ToFmtWrite};
+use crate::SkywayError;
+
+fn action_tag(action: Action) -> &'static str {
+    match action {
+        Action::Create => "create",
+        Action::Modify => "modify",
+        Action::Delete => "delete",
+    }
+}
+
+// elements read from a snapshot have no action, so they are
+// written as deletions if they are no longer visible
+fn get_action(element: &Element) -> Action {
+    match element.action {
+        Some(a) => a,
+        None if element.visible == Some(false) => Action::Delete,
+        None => Action::Modify,
+    }
+}
+
+/// Writes elements as an OsmChange document. Elements are kept in the order they
+/// are received, and each run of elements with the same action shares one block.
+pub fn write_osc<D: std::io::Write>(
+    receiver: Receiver<Vec<Element>>,
+    metadata: Metadata,
+    dest: D,
+) -> Result<(), SkywayError> {
+    let mut writer = ToFmtWrite::new(BufWriter::new(dest));
+
+    let mut header =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osmChange version=\"");
+    header.push_str(&escape(metadata.version.as_deref().unwrap_or("0.6")));
+    header.push('"');
+    if let Some(g) = metadata.generator {
+        header.push_str(" generator=\"");
+        header.push_str(&escape(&g));
+        header.push('"');
+    }
+    header.push_str(">\n");
+    let result = writer.write_str(&header);
+    writer.check(result)?;
+
+    let mut current_action = None;
+    for element in receiver.iter().flatten() {
+        let action = get_action(&element);
+        if current_action != Some(action) {
+            if let Some(a) = current_action {
+                let result = writeln!(writer, "</{}>", action_tag(a));
+                writer.check(result)?;
+            }
+            let result = writeln!(writer, "<{}>", action_tag(action));
+            writer.check(result)?;
+            current_action = Some(action);
+        }
+        write_element(&mut writer, element)?;
+        let result = writer.write_char('\n');
+        writer.check(result)?;
+    }
+    if let Some(a) = current_action {
+        let result = writeln!(writer, "</{}>", action_tag(a));
+        writer.check(result)?;
+    }
+
+    let result = writer.write_str("</osmChange>\n");
+    writer.check(result)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elements::ElementType;
+    use crate::fixtures;
+    use std::sync::mpsc::channel;
+
+    fn node(id: i64, action: Option<Action>, visible: Option<bool>) -> Element {
+        Element {
+            visible,
+            action,
+            ..fixtures::element(id, ElementType::Node { lat: 1.0, lon: 2.0 }, &[])
+        }
+    }
+
+    #[test]
+    fn test_write_osc() {
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                node(1, Some(Action::Create), None),
+                node(2, Some(Action::Create), None),
+                node(3, None, Some(false)),
+                node(4, None, Some(true)),
+            ])
+            .unwrap();
+        drop(sender);
+
+        let mut output = Vec::new();
+        write_osc(receiver, Metadata::default(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let blocks: Vec<&str> = output
+            .lines()
+            .filter(|l| !l.starts_with("<?xml") && !l.starts_with("<node"))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                "<osmChange version=\"0.6\">",
+                "<create>",
+                "</create>",
+                "<delete>",
+                "</delete>",
+                "<modify>",
+                "</modify>",
+                "</osmChange>"
+            ]
+        );
+        assert!(
+            output.contains("<node lat=\"1\" lon=\"2\" id=\"3\" visible=\"false\" version=\"1\"/>")
+        );
+    }
+}
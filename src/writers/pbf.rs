@@ -0,0 +1,569 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+use crate::channel::encode_in_parallel;
+use crate::elements::{Element, ElementType, Metadata, SimpleElementType};
+use crate::sort::SORT_TYPE_THEN_ID;
+use crate::timestamps::parse_timestamp;
+use crate::SkywayError;
+
+use super::{member_type, WriteOptions};
+
+// protobuf wire types
+const VARINT: u64 = 0;
+const LENGTH_DELIMITED: u64 = 2;
+
+// coordinates are stored in units of 100 nanodegrees, and
+// timestamps in seconds (the defaults for granularity and
+// date_granularity, respectively)
+const COORDINATE_SCALE: f64 = 1e7;
+
+fn push_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+// map signed integers onto unsigned ones for sint32/sint64 fields
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn push_key(output: &mut Vec<u8>, field: u64, wire_type: u64) {
+    push_varint(output, field << 3 | wire_type);
+}
+
+// int32, int64, uint32 and bool fields
+fn push_int_field(output: &mut Vec<u8>, field: u64, value: i64) {
+    push_key(output, field, VARINT);
+    push_varint(output, value as u64);
+}
+
+fn push_bytes_field(output: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    push_key(output, field, LENGTH_DELIMITED);
+    push_varint(output, bytes.len() as u64);
+    output.extend(bytes);
+}
+
+// packed repeated fields, with each value already mapped to a varint
+fn push_packed_field<I: IntoIterator<Item = u64>>(output: &mut Vec<u8>, field: u64, values: I) {
+    let mut packed = Vec::new();
+    for v in values {
+        push_varint(&mut packed, v);
+    }
+    if !packed.is_empty() {
+        push_bytes_field(output, field, &packed);
+    }
+}
+
+// delta-code a sequence of values, mapping each delta with zigzag
+fn delta_encode<I: IntoIterator<Item = i64>>(values: I) -> Vec<u64> {
+    let mut previous = 0;
+    values
+        .into_iter()
+        .map(|v| {
+            let delta = zigzag(v - previous);
+            previous = v;
+            delta
+        })
+        .collect()
+}
+
+fn convert_coordinate(input: f64) -> i64 {
+    (input * COORDINATE_SCALE).round() as i64
+}
+
+fn convert_timestamp(element: &Element) -> i64 {
+    element
+        .timestamp
+        .as_deref()
+        .and_then(parse_timestamp)
+        .unwrap_or(0)
+}
+
+// strings for a single block are stored once, and referenced by index
+struct StringTable {
+    indices: HashMap<String, u64>,
+    strings: Vec<String>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        // index 0 is reserved (as a delimiter in dense nodes)
+        StringTable {
+            indices: HashMap::new(),
+            strings: vec![String::new()],
+        }
+    }
+
+    fn index(&mut self, s: &str) -> u64 {
+        if let Some(i) = self.indices.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as u64;
+        self.indices.insert(s.to_owned(), i);
+        self.strings.push(s.to_owned());
+        i
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        for s in &self.strings {
+            push_bytes_field(&mut output, 1, s.as_bytes());
+        }
+        output
+    }
+}
+
+fn has_info(element: &Element) -> bool {
+    element.version.is_some()
+        || element.timestamp.is_some()
+        || element.changeset.is_some()
+        || element.uid.is_some()
+        || element.user.is_some()
+        || element.visible == Some(false)
+}
+
+// the Info message for a way or relation
+fn serialize_info(element: &Element, string_table: &mut StringTable) -> Vec<u8> {
+    let mut output = Vec::new();
+    if let Some(v) = element.version {
+        push_int_field(&mut output, 1, v.into());
+    }
+    if element.timestamp.is_some() {
+        push_int_field(&mut output, 2, convert_timestamp(element));
+    }
+    if let Some(c) = element.changeset {
+        push_int_field(&mut output, 3, c);
+    }
+    if let Some(u) = element.uid {
+        push_int_field(&mut output, 4, u.into());
+    }
+    if let Some(u) = &element.user {
+        push_int_field(&mut output, 5, string_table.index(u) as i64);
+    }
+    if element.visible == Some(false) {
+        push_int_field(&mut output, 6, 0);
+    }
+    output
+}
+
+fn serialize_tags(element: &Element, string_table: &mut StringTable, output: &mut Vec<u8>) {
+    let (keys, values): (Vec<u64>, Vec<u64>) = element
+        .tags
+        .iter()
+        .map(|(k, v)| (string_table.index(k), string_table.index(v)))
+        .unzip();
+    push_packed_field(output, 2, keys);
+    push_packed_field(output, 3, values);
+}
+
+fn serialize_dense_nodes(nodes: &[Element], string_table: &mut StringTable) -> Vec<u8> {
+    let mut output = Vec::new();
+    push_packed_field(&mut output, 1, delta_encode(nodes.iter().map(|n| n.id)));
+
+    // metadata is stored in parallel arrays, so it is either
+    // written for every node or for none of them
+    if nodes.iter().any(has_info) {
+        let mut info = Vec::new();
+        push_packed_field(
+            &mut info,
+            1,
+            nodes.iter().map(|n| n.version.unwrap_or(0) as u64),
+        );
+        push_packed_field(
+            &mut info,
+            2,
+            delta_encode(nodes.iter().map(convert_timestamp)),
+        );
+        push_packed_field(
+            &mut info,
+            3,
+            delta_encode(nodes.iter().map(|n| n.changeset.unwrap_or(0))),
+        );
+        push_packed_field(
+            &mut info,
+            4,
+            delta_encode(nodes.iter().map(|n| n.uid.unwrap_or(0).into())),
+        );
+        let user_indices: Vec<i64> = nodes
+            .iter()
+            .map(|n| string_table.index(n.user.as_deref().unwrap_or_default()) as i64)
+            .collect();
+        push_packed_field(&mut info, 5, delta_encode(user_indices));
+        if nodes.iter().any(|n| n.visible == Some(false)) {
+            push_packed_field(
+                &mut info,
+                6,
+                nodes.iter().map(|n| u64::from(n.visible != Some(false))),
+            );
+        }
+        push_bytes_field(&mut output, 5, &info);
+    }
+
+    let (lats, lons): (Vec<i64>, Vec<i64>) = nodes
+        .iter()
+        .map(|n| match n.element_type {
+            ElementType::Node { lat, lon } => (convert_coordinate(lat), convert_coordinate(lon)),
+            _ => unreachable!(),
+        })
+        .unzip();
+    push_packed_field(&mut output, 8, delta_encode(lats));
+    push_packed_field(&mut output, 9, delta_encode(lons));
+
+    // tags for all nodes are stored in a single array, with
+    // each node's tags terminated by a 0
+    if nodes.iter().any(|n| !n.tags.is_empty()) {
+        let mut keys_vals = Vec::new();
+        for node in nodes {
+            for (k, v) in &node.tags {
+                keys_vals.push(string_table.index(k));
+                keys_vals.push(string_table.index(v));
+            }
+            keys_vals.push(0);
+        }
+        push_packed_field(&mut output, 10, keys_vals);
+    }
+    output
+}
+
+fn serialize_way(way: &Element, string_table: &mut StringTable) -> Vec<u8> {
+    let mut output = Vec::new();
+    push_int_field(&mut output, 1, way.id);
+    serialize_tags(way, string_table, &mut output);
+    if has_info(way) {
+        let info = serialize_info(way, string_table);
+        push_bytes_field(&mut output, 4, &info);
+    }
+    if let ElementType::Way { nodes } = &way.element_type {
+        push_packed_field(&mut output, 8, delta_encode(nodes.iter().copied()));
+    }
+    output
+}
+
+fn serialize_relation(
+    relation: &Element,
+    string_table: &mut StringTable,
+) -> Result<Vec<u8>, SkywayError> {
+    let mut output = Vec::new();
+    push_int_field(&mut output, 1, relation.id);
+    serialize_tags(relation, string_table, &mut output);
+    if has_info(relation) {
+        let info = serialize_info(relation, string_table);
+        push_bytes_field(&mut output, 4, &info);
+    }
+    if let ElementType::Relation { members } = &relation.element_type {
+        let roles: Vec<u64> = members
+            .iter()
+            .map(|m| string_table.index(m.role.as_deref().unwrap_or_default()))
+            .collect();
+        push_packed_field(&mut output, 8, roles);
+        push_packed_field(&mut output, 9, delta_encode(members.iter().map(|m| m.id)));
+        let types = members
+            .iter()
+            .map(|m| {
+                member_type(relation.id, m).map(|t| match t {
+                    SimpleElementType::Node => 0,
+                    SimpleElementType::Way => 1,
+                    SimpleElementType::Relation => 2,
+                })
+            })
+            .collect::<Result<Vec<u64>, SkywayError>>()?;
+        push_packed_field(&mut output, 10, types);
+    }
+    Ok(output)
+}
+
+// serialize a chunk of elements into a PrimitiveBlock, with one
+// PrimitiveGroup for each run of elements of the same type
+fn serialize_chunk(chunk: Vec<Element>) -> Result<Vec<u8>, SkywayError> {
+    let mut string_table = StringTable::new();
+    let mut groups = Vec::new();
+
+    let mut remaining = &chunk[..];
+    while let Some(first) = remaining.first() {
+        let same_type = |e: &Element| {
+            std::mem::discriminant(&e.element_type) == std::mem::discriminant(&first.element_type)
+        };
+        let run_length = remaining
+            .iter()
+            .position(|e| !same_type(e))
+            .unwrap_or(remaining.len());
+        let (run, rest) = remaining.split_at(run_length);
+        remaining = rest;
+
+        let mut group = Vec::new();
+        match first.element_type {
+            ElementType::Node { .. } => {
+                let dense = serialize_dense_nodes(run, &mut string_table);
+                push_bytes_field(&mut group, 2, &dense);
+            }
+            ElementType::Way { .. } => {
+                for way in run {
+                    let serialized = serialize_way(way, &mut string_table);
+                    push_bytes_field(&mut group, 3, &serialized);
+                }
+            }
+            ElementType::Relation { .. } => {
+                for relation in run {
+                    let serialized = serialize_relation(relation, &mut string_table)?;
+                    push_bytes_field(&mut group, 4, &serialized);
+                }
+            }
+        }
+        groups.push(group);
+    }
+
+    let mut output = Vec::new();
+    push_bytes_field(&mut output, 1, &string_table.serialize());
+    for group in groups {
+        push_bytes_field(&mut output, 2, &group);
+    }
+    Ok(output)
+}
+
+// the writing program is this one, not whatever wrote the input
+const WRITING_PROGRAM: &str = concat!("skyway ", env!("CARGO_PKG_VERSION"));
+
+fn serialize_header(metadata: Metadata) -> Vec<u8> {
+    let mut output = Vec::new();
+    if let Some(b) = metadata.bbox {
+        // HeaderBBox coordinates are in nanodegrees
+        let mut bbox = Vec::new();
+        for (field, value) in [
+            (1, b.min_lon),
+            (2, b.max_lon),
+            (3, b.max_lat),
+            (4, b.min_lat),
+        ] {
+            push_key(&mut bbox, field, VARINT);
+            push_varint(&mut bbox, zigzag((value * 1e9).round() as i64));
+        }
+        push_bytes_field(&mut output, 1, &bbox);
+    }
+    push_bytes_field(&mut output, 4, b"OsmSchema-V0.6");
+    push_bytes_field(&mut output, 4, b"DenseNodes");
+    // features copied from the input header (like LocationsOnWays) may not hold
+    // for this output, and the only one that's kept track of is sorting
+    if metadata
+        .optional_features
+        .iter()
+        .any(|f| f == SORT_TYPE_THEN_ID)
+    {
+        push_bytes_field(&mut output, 5, SORT_TYPE_THEN_ID.as_bytes());
+    }
+    push_bytes_field(&mut output, 16, WRITING_PROGRAM.as_bytes());
+    if let Some(t) = metadata.timestamp.as_deref().and_then(parse_timestamp) {
+        push_int_field(&mut output, 32, t);
+    }
+    if let Some(n) = metadata.replication_sequence_number {
+        push_int_field(&mut output, 33, n);
+    }
+    if let Some(u) = metadata.replication_base_url {
+        push_bytes_field(&mut output, 34, u.as_bytes());
+    }
+    output
+}
+
+// wrap a serialized block in a zlib-compressed Blob, preceded by
+// its BlobHeader and the length of that header
+fn serialize_blob(blob_type: &str, block: Vec<u8>) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&block)
+        .expect("Failed to compress PBF block");
+    let compressed = encoder.finish().expect("Failed to compress PBF block");
+
+    let mut blob = Vec::new();
+    push_int_field(&mut blob, 2, block.len() as i64);
+    push_bytes_field(&mut blob, 3, &compressed);
+
+    let mut blob_header = Vec::new();
+    push_bytes_field(&mut blob_header, 1, blob_type.as_bytes());
+    push_int_field(&mut blob_header, 3, blob.len() as i64);
+
+    let mut output = Vec::new();
+    output.extend((blob_header.len() as u32).to_be_bytes());
+    output.extend(blob_header);
+    output.extend(blob);
+    output
+}
+
+pub fn write_pbf<D: Write>(
+    receiver: Receiver<Vec<Element>>,
+    metadata: Metadata,
+    mut dest: D,
+    options: &WriteOptions,
+) -> Result<(), SkywayError> {
+    dest.write_all(&serialize_blob("OSMHeader", serialize_header(metadata)))?;
+
+    encode_in_parallel(
+        receiver.into_iter(),
+        options.threads,
+        options.buffer_chunks,
+        options.preserve_order,
+        |chunk| match chunk.is_empty() {
+            // empty chunks still take up a place in the sequence
+            true => Ok(Vec::new()),
+            false => serialize_chunk(chunk).map(|c| serialize_blob("OSMData", c)),
+        },
+        |output_bytes| Ok(dest.write_all(&output_bytes?)?),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elements::{Bounds, Member};
+    use crate::fixtures;
+    use osmpbf::{BlobDecode, BlobReader};
+    use std::io::Cursor;
+
+    fn element(id: i64, element_type: ElementType) -> Element {
+        Element {
+            changeset: Some(7),
+            user: Some(String::from("John")),
+            version: Some(2),
+            uid: Some(1020),
+            timestamp: Some(String::from("2024-09-25T14:03:00Z")),
+            visible: Some(true),
+            ..fixtures::element(id, element_type, &[("highway", "path")])
+        }
+    }
+
+    #[test]
+    fn test_push_varint() {
+        let mut output = Vec::new();
+        push_varint(&mut output, 1);
+        push_varint(&mut output, 300);
+        assert_eq!(output, vec![0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_zigzag() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(-2), 3);
+    }
+
+    #[test]
+    fn test_delta_encode() {
+        assert_eq!(delta_encode(vec![5, 7, 6]), vec![10, 4, 1]);
+    }
+
+    #[test]
+    fn test_serialize_blob() {
+        let chunk = vec![
+            element(
+                1,
+                ElementType::Node {
+                    lat: 37.5385087,
+                    lon: -77.4360481,
+                },
+            ),
+            element(
+                2,
+                ElementType::Node {
+                    lat: 37.5,
+                    lon: -77.4,
+                },
+            ),
+            element(3, ElementType::Way { nodes: vec![1, 2] }),
+            element(
+                4,
+                ElementType::Relation {
+                    members: vec![Member {
+                        t: Some(SimpleElementType::Way),
+                        id: 3,
+                        role: Some(String::from("outer")),
+                    }],
+                },
+            ),
+        ];
+        let metadata = Metadata {
+            bbox: Some(Bounds {
+                min_lat: 37.5,
+                min_lon: -77.5,
+                max_lat: 37.6,
+                max_lon: -77.4,
+            }),
+            replication_sequence_number: Some(4230),
+            generator: Some(String::from("osmium/1.16.0")),
+            optional_features: vec![
+                String::from("LocationsOnWays"),
+                String::from("Sort.Type_then_ID"),
+            ],
+            ..Default::default()
+        };
+        let mut bytes = serialize_blob("OSMHeader", serialize_header(metadata));
+        bytes.extend(serialize_blob("OSMData", serialize_chunk(chunk).unwrap()));
+
+        let mut blobs = BlobReader::new(Cursor::new(bytes));
+        match blobs.next().unwrap().unwrap().decode() {
+            Ok(BlobDecode::OsmHeader(header)) => {
+                assert!(header
+                    .required_features()
+                    .contains(&String::from("DenseNodes")));
+                let bbox = header.bbox().unwrap();
+                assert_eq!((bbox.left, bbox.bottom), (-77.5, 37.5));
+                assert_eq!((bbox.right, bbox.top), (-77.4, 37.6));
+                assert_eq!(header.osmosis_replication_sequence_number(), Some(4230));
+                assert_eq!(header.optional_features(), ["Sort.Type_then_ID"]);
+                assert_eq!(header.writing_program(), Some(WRITING_PROGRAM));
+            }
+            _ => panic!("expected a header block"),
+        }
+        let block = match blobs.next().unwrap().unwrap().decode() {
+            Ok(BlobDecode::OsmData(block)) => block,
+            _ => panic!("expected a data block"),
+        };
+
+        let mut ids = Vec::new();
+        block.for_each_element(|e| match e {
+            osmpbf::Element::DenseNode(n) => {
+                assert_eq!(n.tags().collect::<Vec<_>>(), vec![("highway", "path")]);
+                assert_eq!(n.info().unwrap().user().unwrap(), "John");
+                ids.push(n.id());
+                if n.id() == 1 {
+                    assert_eq!(n.nano_lat(), 37538508700);
+                    assert_eq!(n.nano_lon(), -77436048100);
+                }
+            }
+            osmpbf::Element::Way(w) => {
+                assert_eq!(w.refs().collect::<Vec<_>>(), vec![1, 2]);
+                assert_eq!(w.info().milli_timestamp(), Some(1727272980000));
+                ids.push(w.id());
+            }
+            osmpbf::Element::Relation(r) => {
+                let member = r.members().next().unwrap();
+                assert_eq!(member.member_id, 3);
+                assert_eq!(member.role().unwrap(), "outer");
+                ids.push(r.id());
+            }
+            osmpbf::Element::Node(_) => panic!("expected dense nodes"),
+        });
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        // members without a type can't be written
+        let relation = element(
+            5,
+            ElementType::Relation {
+                members: vec![Member {
+                    t: None,
+                    id: 3,
+                    role: None,
+                }],
+            },
+        );
+        assert_eq!(
+            serialize_chunk(vec![relation]).unwrap_err().to_string(),
+            "Member 3 of relation 5 has no type"
+        );
+    }
+}
@@ -1,10 +1,14 @@
+use quick_xml::escape::escape;
 use quick_xml::se::to_writer_with_root;
 use serde::{Serialize, Serializer};
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::fmt::{Display, Write};
+use std::io::{self, BufWriter};
 use std::sync::mpsc::Receiver;
 
 use crate::elements::{Element, ElementType, Member, Metadata, SimpleElementType};
+use crate::spool::Spool;
+use crate::SkywayError;
 
 fn serialize_simple_element_type<S>(
     value: &Option<SimpleElementType>,
@@ -44,17 +48,17 @@ struct XmlTags {
 pub struct XmlElementMeta {
     #[serde(rename = "@id")]
     id: i64,
-    #[serde(rename = "@user")]
+    #[serde(rename = "@user", skip_serializing_if = "Option::is_none")]
     user: Option<String>,
-    #[serde(rename = "@uid")]
+    #[serde(rename = "@uid", skip_serializing_if = "Option::is_none")]
     uid: Option<i32>,
     #[serde(rename = "@visible")]
     visible: bool,
-    #[serde(rename = "@version")]
+    #[serde(rename = "@version", skip_serializing_if = "Option::is_none")]
     version: Option<i32>,
-    #[serde(rename = "@changeset")]
+    #[serde(rename = "@changeset", skip_serializing_if = "Option::is_none")]
     changeset: Option<i64>,
-    #[serde(rename = "@timestamp")]
+    #[serde(rename = "@timestamp", skip_serializing_if = "Option::is_none")]
     timestamp: Option<String>,
 }
 
@@ -107,37 +111,55 @@ struct XmlRelation {
 }
 
 #[derive(Serialize)]
-pub struct XmlMetadata {
-    #[serde(rename = "@version", skip_serializing_if = "Option::is_none")]
-    pub version: Option<String>,
-    #[serde(rename = "@generator", skip_serializing_if = "Option::is_none")]
-    pub generator: Option<String>,
-    #[serde(rename = "@copyright", skip_serializing_if = "Option::is_none")]
-    pub copyright: Option<String>,
-    #[serde(rename = "@license", skip_serializing_if = "Option::is_none")]
-    pub license: Option<String>,
+struct XmlBounds {
+    #[serde(rename = "@minlat")]
+    min_lat: f64,
+    #[serde(rename = "@minlon")]
+    min_lon: f64,
+    #[serde(rename = "@maxlat")]
+    max_lat: f64,
+    #[serde(rename = "@maxlon")]
+    max_lon: f64,
 }
 
-#[derive(Serialize)]
-struct OsmXmlDocument {
-    #[serde(flatten)]
-    metadata: XmlMetadata,
-    #[serde(default)]
-    node: Vec<XmlNode>,
-    #[serde(default)]
-    way: Vec<XmlWay>,
-    #[serde(default)]
-    relation: Vec<XmlRelation>,
+// wrapper struct that implements std::fmt::Write for any type that implements
+// std::io::Write, holding on to the I/O error behind a failed write
+pub(super) struct ToFmtWrite<T> {
+    inner: T,
+    error: Option<io::Error>,
 }
 
-struct ToFmtWrite<T>(pub T);
+impl<T: io::Write> ToFmtWrite<T> {
+    pub(super) fn new(inner: T) -> Self {
+        ToFmtWrite { inner, error: None }
+    }
+
+    /// Turns the result of writing or serializing into a `SkywayError`, which is
+    /// the underlying I/O error if there was one.
+    pub(super) fn check<E: Display>(&mut self, result: Result<(), E>) -> Result<(), SkywayError> {
+        match (result, self.error.take()) {
+            (Ok(()), _) => Ok(()),
+            (Err(_), Some(e)) => Err(SkywayError::IoError(e)),
+            (Err(e), None) => Err(SkywayError::IoError(io::Error::other(format!(
+                "XML serialization error: {e}"
+            )))),
+        }
+    }
+
+    pub(super) fn flush(&mut self) -> Result<(), SkywayError> {
+        Ok(self.inner.flush()?)
+    }
+}
 
 impl<T> Write for ToFmtWrite<T>
 where
-    T: std::io::Write,
+    T: io::Write,
 {
     fn write_str(&mut self, s: &str) -> std::fmt::Result {
-        self.0.write_all(s.as_bytes()).map_err(|_| std::fmt::Error)
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            std::fmt::Error
+        })
     }
 }
 
@@ -155,73 +177,153 @@ fn convert_nodes(way_nodes: Vec<i64>) -> Vec<XmlWayNode> {
         .collect()
 }
 
-fn split_and_convert_elements<I>(
-    received_elements: I,
-) -> (Vec<XmlNode>, Vec<XmlWay>, Vec<XmlRelation>)
-where
-    I: Iterator<Item = Element>,
-{
-    let mut nodes = Vec::new();
-    let mut ways = Vec::new();
-    let mut relations = Vec::new();
-    for e in received_elements {
-        let meta = XmlElementMeta {
-            id: e.id,
-            user: e.user,
-            uid: e.uid,
-            visible: e.visible.unwrap_or(true), // TODO: better default behavior?
-            version: e.version,
-            changeset: e.changeset,
-            timestamp: e.timestamp,
-        };
-        let tags = convert_tags(e.tags);
-        match e.element_type {
-            ElementType::Node { lat, lon } => nodes.push(XmlNode {
-                lat,
-                lon,
-                meta,
-                tags,
-            }),
-            ElementType::Way { nodes } => ways.push(XmlWay {
-                meta,
-                nd: convert_nodes(nodes),
-                tags,
-            }),
-            ElementType::Relation { members } => relations.push(XmlRelation {
-                meta,
-                member: members,
-                tags,
-            }),
-        }
+enum XmlElement {
+    Node(XmlNode),
+    Way(XmlWay),
+    Relation(XmlRelation),
+}
+
+fn convert_element(e: Element) -> XmlElement {
+    let meta = XmlElementMeta {
+        id: e.id,
+        user: e.user,
+        uid: e.uid,
+        visible: e.visible.unwrap_or(true), // TODO: better default behavior?
+        version: e.version,
+        changeset: e.changeset,
+        timestamp: e.timestamp,
+    };
+    let tags = convert_tags(e.tags);
+    match e.element_type {
+        ElementType::Node { lat, lon } => XmlElement::Node(XmlNode {
+            lat,
+            lon,
+            meta,
+            tags,
+        }),
+        ElementType::Way { nodes } => XmlElement::Way(XmlWay {
+            meta,
+            nd: convert_nodes(nodes),
+            tags,
+        }),
+        ElementType::Relation { members } => XmlElement::Relation(XmlRelation {
+            meta,
+            member: members,
+            tags,
+        }),
     }
-    (nodes, ways, relations)
-}
-
-pub fn write_xml<D: std::io::Write>(receiver: Receiver<Vec<Element>>, metadata: Metadata, dest: D) {
-    let (node, way, relation) = split_and_convert_elements(receiver.iter().flatten());
-
-    let xml_osm_document = OsmXmlDocument {
-        metadata: XmlMetadata {
-            version: metadata.version,
-            generator: metadata.generator,
-            copyright: metadata.copyright,
-            license: metadata.license,
-        },
-        node,
-        way,
-        relation,
+}
+
+/// Writes a single element as a <node>, <way> or <relation> tag.
+pub(super) fn write_element<W: io::Write>(
+    writer: &mut ToFmtWrite<W>,
+    element: Element,
+) -> Result<(), SkywayError> {
+    let result = match convert_element(element) {
+        XmlElement::Node(n) => to_writer_with_root(&mut *writer, "node", &n),
+        XmlElement::Way(w) => to_writer_with_root(&mut *writer, "way", &w),
+        XmlElement::Relation(r) => to_writer_with_root(&mut *writer, "relation", &r),
     };
+    writer.check(result)
+}
 
-    let mut writer = ToFmtWrite(dest);
+/// Writes elements as an OSM XML document: nodes, then ways, then relations, each in
+/// the order they are received.
+pub fn write_xml<D: io::Write>(
+    receiver: Receiver<Vec<Element>>,
+    metadata: Metadata,
+    dest: D,
+) -> Result<(), SkywayError> {
+    let mut writer = ToFmtWrite::new(BufWriter::new(dest));
 
-    writer
-        .write_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>")
-        .expect("Unable to write header to XML file!");
+    let mut header = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><osm");
+    for (name, value) in [
+        ("version", metadata.version),
+        ("generator", metadata.generator),
+        ("copyright", metadata.copyright),
+        ("license", metadata.license),
+    ] {
+        if let Some(v) = value {
+            header.push(' ');
+            header.push_str(name);
+            header.push_str("=\"");
+            header.push_str(&escape(&v));
+            header.push('"');
+        }
+    }
+    header.push('>');
+    let result = writer.write_str(&header);
+    writer.check(result)?;
+
+    if let Some(b) = metadata.bbox {
+        let bounds = XmlBounds {
+            min_lat: b.min_lat,
+            min_lon: b.min_lon,
+            max_lat: b.max_lat,
+            max_lon: b.max_lon,
+        };
+        let result = to_writer_with_root(&mut writer, "bounds", &bounds);
+        writer.check(result)?;
+    }
 
-    match to_writer_with_root(writer, "osm", &xml_osm_document) {
-        Ok(_) => (),
-        Err(e) => {
-            panic!("XML serialization error: {e:?}");
+    // OSM XML lists every node, then every way, then every relation. nodes are written
+    // as they arrive, and ways and relations are held in temporary files until the end,
+    // so the document is never held in memory
+    let mut ways = Spool::new()?;
+    let mut relations = Spool::new()?;
+    for element in receiver.iter().flatten() {
+        match element.element_type {
+            ElementType::Node { .. } => write_element(&mut writer, element)?,
+            ElementType::Way { .. } => ways.push(&element)?,
+            ElementType::Relation { .. } => relations.push(&element)?,
         }
     }
+    for element in ways.iter()?.chain(relations.iter()?) {
+        write_element(&mut writer, element?)?;
+    }
+
+    let result = writer.write_str("</osm>");
+    writer.check(result)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::element;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_write_xml() {
+        let (sender, receiver) = channel();
+        sender
+            .send(vec![
+                element(20, ElementType::Relation { members: vec![] }, &[]),
+                element(10, ElementType::Way { nodes: vec![1, 2] }, &[]),
+                element(1, ElementType::Node { lat: 1.5, lon: 2.5 }, &[]),
+            ])
+            .unwrap();
+        sender
+            .send(vec![element(
+                2,
+                ElementType::Node { lat: 3.0, lon: 4.0 },
+                &[],
+            )])
+            .unwrap();
+        drop(sender);
+
+        let mut output = Vec::new();
+        write_xml(receiver, Metadata::default(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let order: Vec<usize> = [
+            "lon=\"2.5\" id=\"1\"",
+            "lon=\"4\" id=\"2\"",
+            "<way id=\"10\"",
+            "<relation id=\"20\"",
+        ]
+        .iter()
+        .map(|tag| output.find(tag).unwrap())
+        .collect();
+        assert!(order.windows(2).all(|w| w[0] < w[1]), "{output}");
+    }
 }